@@ -0,0 +1,103 @@
+#![feature(test)]
+
+extern crate test;
+
+use scripting::{compact_value::CompactValue, globals::GlobalValues, heap::Heap, compiler::{Compiler, Program}, value::Value, vm::VirtualMachine};
+use test::{black_box, Bencher};
+
+fn bench_source(b: &mut Bencher, source: &str) {
+    let mut program = Program::new();
+    Compiler::compile(source, None, &mut program).unwrap();
+    b.iter(|| {
+        let mut stack = vec![CompactValue::NONE];
+        let mut heap = Heap::new();
+        let mut globals = GlobalValues::new();
+        VirtualMachine::run(&program, 0, &mut stack, &mut heap, &mut globals).unwrap();
+    });
+}
+
+#[bench]
+fn counting_loop(b: &mut Bencher) {
+    bench_source(b, "
+        var count = func(n) {
+            var i = 0
+            while i < n {
+                i += 1
+            }
+            return i
+        }
+        count(10000)
+    ");
+}
+
+#[bench]
+fn local_shuffle(b: &mut Bencher) {
+    bench_source(b, "
+        var shuffle = func(n) {
+            var a = 1
+            var b = 2
+            var c = 3
+            var i = 0
+            while i < n {
+                var t = a
+                a = b
+                b = c
+                c = t
+                i += 1
+            }
+            return a + b + c
+        }
+        shuffle(10000)
+    ");
+}
+
+#[bench]
+fn float_accumulate(b: &mut Bencher) {
+    bench_source(b, "
+        var sum = func(n) {
+            var total = 0.0
+            var i = 0
+            while i < n {
+                total += 0.5
+                i += 1
+            }
+            return total
+        }
+        sum(10000)
+    ");
+}
+
+/// The stack traffic of `local_shuffle` without the interpreter around it,
+/// once on 24 byte `Value`s as the VM stored them before and once on the
+/// 8 byte `CompactValue`s it stores now.
+fn shuffle_stack<T: Copy>(b: &mut Bencher, values: [T; 4], mut add: impl FnMut(T, T) -> T) {
+    b.iter(|| {
+        let mut stack = black_box(values.to_vec());
+        for _ in 0..10000 {
+            stack.push(stack[0]);
+            stack[0] = stack[1];
+            stack[1] = stack[2];
+            stack[2] = stack.pop().unwrap();
+            stack[3] = add(stack[3], stack[2]);
+        }
+        stack
+    });
+}
+
+#[bench]
+fn value_stack(b: &mut Bencher) {
+    shuffle_stack(b, [Value::Int(1), Value::Int(2), Value::Int(3), Value::Int(0)], |a, b| match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Value::Int(a.wrapping_add(b) & 0xffff),
+        _ => Value::None,
+    });
+}
+
+#[bench]
+fn compact_value_stack(b: &mut Bencher) {
+    let mut heap = Heap::new();
+    let values = [1, 2, 3, 0].map(|int| CompactValue::from_int(int, &mut heap));
+    shuffle_stack(b, values, |a, b| match (a.as_int(), b.as_int()) {
+        (Some(a), Some(b)) => CompactValue::from_int(a.wrapping_add(b) & 0xffff, &mut heap),
+        _ => CompactValue::NONE,
+    });
+}
@@ -0,0 +1,548 @@
+use std::{collections::HashMap, convert::{TryFrom, TryInto}};
+
+use crate::{builtins, compiler::Program, diagnostic::{Diagnostic, Diagnostics, Span}, func::{self, ClosureValue, Constant, Func}, module, opcode::{JumpKind, Opcode}, peephole};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'src> {
+    Word(&'src str),
+    String(String),
+    Punct(char),
+}
+
+/// Splits one line of a listing into tokens. A `;` outside a string starts
+/// a comment that runs to the end of the line.
+fn tokenize(line: &str, start: usize) -> Result<Vec<(Token<'_>, Span)>, Diagnostic> {
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let span_to = |end: usize| Span::new(start + i, start + end);
+        match c {
+            ';' => break,
+            '(' | ')' | ',' | ':' => tokens.push((Token::Punct(c), span_to(i + 1))),
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    let (j, c) = chars.next().ok_or_else(|| Diagnostic::new("unterminated string", span_to(line.len())))?;
+                    match c {
+                        '"' => {
+                            tokens.push((Token::String(string), span_to(j + 1)));
+                            break
+                        }
+                        '\\' => {
+                            let escape = match chars.next() {
+                                Some((_, 'n')) => '\n',
+                                Some((_, 'r')) => '\r',
+                                Some((_, 't')) => '\t',
+                                Some((_, '0')) => '\0',
+                                Some((_, c @ ('\\' | '"' | '\''))) => c,
+                                Some((_, 'u')) => {
+                                    let rest = &line[j + 2..];
+                                    let code = rest.strip_prefix('{')
+                                        .and_then(|rest| rest.split_once('}'))
+                                        .and_then(|(hex, _)| Some((hex.len(), u32::from_str_radix(hex, 16).ok()?)))
+                                        .and_then(|(len, code)| Some((len, char::from_u32(code)?)));
+                                    match code {
+                                        Some((len, c)) => {
+                                            for _ in 0..len + 2 {
+                                                chars.next();
+                                            }
+                                            c
+                                        }
+                                        None => return Err(Diagnostic::new("invalid unicode escape", Span::new(start + j, start + j + 2))),
+                                    }
+                                }
+                                _ => return Err(Diagnostic::new("invalid escape", Span::new(start + j, start + j + 2))),
+                            };
+                            string.push(escape);
+                        }
+                        c => string.push(c),
+                    }
+                }
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut end = i + c.len_utf8();
+                while let Some(&(j, c)) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, ';' | '(' | ')' | ',' | ':' | '"') {
+                        break
+                    }
+                    end = j + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push((Token::Word(&line[i..end]), span_to(end)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// The tokens of one line, consumed from the front.
+struct Line<'src, 't> {
+    tokens: &'t [(Token<'src>, Span)],
+    /// Where the line ends, for errors about missing tokens.
+    end: Span,
+}
+
+impl<'src, 't> Line<'src, 't> {
+    fn span(&self) -> Span {
+        self.tokens.first().map_or(self.end, |(_, span)| *span)
+    }
+    fn next(&mut self, expected: &str) -> Result<(&'t Token<'src>, Span), Diagnostic> {
+        match self.tokens {
+            [(token, span), rest @ ..] => {
+                self.tokens = rest;
+                Ok((token, *span))
+            }
+            [] => Err(Diagnostic::new(format!("expected {}", expected), self.end)),
+        }
+    }
+    fn word(&mut self, expected: &str) -> Result<(&'src str, Span), Diagnostic> {
+        match self.next(expected)? {
+            (Token::Word(word), span) => Ok((word, span)),
+            (_, span) => Err(Diagnostic::new(format!("expected {}", expected), span)),
+        }
+    }
+    fn punct(&mut self, punct: char) -> Result<(), Diagnostic> {
+        match self.next(&format!("`{}`", punct))? {
+            (Token::Punct(c), _) if *c == punct => Ok(()),
+            (_, span) => Err(Diagnostic::new(format!("expected `{}`", punct), span)),
+        }
+    }
+    fn keyword(&mut self, keyword: &str) -> Result<(), Diagnostic> {
+        match self.word(&format!("`{}`", keyword))? {
+            (word, _) if word == keyword => Ok(()),
+            (_, span) => Err(Diagnostic::new(format!("expected `{}`", keyword), span)),
+        }
+    }
+    fn eat_punct(&mut self, punct: char) -> bool {
+        match self.tokens {
+            [(Token::Punct(c), _), rest @ ..] if *c == punct => {
+                self.tokens = rest;
+                true
+            }
+            _ => false,
+        }
+    }
+    fn number<T: TryFrom<u64>>(&mut self, what: &str) -> Result<T, Diagnostic> {
+        let (word, span) = self.word(what)?;
+        word.parse::<u64>().ok()
+            .and_then(|number| T::try_from(number).ok())
+            .ok_or_else(|| Diagnostic::new(format!("expected {}", what), span))
+    }
+    fn end(&self) -> Result<(), Diagnostic> {
+        match self.tokens {
+            [] => Ok(()),
+            [(_, span), ..] => Err(Diagnostic::new("unexpected tokens after instruction", *span)),
+        }
+    }
+}
+
+/// A jump whose offset is written once its label is defined.
+struct Fixup<'src> {
+    /// Where the offset starts in the bytecode.
+    at: usize,
+    width: usize,
+    label: &'src str,
+    span: Span,
+}
+
+/// A function being assembled.
+struct FuncAsm<'src> {
+    id: usize,
+    func: Func,
+    constant_indices: HashMap<Constant, u32>,
+    labels: HashMap<&'src str, (usize, Span)>,
+    fixups: Vec<Fixup<'src>>,
+}
+
+struct Assembler<'src> {
+    program: Program,
+    /// The functions whose `end` has not been reached, innermost last.
+    funcs: Vec<FuncAsm<'src>>,
+    /// Which entries of `program.funcs` have been assembled.
+    defined: Vec<bool>,
+    /// The value of every opcode's name.
+    opcodes: HashMap<String, Opcode>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'src> FuncAsm<'src> {
+    fn constant(&mut self, constant: Constant) -> u32 {
+        func::add_constant(&mut self.func.constants, &mut self.constant_indices, constant)
+    }
+    /// Fills in every jump offset, reporting labels that were never
+    /// defined or are out of reach.
+    fn finish(mut self, diagnostics: &mut Vec<Diagnostic>) -> Func {
+        for fixup in &self.fixups {
+            let target = match self.labels.get(fixup.label) {
+                Some((target, _)) => *target,
+                None => {
+                    diagnostics.push(Diagnostic::new(format!("undefined label `{}`", fixup.label), fixup.span));
+                    continue
+                }
+            };
+            let relative = target as isize - (fixup.at + fixup.width) as isize;
+            if !peephole::fits(relative, fixup.width) {
+                diagnostics.push(Diagnostic::new(format!("label `{}` is out of reach of a {} bit offset", fixup.label, fixup.width * 8), fixup.span));
+                continue
+            }
+            self.func.bytecode[fixup.at..fixup.at + fixup.width].copy_from_slice(&(relative as i32).to_be_bytes()[4 - fixup.width..]);
+        }
+        self.func
+    }
+}
+
+impl<'src> Assembler<'src> {
+    fn line(&mut self, tokens: &[(Token<'src>, Span)], end: Span) -> Result<(), Diagnostic> {
+        let mut line = Line { tokens, end };
+        // Offsets and labels before the instruction, as in `Func` listings.
+        while let [(Token::Word(word), span), (Token::Punct(':'), _), rest @ ..] = line.tokens {
+            line.tokens = rest;
+            if word.parse::<usize>().is_ok() {
+                continue
+            }
+            let func = self.funcs.last_mut().ok_or_else(|| Diagnostic::new("label outside a function", *span))?;
+            let offset = func.func.bytecode.len();
+            if let Some((_, first)) = func.labels.insert(word, (offset, *span)) {
+                return Err(Diagnostic::new(format!("label `{}` is defined twice", word), *span).with_label(first, "first defined here"))
+            }
+        }
+        let span = line.span();
+        match line.tokens {
+            [] => Ok(()),
+            [(Token::Word(word), span), (Token::Punct('('), _), ..] if word.starts_with("func") => {
+                let id = word["func".len()..].parse()
+                    .map_err(|_| Diagnostic::new("expected a function id such as `func0`", *span))?;
+                line.tokens = &line.tokens[2..];
+                self.func_header(id, *span, line)
+            }
+            [(Token::Word("module"), _), ..] => {
+                line.next("`module`")?;
+                self.module(line)
+            }
+            [(Token::Word("global"), _), ..] => {
+                line.next("`global`")?;
+                let slot: u32 = line.number("a global slot")?;
+                let (name, _) = line.word("a global name")?;
+                let module = match line.tokens {
+                    [] => module::MAIN,
+                    _ => self.module_id(&mut line)? as usize,
+                };
+                line.end()?;
+                self.reserve_global(slot);
+                let symbol = self.program.symbols.add(name);
+                self.program.globals.assign(slot, module, Some(symbol));
+                Ok(())
+            }
+            [(Token::Word("end"), _), ..] => {
+                line.next("`end`")?;
+                line.end()?;
+                let func = self.funcs.pop().ok_or_else(|| Diagnostic::new("`end` outside a function", span))?;
+                self.finish(func);
+                Ok(())
+            }
+            _ => {
+                let mut func = self.funcs.pop().ok_or_else(|| Diagnostic::new("instruction outside a function", span))?;
+                let result = self.body_line(&mut func, line);
+                self.funcs.push(func);
+                result
+            }
+        }
+    }
+    /// Handles a `line` or `var` directive or an instruction.
+    fn body_line(&mut self, func: &mut FuncAsm<'src>, mut line: Line<'src, '_>) -> Result<(), Diagnostic> {
+        let offset = func.func.bytecode.len() as u32;
+        match line.tokens {
+            [(Token::Word("line"), _), ..] => {
+                line.next("`line`")?;
+                let number = line.number("a line number")?;
+                func::add_line(&mut func.func.lines, offset, number);
+            }
+            [(Token::Word("var"), _), ..] => {
+                line.next("`var`")?;
+                let slot = line.number("a local slot from 0 to 65535")?;
+                let (name, _) = line.word("a variable name")?;
+                func.func.local_names.push((offset, slot, self.program.symbols.add(name)));
+            }
+            _ => return self.instr(func, line),
+        }
+        line.end()
+    }
+    /// Starts a function, nested in the current one if there is one, from
+    /// the rest of its header after the `(`. The function is started even
+    /// if the header is wrong, so that its `end` still matches.
+    fn func_header(&mut self, id: usize, span: Span, line: Line<'src, '_>) -> Result<(), Diagnostic> {
+        let mut func = Func::default();
+        let mut result = self.header(&mut func, span, line);
+        if self.defined.get(id) == Some(&true) || self.funcs.iter().any(|func| func.id == id) {
+            result = result.and(Err(Diagnostic::new(format!("`func{}` is defined twice", id), span)));
+        }
+        self.funcs.push(FuncAsm { id, func, constant_indices: HashMap::new(), labels: HashMap::new(), fixups: vec![] });
+        result
+    }
+    fn header(&mut self, func: &mut Func, span: Span, mut line: Line<'src, '_>) -> Result<(), Diagnostic> {
+        if !line.eat_punct(')') {
+            loop {
+                let (name, _) = line.word("a parameter name")?;
+                func.param_names.push(self.program.symbols.add(name));
+                if line.eat_punct(')') {
+                    break
+                }
+                line.punct(',')?;
+            }
+        }
+        func.param_count = func.param_names.len().try_into()
+            .map_err(|_| Diagnostic::new("too many parameters", line.end))?;
+        if let Some((Token::Word("captures"), _)) = line.tokens.first() {
+            line.next("`captures`")?;
+            line.punct('(')?;
+            loop {
+                let (kind, span) = line.word("`stack` or `outer`")?;
+                let index = line.number("a closure index from 0 to 65535")?;
+                func.closure_scope.push(match kind {
+                    "stack" => ClosureValue::Stack(index),
+                    "outer" => ClosureValue::Outer(index),
+                    _ => return Err(Diagnostic::new("expected `stack` or `outer`", span)),
+                });
+                if let [(Token::Word(name), _), ..] = line.tokens {
+                    line.next("a name")?;
+                    func.closure_names.push(self.program.symbols.add(name));
+                }
+                if line.eat_punct(')') {
+                    break
+                }
+                line.punct(',')?;
+            }
+            if !func.closure_names.is_empty() && func.closure_names.len() != func.closure_scope.len() {
+                return Err(Diagnostic::new("either every captured variable is named or none is", span))
+            }
+        }
+        line.end()
+    }
+    /// Defines a module from the rest of its `module` line. Imported
+    /// modules are numbered in order after the main one, which may be
+    /// redefined.
+    fn module(&mut self, mut line: Line<'src, '_>) -> Result<(), Diagnostic> {
+        let span = line.span();
+        let id: usize = line.number("a module id")?;
+        let path = match line.next("a module path")? {
+            (Token::String(path), _) => path.clone(),
+            (_, span) => return Err(Diagnostic::new("expected a module path", span)),
+        };
+        line.keyword("entry")?;
+        let (word, word_span) = line.word("an entry function such as `func0`")?;
+        let entry_func = word.strip_prefix("func").and_then(|id| id.parse().ok())
+            .ok_or_else(|| Diagnostic::new("expected an entry function such as `func0`", word_span))?;
+        line.keyword("slot")?;
+        let slot = line.number("a global slot")?;
+        let mut module = module::Module::new(&path, entry_func, slot);
+        module.loaded = true;
+        if let Some((Token::Word("exports"), _)) = line.tokens.first() {
+            line.next("`exports`")?;
+            line.punct('(')?;
+            while !line.eat_punct(')') {
+                if !module.exports.is_empty() {
+                    line.punct(',')?;
+                }
+                let (name, _) = line.word("an exported name")?;
+                module.exports.push(self.program.symbols.add(name));
+            }
+        }
+        line.end()?;
+        let next = self.program.modules.len();
+        if id != module::MAIN && id != next {
+            return Err(Diagnostic::new(format!("expected module {}, as modules are numbered in order", next), span))
+        }
+        self.reserve_global(slot);
+        let symbol = self.program.globals.symbol(slot);
+        self.program.globals.assign(slot, id, symbol);
+        if id == module::MAIN {
+            self.program.modules[id] = module;
+        } else {
+            self.program.module_ids.insert(path, id);
+            self.program.modules.push(module);
+        }
+        Ok(())
+    }
+    /// Reads a `module<N>` reference to a module defined earlier.
+    fn module_id(&self, line: &mut Line<'src, '_>) -> Result<u32, Diagnostic> {
+        let (name, span) = line.word("a module")?;
+        name.strip_prefix("module")
+            .and_then(|id| id.parse::<u32>().ok())
+            .filter(|id| (*id as usize) < self.program.modules.len())
+            .ok_or_else(|| Diagnostic::new(format!("unknown module `{}`", name), span))
+    }
+    fn reserve_global(&mut self, slot: u32) {
+        while self.program.globals.len() <= slot as usize {
+            self.program.globals.reserve(module::MAIN);
+        }
+    }
+    /// Stores a function whose `end` has been reached.
+    fn finish(&mut self, func: FuncAsm<'src>) {
+        let id = func.id;
+        let func = func.finish(&mut self.diagnostics);
+        if self.defined.len() <= id {
+            self.defined.resize(id + 1, false);
+            self.program.funcs.resize_with(id + 1, Func::default);
+        }
+        self.program.funcs[id] = func;
+        self.defined[id] = true;
+    }
+    fn constant(&mut self, line: &mut Line<'src, '_>) -> Result<Constant, Diagnostic> {
+        match line.next("a constant")? {
+            (Token::String(string), _) => Ok(Constant::String(string.as_str().into())),
+            (Token::Word(word), span) => {
+                if let Some(Ok(func_id)) = word.strip_prefix("func").map(str::parse) {
+                    Ok(Constant::Func(func_id))
+                } else if let Ok(int) = word.parse() {
+                    Ok(Constant::Int(int))
+                } else if let Ok(float) = word.parse() {
+                    Ok(Constant::Float(float))
+                } else {
+                    Err(Diagnostic::new("expected a constant", span))
+                }
+            }
+            (_, span) => Err(Diagnostic::new("expected a constant", span)),
+        }
+    }
+    fn instr(&mut self, func: &mut FuncAsm<'src>, mut line: Line<'src, '_>) -> Result<(), Diagnostic> {
+        let (name, span) = line.word("an instruction")?;
+        let opcode = *self.opcodes.get(name)
+            .ok_or_else(|| Diagnostic::new(format!("unknown instruction `{}`", name), span))?;
+        let bytecode = &mut func.func.bytecode;
+        match opcode {
+            Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide | Opcode::IntDivide | Opcode::Modulus |
+            Opcode::Equal | Opcode::NotEqual | Opcode::Less | Opcode::Greater | Opcode::LessOrEqual | Opcode::GreaterOrEqual |
+            Opcode::PushTrue | Opcode::PushFalse | Opcode::PushNone |
+            Opcode::Return | Opcode::Finish => bytecode.push(opcode.into()),
+
+            Opcode::PushConst | Opcode::PushConstWide => {
+                let constant = self.constant(&mut line)?;
+                let index = func.constant(constant);
+                func::push_const(&mut func.func.bytecode, index);
+            }
+            Opcode::IncLocal => {
+                let local: u8 = line.number("a local slot from 0 to 255")?;
+                bytecode.extend([opcode.into(), local]);
+            }
+            Opcode::AddLocalConst => {
+                let local: u8 = line.number("a local slot from 0 to 255")?;
+                let constant_span = line.span();
+                let constant = self.constant(&mut line)?;
+                let index = u8::try_from(func.constant(constant))
+                    .map_err(|_| Diagnostic::new("constant index does not fit in a byte", constant_span))?;
+                func.func.bytecode.extend([opcode.into(), local, index]);
+            }
+            Opcode::PushLoad | Opcode::PopStore |
+            Opcode::PushClosureLoad | Opcode::PopClosureStore |
+            Opcode::Drop | Opcode::Call | Opcode::PopPrint => {
+                let operand = line.number("a number from 0 to 65535")?;
+                func::push_op(bytecode, opcode, operand);
+            }
+            Opcode::PushPropLoad => {
+                let (name, span) = line.word("a property name")?;
+                let symbol = self.program.symbols.add(name);
+                let operand = u16::try_from(symbol.id()).map_err(|_| Diagnostic::new("too many symbols", span))?;
+                func::push_op(&mut func.func.bytecode, opcode, operand);
+            }
+            Opcode::Wide => return Err(Diagnostic::new("`Wide` is added to instructions that need it", span)),
+            Opcode::Jump8 | Opcode::Jump16 | Opcode::Jump32 |
+            Opcode::JumpIf8 | Opcode::JumpIf16 | Opcode::JumpIf32 |
+            Opcode::JumpIfNot8 | Opcode::JumpIfNot16 | Opcode::JumpIfNot32 |
+            Opcode::JumpIfNone8 | Opcode::JumpIfNone16 | Opcode::JumpIfNone32 |
+            Opcode::JumpIfNotLessLocals8 | Opcode::JumpIfNotLessLocals16 | Opcode::JumpIfNotLessLocals32 => {
+                let (kind, width) = opcode.jump().unwrap();
+                bytecode.push(opcode.into());
+                if kind == JumpKind::IfNotLessLocals {
+                    let a: u8 = line.number("a local slot from 0 to 255")?;
+                    let b = line.number("a local slot from 0 to 255")?;
+                    bytecode.extend([a, b]);
+                }
+                let (label, span) = line.word("a label")?;
+                func.fixups.push(Fixup { at: bytecode.len(), width, label, span });
+                bytecode.resize(bytecode.len() + width, 0);
+            }
+            Opcode::PushList => {
+                let count: u32 = line.number("a count")?;
+                bytecode.push(opcode.into());
+                bytecode.extend(count.to_be_bytes());
+            }
+            Opcode::PushGlobalLoad | Opcode::PopGlobalStore => {
+                let slot = line.number("a global slot")?;
+                self.reserve_global(slot);
+                bytecode.push(opcode.into());
+                bytecode.extend(slot.to_be_bytes());
+            }
+            Opcode::PushBuiltin => {
+                let (name, span) = line.word("a builtin")?;
+                let index = builtins::lookup(name).ok_or_else(|| Diagnostic::new(format!("unknown builtin `{}`", name), span))?;
+                bytecode.extend([opcode.into(), index]);
+            }
+            Opcode::ImportModule => {
+                let module = self.module_id(&mut line)?;
+                let bytecode = &mut func.func.bytecode;
+                bytecode.push(opcode.into());
+                bytecode.extend(module.to_be_bytes());
+            }
+        }
+        line.end()
+    }
+}
+
+/// Builds a program from a listing in the format `DispProgram` prints.
+/// It may start with `module id "path" entry func<N> slot s exports(...)`
+/// lines defining the modules in order, the main module `0` only where it
+/// differs from `Program::new`'s, and `global slot name` lines naming
+/// global slots, followed by `module<id>` for slots of an imported module.
+/// Each function starts with a `func<N>(params)` header, optionally
+/// followed by `captures(stack i name, outer j name, ...)` with or without
+/// the names, and ends with `end`. A function may be nested inside another
+/// and the functions may come in any order, but every id from `func0`, the
+/// entry point, up to the largest must be defined.
+///
+/// Each line of a function holds one instruction, written as its opcode
+/// name and operands. Jumps name a label, defined by `name:` before the
+/// instruction it lands on; local slots, counts and global slots are plain
+/// numbers; properties and builtins are given by name and constants as
+/// they are printed. A leading `offset :` is ignored, `Wide` is added
+/// where an operand needs it, `PushConst` and `PushConstWide` both take
+/// whichever form the constant's index needs, and `;` starts a comment.
+/// The directives `line n` and `var slot name` record the source line and
+/// the name of a local variable from the next instruction on.
+///
+/// The program is not verified; pass it to `verify::verify` before running
+/// untrusted listings.
+pub fn assemble<'src>(source: &'src str, path: Option<&'src str>) -> Result<Program, Diagnostics<'src>> {
+    let opcodes = (0..=u8::MAX)
+        .filter_map(|byte| Opcode::try_from(byte).ok())
+        .map(|opcode| (format!("{:?}", opcode), opcode))
+        .collect();
+    let mut assembler = Assembler { program: Program::new(), funcs: vec![], defined: vec![], opcodes, diagnostics: vec![] };
+    let mut start = 0;
+    for line in source.split_inclusive('\n') {
+        let text = line.trim_end_matches(['\n', '\r']);
+        let end = Span::new(start + text.len(), start + text.len());
+        let result = tokenize(text, start).and_then(|tokens| assembler.line(&tokens, end));
+        if let Err(err) = result {
+            assembler.diagnostics.push(err);
+        }
+        start += line.len();
+    }
+    let end = Span::new(source.len(), source.len());
+    while let Some(func) = assembler.funcs.pop() {
+        assembler.diagnostics.push(Diagnostic::new(format!("`func{}` has no `end`", func.id), end));
+        assembler.finish(func);
+    }
+    if assembler.defined.is_empty() {
+        assembler.diagnostics.push(Diagnostic::new("expected `func0`", end));
+    }
+    for (id, defined) in assembler.defined.iter().enumerate() {
+        if !defined {
+            assembler.diagnostics.push(Diagnostic::new(format!("`func{}` is never defined", id), end));
+        }
+    }
+    if !assembler.diagnostics.is_empty() {
+        return Err(Diagnostics { source, path, errors: assembler.diagnostics })
+    }
+    Ok(assembler.program)
+}
@@ -0,0 +1,100 @@
+use crate::diagnostic::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ident<'src> {
+    pub name: &'src str,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr<'src> {
+    pub kind: ExprKind<'src>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind<'src> {
+    Int(i64),
+    Float(f64),
+    String(&'src str),
+    Bool(bool),
+    None,
+    Var(Ident<'src>),
+    Call(Ident<'src>, Vec<Expr<'src>>),
+    List(Vec<Expr<'src>>),
+    Func(Vec<Ident<'src>>, FuncBody<'src>),
+    Property(Box<Expr<'src>>, Ident<'src>),
+    Binary(BinaryOp, Box<Expr<'src>>, Box<Expr<'src>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FuncBody<'src> {
+    Block(Block<'src>),
+    Expr(Box<Expr<'src>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    IntDivide,
+    Modulus,
+
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block<'src> {
+    pub stmts: Vec<Stmt<'src>>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt<'src> {
+    pub kind: StmtKind<'src>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind<'src> {
+    Var {
+        name: Ident<'src>,
+        value: Expr<'src>,
+        export: bool,
+        doc: Option<String>,
+    },
+    /// `name = value`, or `name op= value` when `op` is set.
+    Assign {
+        name: Ident<'src>,
+        op: Option<BinaryOp>,
+        value: Expr<'src>,
+    },
+    /// A call whose result is discarded.
+    Call(Ident<'src>, Vec<Expr<'src>>),
+    While(Expr<'src>, Block<'src>),
+    If(Expr<'src>, Block<'src>, Option<Else<'src>>),
+    Print(Vec<Expr<'src>>),
+    Return(Expr<'src>),
+    /// `import "path"` binds the module itself, `import a, b from "path"`
+    /// binds the listed exports.
+    Import {
+        names: Vec<Ident<'src>>,
+        path: &'src str,
+    },
+    /// A scope of its own. There is no syntax for this; the optimiser
+    /// leaves one behind when it replaces an `if` with the branch taken.
+    Block(Block<'src>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Else<'src> {
+    Block(Block<'src>),
+    If(Box<Stmt<'src>>),
+}
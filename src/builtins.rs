@@ -0,0 +1,53 @@
+use std::{convert::TryFrom, env};
+
+use crate::{string::Str, value::Value, vm::{RuntimeError, VirtualMachine}};
+
+#[derive(Debug)]
+pub struct NativeFunc {
+    pub name: &'static str,
+    pub param_count: u8,
+    pub func: fn(&mut VirtualMachine, &[Value]) -> Result<Value, RuntimeError>,
+}
+
+pub static BUILTINS: [NativeFunc; 3] = [
+    NativeFunc { name: "type", param_count: 1, func: type_of },
+    NativeFunc { name: "env", param_count: 1, func: env_var },
+    NativeFunc { name: "exit", param_count: 1, func: exit },
+];
+
+pub fn lookup(name: &str) -> Option<u8> {
+    BUILTINS.iter()
+        .position(|native| native.name == name)
+        .map(|index| index as u8)
+}
+
+fn type_of(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, RuntimeError> {
+    let name = Str::new(vm.heap, args[0].type_name());
+    Ok(Value::RustValue(vm.heap.alloc_rust_value(name)))
+}
+
+/// The value of an environment variable, or `none` if it is unset or not
+/// valid UTF-8.
+fn env_var(vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, RuntimeError> {
+    let name = match &args[0] {
+        Value::RustValue(value) if value.is::<Str>() => value.downcast_ref::<Str>().unwrap().as_str(),
+        value => return Err(RuntimeError::Type { expected: "string", found: value.type_name() }),
+    };
+    Ok(match env::var(name) {
+        Ok(value) => {
+            let value = Str::new(vm.heap, &value);
+            Value::RustValue(vm.heap.alloc_rust_value(value))
+        }
+        Err(_) => Value::None,
+    })
+}
+
+/// Stops the script, unwinding out of `VirtualMachine::resume` with
+/// `RuntimeError::Exit`. Only statuses a process can exit with are
+/// accepted, so a failing status is never truncated to zero.
+fn exit(_vm: &mut VirtualMachine, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0] {
+        Value::Int(status) => Err(u8::try_from(status).map_or(RuntimeError::ExitStatus(status), RuntimeError::Exit)),
+        value => Err(RuntimeError::Type { expected: "int", found: value.type_name() }),
+    }
+}
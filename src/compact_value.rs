@@ -0,0 +1,167 @@
+use std::{fmt, mem::{align_of_val, size_of}, ptr};
+
+use crate::{builtins::NativeFunc, heap::{Heap, HeapPtr}, value::{Closure, RustValue, Value}};
+
+/// A `Value` packed into 8 bytes. Floats are stored as themselves, every
+/// other kind of value is hidden in the payload of a quiet NaN, tagged by
+/// bits 48 and 49 together with the sign bit. Real NaNs are canonicalised
+/// so they never collide with a tagged value.
+#[derive(Clone, Copy, PartialEq)]
+pub struct CompactValue {
+    bits: u64,
+}
+
+/// Layout of every `RustValue` on the heap. `HeapPtr<dyn RustValue>` is a
+/// fat pointer, which does not fit in a payload, so each value is preceded
+/// by its own fat pointer and the thin pointer to that header is stored
+/// instead.
+#[repr(C)]
+struct RustValueBox<T> {
+    header: HeapPtr<dyn RustValue>,
+    value: T,
+}
+
+const QNAN: u64 = 0x7ffc000000000000;
+const SIGN_BIT: u64 = 0x8000000000000000;
+const TAG_MASK: u64 = 0x8003000000000000;
+const PAYLOAD_MASK: u64 = 0x0000ffffffffffff;
+const CANONICAL_NAN: u64 = 0x7ff8000000000000;
+
+const NONE_TAG: u64 = 0x0000000000000000;
+const BOOL_TAG: u64 = 0x0001000000000000;
+const INT_TAG: u64 = 0x0002000000000000;
+const NATIVE_FUNC_TAG: u64 = 0x0003000000000000;
+const CLOSURE_TAG: u64 = SIGN_BIT;
+const RUST_VALUE_TAG: u64 = SIGN_BIT | BOOL_TAG;
+const BOXED_INT_TAG: u64 = SIGN_BIT | INT_TAG;
+
+const INT_MIN: i64 = -(1 << 47);
+const INT_MAX: i64 = (1 << 47) - 1;
+
+impl CompactValue {
+    pub const NONE: CompactValue = CompactValue { bits: QNAN | NONE_TAG };
+    pub const TRUE: CompactValue = CompactValue { bits: QNAN | BOOL_TAG | 1 };
+    pub const FALSE: CompactValue = CompactValue { bits: QNAN | BOOL_TAG };
+
+    #[inline]
+    fn tagged(tag: u64, payload: u64) -> CompactValue {
+        CompactValue { bits: QNAN | tag | payload }
+    }
+    #[inline]
+    fn from_ptr<T>(tag: u64, ptr: *const T) -> CompactValue {
+        let addr = ptr as u64;
+        assert!(addr & !PAYLOAD_MASK == 0, "pointer does not fit in a NaN payload");
+        CompactValue::tagged(tag, addr)
+    }
+    #[inline]
+    fn tag(self) -> u64 {
+        self.bits & TAG_MASK
+    }
+    #[inline]
+    fn payload(self) -> u64 {
+        self.bits & PAYLOAD_MASK
+    }
+    #[inline]
+    fn is_float(self) -> bool {
+        self.bits & QNAN != QNAN
+    }
+    /// Ints that do not fit in the 48 bit payload are boxed, which is the
+    /// only reason encoding needs the heap.
+    #[inline]
+    pub fn from_int(int: i64, heap: &mut Heap) -> CompactValue {
+        if (INT_MIN..=INT_MAX).contains(&int) {
+            CompactValue::tagged(INT_TAG, int as u64 & PAYLOAD_MASK)
+        } else {
+            CompactValue::boxed_int(int, heap)
+        }
+    }
+    #[cold]
+    fn boxed_int(int: i64, heap: &mut Heap) -> CompactValue {
+        CompactValue::from_ptr(BOXED_INT_TAG, heap.alloc(int).as_ptr())
+    }
+    #[inline]
+    pub fn from_float(float: f64) -> CompactValue {
+        if float.is_nan() {
+            CompactValue { bits: CANONICAL_NAN }
+        } else {
+            CompactValue { bits: float.to_bits() }
+        }
+    }
+    #[inline]
+    pub fn from_bool(bool: bool) -> CompactValue {
+        CompactValue::tagged(BOOL_TAG, bool as u64)
+    }
+    /// Panics if `value` was not made by `Heap::alloc_rust_value`, as it
+    /// has no header to point at.
+    fn from_rust_value(value: HeapPtr<dyn RustValue>) -> CompactValue {
+        let header_size = size_of::<HeapPtr<dyn RustValue>>().max(align_of_val(&*value));
+        let header = unsafe { (value.as_ptr() as *const u8).sub(header_size) };
+        assert!(ptr::addr_eq(unsafe { *(header as *const HeapPtr<dyn RustValue>) }.as_ptr(), value.as_ptr()),
+            "rust values must be allocated with Heap::alloc_rust_value");
+        CompactValue::from_ptr(RUST_VALUE_TAG, header)
+    }
+    /// Fast path for ints stored inline; boxed ints go through `decode`.
+    #[inline]
+    pub fn as_int(self) -> Option<i64> {
+        if self.bits & (QNAN | TAG_MASK) == QNAN | INT_TAG {
+            Some(((self.payload() << 16) as i64) >> 16)
+        } else {
+            None
+        }
+    }
+    #[inline]
+    pub fn as_float(self) -> Option<f64> {
+        if self.is_float() {
+            Some(f64::from_bits(self.bits))
+        } else {
+            None
+        }
+    }
+    #[inline]
+    pub fn encode(value: Value, heap: &mut Heap) -> CompactValue {
+        match value {
+            Value::Int(int) => CompactValue::from_int(int, heap),
+            Value::Float(float) => CompactValue::from_float(float),
+            Value::Bool(bool) => CompactValue::from_bool(bool),
+            Value::None => CompactValue::NONE,
+            Value::Closure(closure) => CompactValue::from_ptr(CLOSURE_TAG, closure.as_ptr()),
+            Value::NativeFunc(native) => CompactValue::from_ptr(NATIVE_FUNC_TAG, native),
+            Value::RustValue(value) => CompactValue::from_rust_value(value),
+        }
+    }
+    #[inline]
+    pub fn decode(self) -> Value {
+        if self.is_float() {
+            return Value::Float(f64::from_bits(self.bits))
+        }
+        let payload = self.payload();
+        unsafe {
+            match self.tag() {
+                NONE_TAG => Value::None,
+                BOOL_TAG => Value::Bool(payload != 0),
+                INT_TAG => Value::Int(((payload << 16) as i64) >> 16),
+                NATIVE_FUNC_TAG => Value::NativeFunc(&*(payload as *const NativeFunc)),
+                CLOSURE_TAG => Value::Closure(HeapPtr::from_raw(payload as *mut Closure)),
+                RUST_VALUE_TAG => Value::RustValue(*(payload as *const HeapPtr<dyn RustValue>)),
+                BOXED_INT_TAG => Value::Int(*(payload as *const i64)),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl Heap {
+    pub fn alloc_rust_value<T: RustValue>(&mut self, value: T) -> HeapPtr<dyn RustValue> {
+        let dangling = unsafe { HeapPtr::from_raw(ptr::NonNull::<T>::dangling().as_ptr()) };
+        let mut boxed = self.alloc(RustValueBox { header: dangling as HeapPtr<dyn RustValue>, value });
+        let value = unsafe { HeapPtr::from_raw(&mut boxed.value as *mut T) };
+        boxed.header = value;
+        value
+    }
+}
+
+impl fmt::Debug for CompactValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.decode())
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+
+use crate::{opcode::Opcode, parser::Parser, resolve::{self, undefined_var}, optimize, lexer::Lexer, token::TokenKind, func::{Constant, Func, FuncBuilder, Variable, MAX_OPERAND}, symbols::{Symbols, Symbol}, globals::Globals, builtins};
+use crate::ast::{Block, BinaryOp, Else, Expr, ExprKind, FuncBody, Ident, Stmt, StmtKind};
+use crate::diagnostic::{Cause, Diagnostic, Diagnostics, LineIndex, Span};
+use crate::module::{self, Module, ModuleLoader, FileLoader, ImportError};
+
+/// Walks the syntax tree of one module and emits its bytecode into a
+/// `Program`.
+pub struct Compiler<'p> {
+    path: Option<&'p str>,
+    program: &'p mut Program,
+    module: usize,
+    depth: u32,
+    diagnostics: Vec<Diagnostic>,
+}
+
+pub struct Program {
+    pub funcs: Vec<Func>,
+    pub symbols: Symbols,
+    pub globals: Globals,
+    pub modules: Vec<Module>,
+    pub(crate) module_ids: HashMap<String, usize>,
+    loader: Box<dyn ModuleLoader>,
+}
+
+impl Program {
+    pub fn new() -> Program {
+        Program::with_loader(FileLoader::default())
+    }
+    pub fn with_loader(loader: impl ModuleLoader + 'static) -> Program {
+        let mut globals = Globals::new();
+        let mut main = Module::new(module::MAIN_PATH, 0, globals.reserve(module::MAIN));
+        main.loaded = true;
+        Program {
+            funcs: vec![],
+            symbols: Symbols::new(),
+            globals,
+            modules: vec![main],
+            module_ids: HashMap::new(),
+            loader: Box::new(loader),
+        }
+    }
+    pub fn define_global(&mut self, name: &str) -> u32 {
+        let symbol = self.symbols.add(name);
+        self.globals.define(module::MAIN, symbol)
+    }
+    pub fn resolve_global(&self, name: &str) -> Option<u32> {
+        self.globals.resolve(module::MAIN, self.symbols.get(name)?)
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Program::new()
+    }
+}
+
+fn binary_opcode(op: BinaryOp) -> Opcode {
+    match op {
+        BinaryOp::Add => Opcode::Add,
+        BinaryOp::Subtract => Opcode::Subtract,
+        BinaryOp::Multiply => Opcode::Multiply,
+        BinaryOp::Divide => Opcode::Divide,
+        BinaryOp::IntDivide => Opcode::IntDivide,
+        BinaryOp::Modulus => Opcode::Modulus,
+        BinaryOp::Equal => Opcode::Equal,
+        BinaryOp::NotEqual => Opcode::NotEqual,
+        BinaryOp::Less => Opcode::Less,
+        BinaryOp::Greater => Opcode::Greater,
+        BinaryOp::LessOrEqual => Opcode::LessOrEqual,
+        BinaryOp::GreaterOrEqual => Opcode::GreaterOrEqual,
+    }
+}
+
+/// Checks that a count or index fits in an instruction operand.
+fn check_operand(value: usize, what: &str, span: Span) -> Result<u16, Diagnostic> {
+    if value > MAX_OPERAND {
+        return Err(Diagnostic::new(format!("too many {}", what), span).with_note(format!("the limit is {}", MAX_OPERAND)))
+    }
+    Ok(value as u16)
+}
+
+fn import_error(err: ImportError, span: Span) -> Diagnostic {
+    let message = err.to_string();
+    match err {
+        ImportError::InModule(cause) => Diagnostic::new(message, span).with_cause(cause),
+        _ => Diagnostic::new(message, span),
+    }
+}
+
+impl<'p> Compiler<'p> {
+    fn resolve_var(&self, func: &mut FuncBuilder, symbol: Symbol) -> Option<Variable> {
+        func.resolve_var(symbol).or_else(|| self.program.globals.resolve(self.module, symbol).map(Variable::Global))
+    }
+    fn resolve_ident(&mut self, func: &mut FuncBuilder, ident: &Ident) -> Result<Variable, Diagnostic> {
+        let symbol = self.program.symbols.add(ident.name);
+        self.resolve_var(func, symbol).ok_or_else(|| undefined_var(ident))
+    }
+    /// Defines a variable whose value is about to be pushed. Top level
+    /// variables are globals of the module being compiled, so their slot is
+    /// returned for the caller to store into.
+    fn define_var(&mut self, func: &mut FuncBuilder, symbol: Symbol, span: Span) -> Result<Option<u32>, Diagnostic> {
+        if self.depth == 0 {
+            Ok(Some(self.program.globals.define(self.module, symbol)))
+        } else {
+            check_operand(func.stack_size(), "local variables in one function", span)?;
+            func.define_var(symbol);
+            Ok(None)
+        }
+    }
+    fn push_ident(&mut self, func: &mut FuncBuilder, ident: &Ident) -> Result<(), Diagnostic> {
+        let symbol = self.program.symbols.add(ident.name);
+        match self.resolve_var(func, symbol) {
+            Some(var) => func.push_var(var),
+            None => match builtins::lookup(ident.name) {
+                Some(index) => func.push_bytes(&[Opcode::PushBuiltin.into(), index]),
+                None => return Err(undefined_var(ident)),
+            }
+        }
+        Ok(())
+    }
+    fn compile_call(&mut self, func: &mut FuncBuilder, name: &Ident, args: &[Expr]) -> Result<(), Diagnostic> {
+        func.push_bytes(&[Opcode::PushNone.into()]);
+        for arg in args {
+            self.compile_expr(func, arg)?;
+        }
+        self.push_ident(func, name)?;
+        func.push_op(Opcode::Call, check_operand(args.len(), "arguments", name.span)?);
+        Ok(())
+    }
+    fn compile_func(&mut self, func: &mut FuncBuilder, params: &[Ident], body: &FuncBody) -> Result<(), Diagnostic> {
+        let func_index = self.program.funcs.len();
+        self.program.funcs.push(Func::default());
+        func.push_const(Constant::Func(func_index as u32));
+
+        let mut child_func = func.new_child();
+        for param in params {
+            check_operand(child_func.stack_size(), "parameters", param.span)?;
+            let symbol = self.program.symbols.add(param.name);
+            child_func.define_param(symbol);
+        }
+        match body {
+            FuncBody::Block(block) => self.compile_block(&mut child_func, block),
+            FuncBody::Expr(expr) => {
+                child_func.set_line(expr.span);
+                self.compile_expr(&mut child_func, expr)?;
+                child_func.push_bytes(&[Opcode::PopStore.into(), 0]);
+            }
+        }
+        child_func.push_bytes(&[Opcode::Return.into()]);
+        self.program.funcs[func_index] = child_func.build();
+        Ok(())
+    }
+    fn compile_expr(&mut self, func: &mut FuncBuilder, expr: &Expr) -> Result<(), Diagnostic> {
+        match &expr.kind {
+            ExprKind::Int(val) => func.push_const(Constant::Int(*val)),
+            ExprKind::Float(val) => func.push_const(Constant::Float(*val)),
+            ExprKind::String(string) => func.push_const(Constant::String((*string).into())),
+            ExprKind::Bool(true) => func.push_bytes(&[Opcode::PushTrue.into()]),
+            ExprKind::Bool(false) => func.push_bytes(&[Opcode::PushFalse.into()]),
+            ExprKind::None => func.push_bytes(&[Opcode::PushNone.into()]),
+            ExprKind::Var(ident) => self.push_ident(func, ident)?,
+            ExprKind::Call(name, args) => self.compile_call(func, name, args)?,
+            ExprKind::List(values) => {
+                for value in values {
+                    self.compile_expr(func, value)?;
+                }
+                func.push_bytes(&[Opcode::PushList.into()]);
+                func.push_bytes(&(values.len() as u32).to_be_bytes());
+            }
+            ExprKind::Func(params, body) => self.compile_func(func, params, body)?,
+            ExprKind::Property(value, name) => {
+                self.compile_expr(func, value)?;
+                let symbol = self.program.symbols.add(name.name);
+                func.push_op(Opcode::PushPropLoad, check_operand(symbol.id() as usize, "distinct names in one program", name.span)?);
+            }
+            ExprKind::Binary(op, lhs, rhs) => {
+                self.compile_expr(func, lhs)?;
+                self.compile_expr(func, rhs)?;
+                func.push_bytes(&[binary_opcode(*op).into()]);
+            }
+        }
+        Ok(())
+    }
+    fn compile_if(&mut self, func: &mut FuncBuilder, cond: &Expr, then: &Block, otherwise: &Option<Else>) -> Result<(), Diagnostic> {
+        self.compile_expr(func, cond)?;
+        let cond = func.push_jump_if_not();
+        self.compile_block(func, then);
+        match otherwise {
+            Some(otherwise) => {
+                let exit = func.push_jump();
+                let else_target = func.create_jump_target();
+                func.connect_jump(cond, &else_target);
+                match otherwise {
+                    Else::If(stmt) => self.compile_stmt(func, stmt)?,
+                    Else::Block(block) => self.compile_block(func, block),
+                }
+                let end = func.create_jump_target();
+                func.connect_jump(exit, &end);
+            }
+            None => {
+                let end = func.create_jump_target();
+                func.connect_jump(cond, &end);
+            }
+        }
+        Ok(())
+    }
+    fn compile_import(&mut self, func: &mut FuncBuilder, names: &[Ident], path: &str, at: Span) -> Result<(), Diagnostic> {
+        let id = self.import_module(path).map_err(|err| import_error(err, at))?;
+        func.push_bytes(&[Opcode::ImportModule.into()]);
+        func.push_bytes(&(id as u32).to_be_bytes());
+        if names.is_empty() {
+            let name = module::binding_name(path);
+            if Lexer::new(name).next_token().kind != TokenKind::Ident(name) {
+                return Err(import_error(ImportError::InvalidName(path.to_string()), at))
+            }
+            let symbol = self.program.symbols.add(name);
+            if let Some(slot) = self.define_var(func, symbol, at)? {
+                func.pop_var(Variable::Global(slot));
+            }
+        } else {
+            func.push_bytes(&[Opcode::Drop.into(), 1]);
+            for name in names {
+                let symbol = self.program.symbols.add(name.name);
+                let module = &self.program.modules[id];
+                if !module.is_exported(symbol) {
+                    return Err(import_error(ImportError::NotExported(module.path.clone(), name.name.to_string()), name.span))
+                }
+                let global = self.define_var(func, symbol, name.span)?;
+                func.push_var(Variable::Global(self.program.globals.resolve(id, symbol).unwrap()));
+                if let Some(slot) = global {
+                    func.pop_var(Variable::Global(slot));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Compiles the module at `path` into the program unless it already
+    /// has been, returning its id.
+    fn import_module(&mut self, path: &str) -> Result<usize, ImportError> {
+        let key = self.program.loader.resolve(self.path, path);
+        if let Some(&id) = self.program.module_ids.get(&key) {
+            // Every other module is imported while the main one compiles,
+            // so importing the main module is always a cycle.
+            return if id != module::MAIN && self.program.modules[id].loaded {
+                Ok(id)
+            } else {
+                Err(ImportError::Cycle(key))
+            }
+        }
+        let source = self.program.loader.load(&key).map_err(|err| ImportError::Load(key.clone(), err))?;
+        let id = self.program.modules.len();
+        let slot = self.program.globals.reserve(id);
+        self.program.modules.push(Module::new(&key, self.program.funcs.len(), slot));
+        self.program.module_ids.insert(key.clone(), id);
+        let result = Compiler::compile_chunk(&source, Some(&key), self.program, id, Opcode::Return)
+            .map_err(|err| err.errors);
+        match result {
+            Ok(()) => {
+                self.program.modules[id].loaded = true;
+                Ok(id)
+            }
+            Err(err) => {
+                self.program.module_ids.remove(&key);
+                Err(ImportError::InModule(Cause { path: key, source, errors: err }))
+            }
+        }
+    }
+    fn compile_stmt(&mut self, func: &mut FuncBuilder, stmt: &Stmt) -> Result<(), Diagnostic> {
+        func.set_line(stmt.span);
+        match &stmt.kind {
+            StmtKind::While(cond, body) => {
+                let start = func.create_jump_target();
+                self.compile_expr(func, cond)?;
+                let cond = func.push_jump_if_not();
+                self.compile_block(func, body);
+                let repeat = func.push_jump();
+                let exit = func.create_jump_target();
+                func.connect_jump(repeat, &start);
+                func.connect_jump(cond, &exit);
+            }
+            StmtKind::If(cond, then, otherwise) => self.compile_if(func, cond, then, otherwise)?,
+            StmtKind::Var { name, value, export, doc } => {
+                if *export && self.depth != 0 {
+                    return Err(Diagnostic::new("`export` is only allowed at the top level of a module", stmt.span))
+                }
+                let symbol = self.program.symbols.add(name.name);
+                let global = self.define_var(func, symbol, name.span)?;
+                if let (Some(slot), Some(doc)) = (global, doc) {
+                    self.program.globals.set_doc(slot, doc.clone());
+                }
+                self.compile_expr(func, value)?;
+                if let Some(slot) = global {
+                    func.pop_var(Variable::Global(slot));
+                }
+                let exports = &mut self.program.modules[self.module].exports;
+                if *export && !exports.contains(&symbol) {
+                    exports.push(symbol);
+                }
+            }
+            StmtKind::Assign { name, op, value } => {
+                let var = self.resolve_ident(func, name)?;
+                if let Some(op) = op {
+                    func.push_var(var);
+                    self.compile_expr(func, value)?;
+                    func.push_bytes(&[binary_opcode(*op).into()]);
+                } else {
+                    self.compile_expr(func, value)?;
+                }
+                func.pop_var(var);
+            }
+            StmtKind::Call(name, args) => {
+                self.compile_call(func, name, args)?;
+                func.push_bytes(&[Opcode::Drop.into(), 1]);
+            }
+            StmtKind::Print(values) => {
+                for value in values {
+                    self.compile_expr(func, value)?;
+                }
+                func.push_op(Opcode::PopPrint, check_operand(values.len(), "values to print", stmt.span)?);
+            }
+            StmtKind::Return(value) => {
+                self.compile_expr(func, value)?;
+                func.push_bytes(&[Opcode::PopStore.into(), 0]);
+                func.push_bytes(&[Opcode::Return.into()]);
+            }
+            StmtKind::Import { names, path } => self.compile_import(func, names, path, stmt.span)?,
+            StmtKind::Block(block) => self.compile_block(func, block),
+        }
+        Ok(())
+    }
+    /// Compiles a statement, recording any error so the statements after it
+    /// are still checked.
+    fn compile_stmt_or_report(&mut self, func: &mut FuncBuilder, stmt: &Stmt) {
+        if let Err(err) = self.compile_stmt(func, stmt) {
+            self.diagnostics.push(err);
+        }
+    }
+    fn compile_block(&mut self, func: &mut FuncBuilder, block: &Block) {
+        let start_stack_size = func.stack_size();
+        self.depth += 1;
+        for stmt in block.stmts.iter() {
+            self.compile_stmt_or_report(func, stmt);
+        }
+        self.depth -= 1;
+        let n = func.stack_size() - start_stack_size;
+        if n > 0 {
+            func.free_vars(n as u16);
+        }
+    }
+    /// Parses and compiles the main chunk of a program. The file at `path`
+    /// is registered as the main module, so a module importing it back is
+    /// reported as a cycle.
+    pub fn compile<'src>(source: &'src str, path: Option<&'src str>, program: &mut Program) -> Result<(), Diagnostics<'src>> {
+        if let Some(path) = path {
+            let key = program.loader.resolve(None, path);
+            program.module_ids.insert(key, module::MAIN);
+        }
+        Compiler::compile_chunk(source, path, program, module::MAIN, Opcode::Finish)
+    }
+    fn compile_chunk<'src>(source: &'src str, path: Option<&'src str>, program: &mut Program, module: usize, end: Opcode) -> Result<(), Diagnostics<'src>> {
+        let mut stmts = Parser::parse(source, path)?;
+        let errors = resolve::check(&stmts, program, module);
+        if !errors.is_empty() {
+            return Err(Diagnostics { source, path, errors })
+        }
+        optimize::optimize(&mut stmts);
+        let func_index = program.funcs.len();
+        program.funcs.push(Func::default());
+        let line_index = LineIndex::new(source);
+        let mut func = FuncBuilder::new(source, &line_index);
+        let mut compiler = Compiler { path, program, module, depth: 0, diagnostics: vec![] };
+        for stmt in stmts.iter() {
+            compiler.compile_stmt_or_report(&mut func, stmt);
+        }
+        if !compiler.diagnostics.is_empty() {
+            return Err(Diagnostics { source, path, errors: compiler.diagnostics })
+        }
+        func.push_bytes(&[end.into()]);
+        compiler.program.funcs[func_index] = func.build();
+        Ok(())
+    }
+}
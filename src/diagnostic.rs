@@ -0,0 +1,300 @@
+use std::{fmt::{self, Write}, iter};
+
+/// A range of bytes in a source file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A secondary span pointing at something related to the error, such as
+/// the bracket that was never closed.
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub cause: Option<Box<Cause>>,
+}
+
+/// The errors in another file that led to a diagnostic, such as an
+/// imported module that failed to compile.
+#[derive(Debug, Clone)]
+pub struct Cause {
+    pub path: String,
+    pub source: String,
+    pub errors: Vec<Diagnostic>,
+}
+
+/// Every error reported while compiling one source file, in the order
+/// they were found.
+#[derive(Debug, Clone)]
+pub struct Diagnostics<'src> {
+    pub source: &'src str,
+    pub path: Option<&'src str>,
+    pub errors: Vec<Diagnostic>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Byte offset of the start of every line, so positions are found with a
+/// binary search rather than a scan from the start of the source.
+#[derive(Debug, Clone)]
+pub struct LineIndex {
+    starts: Vec<usize>,
+}
+
+/// Renders diagnostics the way a compiler does: a header, the source lines
+/// with line numbers, and the spans underlined.
+pub struct DispDiagnostics<'a, 'src> {
+    diagnostics: &'a Diagnostics<'src>,
+    palette: &'static Palette,
+}
+
+struct Palette {
+    error: &'static str,
+    primary: &'static str,
+    secondary: &'static str,
+    bold: &'static str,
+    reset: &'static str,
+}
+
+const PLAIN: Palette = Palette { error: "", primary: "", secondary: "", bold: "", reset: "" };
+const ANSI: Palette = Palette {
+    error: "\x1b[1;31m",
+    primary: "\x1b[1;31m",
+    secondary: "\x1b[1;34m",
+    bold: "\x1b[1m",
+    reset: "\x1b[0m",
+};
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+    /// The span from the start of this one to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start, other.end)
+    }
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic { message: message.into(), span, labels: vec![], notes: vec![], cause: None }
+    }
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.labels.push(Label { span, message: message.into() });
+        self
+    }
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+    pub fn with_cause(mut self, cause: Cause) -> Diagnostic {
+        self.cause = Some(Box::new(cause));
+        self
+    }
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> LineIndex {
+        let starts = iter::once(0).chain(source.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        LineIndex { starts }
+    }
+    /// Zero based line containing the byte at `offset`.
+    fn line_of(&self, offset: usize) -> usize {
+        match self.starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        }
+    }
+    fn line_text<'s>(&self, source: &'s str, line: usize) -> &'s str {
+        let end = self.starts.get(line + 1).copied().unwrap_or(source.len());
+        source[self.starts[line]..end].trim_end_matches(['\n', '\r'])
+    }
+    /// Number of chars between the start of `line` and `offset`.
+    fn column_of(&self, source: &str, line: usize, offset: usize) -> usize {
+        source[self.starts[line]..offset].chars().count()
+    }
+    pub fn position(&self, source: &str, offset: usize) -> Position {
+        let line = self.line_of(offset);
+        Position { line: line as u32 + 1, column: self.column_of(source, line, offset) as u32 + 1 }
+    }
+}
+
+impl<'src> Diagnostics<'src> {
+    /// True if the source only failed because it stopped early, which the
+    /// REPL takes as a sign to read another line.
+    pub fn at_end_of_input(&self) -> bool {
+        self.errors.iter().all(|err| err.span.start == self.source.len())
+    }
+    /// Machine readable form for editors and other tools: an array with
+    /// one object per diagnostic, positions given both as byte offsets and
+    /// as one based lines and columns. The errors in another file that
+    /// caused a diagnostic are nested in the same form under `cause`.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write_json(&mut json, self.source, self.path, &self.errors);
+        json
+    }
+}
+
+fn write_json(json: &mut String, source: &str, path: Option<&str>, errors: &[Diagnostic]) {
+    let index = LineIndex::new(source);
+    let span = |span: Span| {
+        let (start, end) = (index.position(source, span.start), index.position(source, span.end));
+        format!(
+            "{{\"start\":{},\"end\":{},\"start_line\":{},\"start_column\":{},\"end_line\":{},\"end_column\":{}}}",
+            span.start, span.end, start.line, start.column, end.line, end.column,
+        )
+    };
+    json.push('[');
+    for (i, err) in errors.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"message\":");
+        write_json_string(json, &err.message);
+        json.push_str(",\"path\":");
+        match path {
+            Some(path) => write_json_string(json, path),
+            None => json.push_str("null"),
+        }
+        write!(json, ",\"span\":{},\"labels\":[", span(err.span)).unwrap();
+        for (i, label) in err.labels.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"message\":");
+            write_json_string(json, &label.message);
+            write!(json, ",\"span\":{}}}", span(label.span)).unwrap();
+        }
+        json.push_str("],\"notes\":[");
+        for (i, note) in err.notes.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write_json_string(json, note);
+        }
+        json.push_str("],\"cause\":");
+        match &err.cause {
+            Some(cause) => write_json(json, &cause.source, Some(&cause.path), &cause.errors),
+            None => json.push_str("null"),
+        }
+        json.push('}');
+    }
+    json.push(']');
+}
+
+fn write_json_string(json: &mut String, string: &str) {
+    json.push('"');
+    for ch in string.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => write!(json, "\\u{:04x}", ch as u32).unwrap(),
+            ch => json.push(ch),
+        }
+    }
+    json.push('"');
+}
+
+impl<'a, 'src> DispDiagnostics<'a, 'src> {
+    pub fn new(diagnostics: &'a Diagnostics<'src>, colors: bool) -> DispDiagnostics<'a, 'src> {
+        DispDiagnostics { diagnostics, palette: if colors { &ANSI } else { &PLAIN } }
+    }
+    /// Writes one diagnostic, followed by the errors that caused it.
+    fn write_diagnostic(&self, f: &mut fmt::Formatter<'_>, source: &str, path: Option<&str>, index: &LineIndex, err: &Diagnostic) -> fmt::Result {
+        let Palette { error, primary, secondary, bold, reset } = self.palette;
+
+        // Each underline is (span, marker, color, label).
+        let underlines: Vec<(Span, char, &str, Option<&str>)> = iter::once((err.span, '^', *primary, None))
+            .chain(err.labels.iter().map(|label| (label.span, '-', *secondary, Some(label.message.as_str()))))
+            .collect();
+        let last_line = |span: Span| index.line_of(if span.end > span.start { span.end - 1 } else { span.start });
+        let mut lines: Vec<usize> = underlines.iter()
+            .flat_map(|(span, ..)| index.line_of(span.start)..=last_line(*span))
+            .collect();
+        lines.sort_unstable();
+        lines.dedup();
+        let width = (lines.last().unwrap() + 1).to_string().len();
+        let gutter = " ".repeat(width);
+
+        let pos = index.position(source, err.span.start);
+        writeln!(f, "{}error{}{}: {}{}", error, reset, bold, err.message, reset)?;
+        writeln!(f, "{}{}-->{} {}:{}:{}", gutter, secondary, reset, path.unwrap_or("<input>"), pos.line, pos.column)?;
+        write!(f, "{}{} |{}", gutter, secondary, reset)?;
+
+        let mut previous = None;
+        for &line in lines.iter() {
+            if previous.is_some_and(|previous| line > previous + 1) {
+                write!(f, "\n{}...{}", secondary, reset)?;
+            }
+            previous = Some(line);
+            let text = index.line_text(source, line);
+            write!(f, "\n{}{:>width$} |{} {}", secondary, line + 1, reset, text, width = width)?;
+            for &(span, marker, color, label) in underlines.iter() {
+                let (first, last) = (index.line_of(span.start), last_line(span));
+                if line < first || line > last {
+                    continue
+                }
+                let start = if line == first { index.column_of(source, line, span.start) } else { 0 };
+                let end = if line == last {
+                    index.column_of(source, line, span.end)
+                } else {
+                    text.chars().count()
+                };
+                let end = end.min(text.chars().count()).max(start + 1);
+                write!(f, "\n{}{} |{} {}{}{}", secondary, gutter, reset, " ".repeat(start), color, iter::repeat_n(marker, end - start).collect::<String>())?;
+                match label {
+                    Some(label) if line == last => write!(f, " {}{}", label, reset)?,
+                    _ => write!(f, "{}", reset)?,
+                }
+            }
+        }
+        for note in err.notes.iter() {
+            write!(f, "\n{}{} ={} {}note{}: {}", secondary, gutter, reset, bold, reset, note)?;
+        }
+        if let Some(cause) = &err.cause {
+            let index = LineIndex::new(&cause.source);
+            for err in cause.errors.iter() {
+                write!(f, "\n\n")?;
+                self.write_diagnostic(f, &cause.source, Some(&cause.path), &index, err)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'src> fmt::Display for DispDiagnostics<'a, 'src> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let index = LineIndex::new(self.diagnostics.source);
+        for (i, err) in self.diagnostics.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, "\n\n")?;
+            }
+            self.write_diagnostic(f, self.diagnostics.source, self.diagnostics.path, &index, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'src> fmt::Display for Diagnostics<'src> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", DispDiagnostics::new(self, false))
+    }
+}
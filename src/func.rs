@@ -1,28 +1,96 @@
-use std::{cell::Cell, mem::size_of, fmt::Display, convert::TryInto, iter::FromIterator};
+use std::{cell::Cell, collections::HashMap, mem::size_of, fmt::{Display, Write}, convert::{TryFrom, TryInto}, hash::{Hash, Hasher}, iter::FromIterator};
 
-use crate::{opcode::Opcode, symbols::{Symbols, Symbol, self}};
+use crate::{compiler::Program, diagnostic::{LineIndex, Span}, module, opcode::{self, JumpKind, Opcode}, peephole, symbols::{Symbols, Symbol, self}, builtins::BUILTINS};
+
+/// The largest local slot, closure index, count or symbol id an
+/// instruction can refer to, using a `Wide` prefix.
+pub const MAX_OPERAND: usize = u16::MAX as usize;
 
 pub struct FuncBuilder<'src, 'outer> {
     source: &'src str,
+    line_index: &'src LineIndex,
     bytecode: Vec<u8>,
-    param_count: u8,
+    constants: Vec<Constant>,
+    constant_indices: HashMap<Constant, u32>,
+    param_count: u16,
     closure_scope: Cell<Vec<ClosureValue>>,
-    pub scope: Vec<Symbol>,
+    scope: Vec<Symbol>,
+    lines: Vec<(u32, u32)>,
+    local_names: Vec<(u32, u16, Symbol)>,
     outer: Option<&'outer FuncBuilder<'src, 'outer>>,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct Func {
     pub bytecode: Vec<u8>,
-    pub param_count: u8,
+    pub constants: Vec<Constant>,
+    pub param_count: u16,
     pub closure_scope: Vec<ClosureValue>,
     pub param_names: Vec<Symbol>,
+    /// The source line of the code from each offset on, by offset.
+    pub lines: Vec<(u32, u32)>,
+    /// The name a local slot takes from an offset on, as `(offset, slot,
+    /// name)` in the order the variables are defined. Parameters are named
+    /// by `param_names` instead.
+    pub local_names: Vec<(u32, u16, Symbol)>,
+    /// The name of each captured variable, or nothing if unknown.
+    pub closure_names: Vec<Symbol>,
+}
+
+/// An entry in a function's constant table, pushed by `PushConst`.
+#[derive(Debug, Clone)]
+pub enum Constant {
+    Int(i64),
+    Float(f64),
+    String(Box<str>),
+    /// A nested function, by its index in `Program::funcs`. Pushing it
+    /// creates a closure over the current frame.
+    Func(u32),
+}
+
+/// Floats are compared by their bits, so that `0.0` and `-0.0` stay
+/// distinct and a NaN constant is shared like any other.
+impl PartialEq for Constant {
+    fn eq(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (Constant::Int(a), Constant::Int(b)) => a == b,
+            (Constant::Float(a), Constant::Float(b)) => a.to_bits() == b.to_bits(),
+            (Constant::String(a), Constant::String(b)) => a == b,
+            (Constant::Func(a), Constant::Func(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Constant {}
+
+impl Hash for Constant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Constant::Int(int) => int.hash(state),
+            Constant::Float(float) => float.to_bits().hash(state),
+            Constant::String(string) => string.hash(state),
+            Constant::Func(func_id) => func_id.hash(state),
+        }
+    }
+}
+
+impl Display for Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constant::Int(int) => write!(f, "{}", int),
+            Constant::Float(float) => write!(f, "{:?}", float),
+            Constant::String(string) => write!(f, "{:?}", string),
+            Constant::Func(func_id) => write!(f, "func{}", func_id),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum ClosureValue {
-    Outer(u8),
-    Stack(u8),
+    Outer(u16),
+    Stack(u16),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -37,17 +105,67 @@ pub struct JumpTarget {
 
 #[derive(Debug, Clone, Copy)]
 pub enum Variable {
-    Stack(u8),
-    Closure(u8),
+    Stack(u16),
+    Closure(u16),
+    Global(u32),
+}
+
+/// Adds a line table entry, replacing one at the same offset and skipping
+/// one that would not change the line.
+pub(crate) fn add_line(lines: &mut Vec<(u32, u32)>, offset: u32, line: u32) {
+    if lines.last().is_some_and(|(last, _)| *last == offset) {
+        lines.pop();
+    }
+    if lines.last().is_none_or(|(_, last)| *last != line) {
+        lines.push((offset, line));
+    }
+}
+
+/// Returns the index of `constant` in a function's constant table, adding
+/// it unless an equal one is already there.
+pub(crate) fn add_constant(constants: &mut Vec<Constant>, indices: &mut HashMap<Constant, u32>, constant: Constant) -> u32 {
+    *indices.entry(constant.clone()).or_insert_with(|| {
+        constants.push(constant);
+        constants.len() as u32 - 1
+    })
+}
+
+/// Emits a `PushConst` of the constant at `index`, or a `PushConstWide` if
+/// the index does not fit in a byte.
+pub(crate) fn push_const(bytecode: &mut Vec<u8>, index: u32) {
+    match u8::try_from(index) {
+        Ok(index) => bytecode.extend([Opcode::PushConst.into(), index]),
+        Err(_) => {
+            bytecode.push(Opcode::PushConstWide.into());
+            bytecode.extend(index.to_be_bytes());
+        }
+    }
+}
+
+/// Emits an instruction with a single index or count operand, behind a
+/// `Wide` prefix if the operand does not fit in a byte.
+pub(crate) fn push_op(bytecode: &mut Vec<u8>, opcode: Opcode, operand: u16) {
+    match u8::try_from(operand) {
+        Ok(operand) => bytecode.extend([opcode.into(), operand]),
+        Err(_) => {
+            bytecode.extend([u8::from(Opcode::Wide), opcode.into()]);
+            bytecode.extend(operand.to_be_bytes());
+        }
+    }
 }
 
 impl<'src, 'outer> FuncBuilder<'src, 'outer> {
-    pub fn new(source: &'src str, params: Vec<Symbol>) -> FuncBuilder<'src, 'outer> {
+    pub fn new(source: &'src str, line_index: &'src LineIndex) -> FuncBuilder<'src, 'outer> {
         FuncBuilder {
             source,
+            line_index,
             bytecode: vec![],
-            param_count: params.len() as u8 - 1,
-            scope: params,
+            constants: vec![],
+            constant_indices: HashMap::new(),
+            param_count: 0,
+            scope: vec![symbols::RETURN],
+            lines: vec![],
+            local_names: vec![],
             closure_scope: Cell::new(vec![]),
             outer: None,
         }
@@ -55,9 +173,14 @@ impl<'src, 'outer> FuncBuilder<'src, 'outer> {
     pub fn new_child(&self) -> FuncBuilder<'src, '_> {
         FuncBuilder {
             source: self.source,
+            line_index: self.line_index,
             bytecode: vec![],
+            constants: vec![],
+            constant_indices: HashMap::new(),
             param_count: 0,
             scope: vec![symbols::RETURN],
+            lines: vec![],
+            local_names: vec![],
             closure_scope: Cell::new(vec![]),
             outer: Some(self),
         }
@@ -65,35 +188,54 @@ impl<'src, 'outer> FuncBuilder<'src, 'outer> {
     pub fn push_bytes(&mut self, bytes: &[u8]) {
         self.bytecode.extend(bytes)
     }
-    pub fn resolve_stack_var(&self, symbol: Symbol) -> Option<u8> {
+    /// Emits a `PushConst` of `constant`, adding it to the constant table
+    /// unless an equal one is already there.
+    pub fn push_const(&mut self, constant: Constant) {
+        let index = add_constant(&mut self.constants, &mut self.constant_indices, constant);
+        push_const(&mut self.bytecode, index);
+    }
+    /// Emits an instruction with a single index or count operand, behind a
+    /// `Wide` prefix if the operand does not fit in a byte.
+    pub fn push_op(&mut self, opcode: Opcode, operand: u16) {
+        push_op(&mut self.bytecode, opcode, operand);
+    }
+    pub fn resolve_stack_var(&self, symbol: Symbol) -> Option<u16> {
         self.scope.iter()
             .position(|var_symbol| *var_symbol == symbol)
-            .map(|offset| offset as u8)
+            .map(|offset| offset as u16)
     }
-    pub fn resolve_closure_var(&self, symbol: Symbol) -> Option<u8> {
-        let mut closure_scope = self.closure_scope.take();
-        for i in 0..closure_scope.len() {
-            if self.closure_var_symbol(i as u8) == symbol {
-                return Some(i as u8)
+    fn closure_scope_len(&self) -> usize {
+        let closure_scope = self.closure_scope.take();
+        let len = closure_scope.len();
+        self.closure_scope.set(closure_scope);
+        len
+    }
+    /// Resolves a variable of an enclosing function, capturing it if this
+    /// is the first use. Returns `None` if there is no such variable or the
+    /// function already captures `MAX_OPERAND + 1` others.
+    pub fn resolve_closure_var(&self, symbol: Symbol) -> Option<u16> {
+        let len = self.closure_scope_len();
+        for i in 0..len {
+            if self.closure_var_symbol(i as u16) == symbol {
+                return Some(i as u16)
             }
         }
-        let outer = match self.outer {
-            Some(outer) => outer,
-            None => return None,
-        };
+        if len > MAX_OPERAND {
+            return None
+        }
+        let outer = self.outer?;
         let closure_var = if let Some(index) = outer.resolve_stack_var(symbol) {
             ClosureValue::Stack(index)
-        } else if let Some(index) = outer.resolve_closure_var(symbol) {
-            ClosureValue::Outer(index)
         } else {
-            return None
+            ClosureValue::Outer(outer.resolve_closure_var(symbol)?)
         };
+        let mut closure_scope = self.closure_scope.take();
         let index = closure_scope.len();
         closure_scope.push(closure_var);
         self.closure_scope.set(closure_scope);
-        Some(index as u8)
+        Some(index as u16)
     }
-    pub fn closure_var_symbol(&self, index: u8) -> Symbol {
+    pub fn closure_var_symbol(&self, index: u16) -> Symbol {
         let outer = self.outer.unwrap();
         let closure_scope = self.closure_scope.take();
         let symbol = match closure_scope[index as usize] {
@@ -106,63 +248,94 @@ impl<'src, 'outer> FuncBuilder<'src, 'outer> {
     pub fn resolve_var(&mut self, symbol: Symbol) -> Option<Variable> {
         if let Some(index) = self.resolve_stack_var(symbol) {
             Some(Variable::Stack(index))
-        } else if let Some(index) = self.resolve_closure_var(symbol) {
-            Some(Variable::Closure(index))
         } else {
-            None
+            self.resolve_closure_var(symbol).map(Variable::Closure)
         }
     }
     pub fn push_var(&mut self, var: Variable) {
         match var {
-            Variable::Stack(offset) => self.bytecode.extend([Opcode::PushLoad.into(), offset]),
-            Variable::Closure(index) => self.bytecode.extend([Opcode::PushClosureLoad.into(), index]),
+            Variable::Stack(offset) => self.push_op(Opcode::PushLoad, offset),
+            Variable::Closure(index) => self.push_op(Opcode::PushClosureLoad, index),
+            Variable::Global(slot) => {
+                self.bytecode.push(Opcode::PushGlobalLoad.into());
+                self.bytecode.extend(slot.to_be_bytes());
+            }
         }
     }
     pub fn pop_var(&mut self, var: Variable) {
         match var {
-            Variable::Stack(offset) => self.bytecode.extend([Opcode::PopStore.into(), offset]),
-            Variable::Closure(index) => self.bytecode.extend([Opcode::PopClosureStore.into(), index]),
+            Variable::Stack(offset) => self.push_op(Opcode::PopStore, offset),
+            Variable::Closure(index) => self.push_op(Opcode::PopClosureStore, index),
+            Variable::Global(slot) => {
+                self.bytecode.push(Opcode::PopGlobalStore.into());
+                self.bytecode.extend(slot.to_be_bytes());
+            }
         }
     }
     pub fn define_var(&mut self, symbol: Symbol) {
+        self.local_names.push((self.bytecode.len() as u32, self.scope.len() as u16, symbol));
         self.scope.push(symbol);
     }
     pub fn define_param(&mut self, symbol: Symbol) {
-        self.define_var(symbol);
+        self.scope.push(symbol);
         self.param_count += 1;
     }
-    pub fn stack_size(&self) -> u8 {
-        self.scope.len() as u8
+    /// Attributes the code emitted from here on to the line `span` starts
+    /// on.
+    pub fn set_line(&mut self, span: Span) {
+        let line = self.line_index.position(self.source, span.start).line;
+        add_line(&mut self.lines, self.bytecode.len() as u32, line);
+    }
+    pub fn stack_size(&self) -> usize {
+        self.scope.len()
     }
-    pub fn free_vars(&mut self, n: u8) {
+    pub fn free_vars(&mut self, n: u16) {
         self.scope.truncate(self.scope.len() - n as usize);
-        self.bytecode.extend([Opcode::Drop.into(), n]);
+        self.push_op(Opcode::Drop, n);
     }
-    pub fn push_jump(&mut self) -> Jump {
-        self.bytecode.push(Opcode::Jump.into());
+    /// Emits a jump with a four byte offset, to be filled in by
+    /// `connect_jump`. `build` shrinks it to the shortest form that
+    /// reaches its target.
+    fn push_jump_kind(&mut self, kind: JumpKind) -> Jump {
+        self.bytecode.push(kind.opcode(size_of::<i32>()).into());
         let offset = self.bytecode.len() as u32;
-        self.bytecode.extend(0u32.to_be_bytes());
+        self.bytecode.extend(0i32.to_be_bytes());
         Jump { offset }
     }
+    pub fn push_jump(&mut self) -> Jump {
+        self.push_jump_kind(JumpKind::Always)
+    }
+    pub fn push_jump_if(&mut self) -> Jump {
+        self.push_jump_kind(JumpKind::If)
+    }
     pub fn push_jump_if_not(&mut self) -> Jump {
-        self.bytecode.push(Opcode::JumpIfNot.into());
-        let offset = self.bytecode.len() as u32;
-        self.bytecode.extend(0u32.to_be_bytes());
-        Jump { offset }
+        self.push_jump_kind(JumpKind::IfNot)
+    }
+    pub fn push_jump_if_none(&mut self) -> Jump {
+        self.push_jump_kind(JumpKind::IfNone)
     }
     pub fn create_jump_target(&mut self) -> JumpTarget {
         JumpTarget { offset: self.bytecode.len() as u32 }
     }
     pub fn connect_jump(&mut self, jump: Jump, target: &JumpTarget) {
-        self.bytecode[jump.offset as usize..jump.offset as usize + size_of::<u32>()].copy_from_slice(&target.offset.to_be_bytes());
+        let start = jump.offset as usize;
+        let relative = target.offset as i64 - (start + size_of::<i32>()) as i64;
+        self.bytecode[start..start + size_of::<i32>()].copy_from_slice(&(relative as i32).to_be_bytes());
     }
     pub fn build(self) -> Func {
-        Func {
+        let closure_names = (0..self.closure_scope_len()).map(|index| self.closure_var_symbol(index as u16)).collect();
+        let mut func = Func {
             bytecode: self.bytecode,
+            constants: self.constants,
             param_count: self.param_count,
             closure_scope: self.closure_scope.take(),
             param_names: Vec::from_iter(self.scope[1..self.param_count as usize + 1].iter().copied()),
-        }
+            lines: self.lines,
+            local_names: self.local_names,
+            closure_names,
+        };
+        peephole::optimize(&mut func);
+        func
     }
 }
 
@@ -177,49 +350,323 @@ impl<'bytecode> Reader<'bytecode> {
         self.offset += n;
         bytes
     }
+    /// Reads a one-byte operand, or a two-byte one after a `Wide` prefix.
+    fn take_operand(&mut self, wide: bool) -> u16 {
+        if wide {
+            u16::from_be_bytes(self.take_bytes(size_of::<u16>()).try_into().unwrap())
+        } else {
+            self.take_bytes(1)[0] as u16
+        }
+    }
 }
 
+/// Prints a function in the listing format `assemble::assemble` reads.
+/// The header gives the parameter names and captured variables, then each
+/// instruction is printed after its offset, with jump targets as labels
+/// and the source lines and local variable names as directives:
+///
+/// ```text
+/// func1(n)
+/// line 2
+/// var 2 i
+///     0 : PushConst 0
+/// line 3
+/// L0:
+///     2 : JumpIfNotLessLocals8 2 1 L1       ; not i < n ↓ 10
+/// line 4
+///     6 : IncLocal 2                        ; i
+///     8 : Jump8 L0                          ; ↑ 2
+/// line 6
+/// L1:
+///    10 : PushLoad 2                        ; i
+///    12 : PopStore 0                        ; return
+///    14 : Return
+/// end
+/// ```
+///
+/// Everything after a `;` only restates the rest of the listing.
 #[derive(Debug, Clone, Copy)]
 pub struct DispFunc<'a> {
     symbols: &'a Symbols,
     func: &'a Func,
+    id: Option<usize>,
+}
+
+/// Prints the names of a program's global slots and then every function.
+/// Each nested function is printed indented under the first instruction
+/// that creates it, and globals are annotated with their names.
+#[derive(Clone, Copy)]
+pub struct DispProgram<'a> {
+    program: &'a Program,
 }
 
 impl<'a> DispFunc<'a> {
     pub fn new(func: &'a Func, symbols: &'a Symbols) -> DispFunc<'a> {
-        DispFunc { func, symbols }
+        DispFunc { func, symbols, id: None }
+    }
+    /// Names the function `func<id>` in the header.
+    pub fn with_id(mut self, id: usize) -> DispFunc<'a> {
+        self.id = Some(id);
+        self
     }
 }
 
-impl<'a> Display for DispFunc<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let params: Vec<_> = self.func.param_names.iter().map(|symbol| self.symbols.get_name(*symbol)).collect();
-        writeln!(f, "func({})", params.join(", "))?;
+impl<'a> DispProgram<'a> {
+    pub fn new(program: &'a Program) -> DispProgram<'a> {
+        DispProgram { program }
+    }
+}
+
+/// The column comments are aligned to, counted from a function's indent.
+const COMMENT_COLUMN: usize = 42;
 
-        let mut reader = Reader { bytecode: &self.func.bytecode, offset: 0 };
+/// The state of printing one or more functions.
+struct Listing<'a> {
+    symbols: &'a Symbols,
+    /// When printing a whole program, the functions still to be printed.
+    program: Option<(&'a Program, Vec<bool>)>,
+}
+
+/// The offset every jump lands on, in order.
+fn jump_targets(func: &Func) -> Vec<usize> {
+    let mut targets = vec![];
+    let mut reader = Reader { bytecode: &func.bytecode, offset: 0 };
+    while reader.offset < func.bytecode.len() {
+        let opcode: Opcode = reader.take_bytes(1)[0].try_into().unwrap();
+        let operands = reader.take_bytes(opcode.operand_len());
+        if let Some((_, width)) = opcode.jump() {
+            let offset = opcode::read_jump_offset(&operands[operands.len() - width..]);
+            targets.push((reader.offset as isize + offset) as usize);
+        }
+    }
+    targets.sort_unstable();
+    targets.dedup();
+    targets
+}
+
+impl<'a> Listing<'a> {
+    /// The name of a local slot at `offset`, if known.
+    fn local_name(&self, func: &Func, offset: usize, slot: u16) -> Option<&'a str> {
+        let symbol = func.local_names.iter().rev()
+            .find(|(start, local, _)| *local == slot && *start as usize <= offset)
+            .map(|(_, _, symbol)| *symbol)
+            .or_else(|| match slot {
+                0 => Some(symbols::RETURN),
+                _ => func.param_names.get(slot as usize - 1).copied(),
+            })?;
+        Some(self.symbols.get_name(symbol))
+    }
+    fn closure_name(&self, func: &Func, index: u16) -> Option<&'a str> {
+        func.closure_names.get(index as usize).map(|symbol| self.symbols.get_name(*symbol))
+    }
+    fn global_name(&self, slot: u32) -> Option<&'a str> {
+        let (program, _) = self.program.as_ref()?;
+        let symbol = program.globals.slots.get(slot as usize)?.1?;
+        Some(program.symbols.get_name(symbol))
+    }
+    fn func(&mut self, f: &mut std::fmt::Formatter<'_>, id: Option<usize>, func: &Func, indent: usize) -> std::fmt::Result {
+        let pad = " ".repeat(indent);
+        let params: Vec<_> = func.param_names.iter().map(|symbol| self.symbols.get_name(*symbol)).collect();
+        write!(f, "{}func", pad)?;
+        if let Some(id) = id {
+            write!(f, "{}", id)?;
+        }
+        write!(f, "({})", params.join(", "))?;
+        if !func.closure_scope.is_empty() {
+            let names = Some(&func.closure_names).filter(|names| names.len() == func.closure_scope.len());
+            let captures: Vec<_> = func.closure_scope.iter().enumerate().map(|(i, var)| {
+                let mut capture = match var {
+                    ClosureValue::Stack(index) => format!("stack {}", index),
+                    ClosureValue::Outer(index) => format!("outer {}", index),
+                };
+                if let Some(names) = names {
+                    capture.push(' ');
+                    capture.push_str(self.symbols.get_name(names[i]));
+                }
+                capture
+            }).collect();
+            write!(f, " captures({})", captures.join(", "))?;
+        }
+        writeln!(f)?;
+
+        let targets = jump_targets(func);
+        let label = |target: usize| match targets.binary_search(&target) {
+            Ok(index) | Err(index) => index,
+        };
+        let mut lines = func.lines.iter().peekable();
+        let mut local_names = func.local_names.clone();
+        local_names.sort_by_key(|(offset, _, _)| *offset);
+        let mut local_names = local_names.into_iter().peekable();
+        let symbols = self.symbols;
+        // Prints the lines, variables and labels that start at `offset`.
+        let mut markers = |f: &mut std::fmt::Formatter<'_>, offset: usize| {
+            while let Some((_, line)) = lines.next_if(|(start, _)| *start as usize <= offset) {
+                writeln!(f, "{}line {}", pad, line)?;
+            }
+            while let Some((_, slot, symbol)) = local_names.next_if(|(start, _, _)| *start as usize <= offset) {
+                writeln!(f, "{}var {} {}", pad, slot, symbols.get_name(symbol))?;
+            }
+            if targets.binary_search(&offset).is_ok() {
+                writeln!(f, "{}L{}:", pad, label(offset))?;
+            }
+            Ok(())
+        };
+        let mut reader = Reader { bytecode: &func.bytecode, offset: 0 };
 
-        while reader.offset < self.func.bytecode.len() {
-            write!(f, "{:>5} : ", reader.offset)?;
-            let opcode: Opcode = reader.take_bytes(1)[0].try_into().unwrap();
-            write!(f, "{:?} ", opcode)?;
+        while reader.offset < func.bytecode.len() {
+            let offset = reader.offset;
+            markers(f, offset)?;
+            let mut opcode: Opcode = reader.take_bytes(1)[0].try_into().unwrap();
+            let wide = matches!(opcode, Opcode::Wide);
+            if wide {
+                opcode = reader.take_bytes(1)[0].try_into().unwrap();
+            }
+            let mut text = format!("{:?}", opcode);
+            let mut comment = None;
+            let mut nested = None;
+            let mut constant = |index: usize, text: &mut String| {
+                let constant = &func.constants[index];
+                if let Constant::Func(func_id) = constant {
+                    nested = Some(*func_id as usize);
+                }
+                write!(text, " {}", constant)
+            };
 
             match opcode {
-                Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide | Opcode::Modulus |
+                Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide | Opcode::IntDivide | Opcode::Modulus |
                 Opcode::Equal | Opcode::NotEqual | Opcode::Less | Opcode::Greater | Opcode::LessOrEqual | Opcode::GreaterOrEqual |
-                Opcode::PushTrue | Opcode::PushFalse | Opcode::PushNone | Opcode::PopPrint |
-                Opcode::Return | Opcode::Finish => writeln!(f, ""),
-
-                Opcode::PushInt => writeln!(f, "{}", i64::from_be_bytes(reader.take_bytes(size_of::<i64>()).try_into().unwrap())),
-                Opcode::PushFloat => writeln!(f, "{}", f64::from_be_bytes(reader.take_bytes(size_of::<f64>()).try_into().unwrap())),
-                Opcode::PushLoad | Opcode::PopStore => writeln!(f, "{}", reader.take_bytes(1)[0]),
-                Opcode::PushClosureLoad | Opcode::PopClosureStore |
-                Opcode::PushPropLoad | Opcode::PopPropStore |
-                Opcode::Drop | Opcode::Call => writeln!(f, "{}", reader.take_bytes(1)[0]),
-                Opcode::Jump | Opcode::JumpIfNot | Opcode::PushList => writeln!(f, "{}", u32::from_be_bytes(reader.take_bytes(size_of::<u32>()).try_into().unwrap())),
-                Opcode::PushFunc => writeln!(f, "func{}", u32::from_be_bytes(reader.take_bytes(size_of::<u32>()).try_into().unwrap())),
+                Opcode::PushTrue | Opcode::PushFalse | Opcode::PushNone |
+                Opcode::Return | Opcode::Finish | Opcode::Wide => Ok(()),
+
+                Opcode::PushConst => constant(reader.take_bytes(1)[0] as usize, &mut text),
+                Opcode::PushConstWide => constant(u32::from_be_bytes(reader.take_bytes(size_of::<u32>()).try_into().unwrap()) as usize, &mut text),
+                Opcode::IncLocal => {
+                    let slot = reader.take_bytes(1)[0];
+                    comment = self.local_name(func, offset, slot as u16).map(str::to_string);
+                    write!(text, " {}", slot)
+                }
+                Opcode::AddLocalConst => {
+                    let (slot, index) = (reader.take_bytes(1)[0], reader.take_bytes(1)[0]);
+                    comment = self.local_name(func, offset, slot as u16).map(str::to_string);
+                    write!(text, " {} {}", slot, func.constants[index as usize])
+                }
+                Opcode::PushLoad | Opcode::PopStore => {
+                    let slot = reader.take_operand(wide);
+                    comment = self.local_name(func, offset, slot).map(str::to_string);
+                    write!(text, " {}", slot)
+                }
+                Opcode::PushClosureLoad | Opcode::PopClosureStore => {
+                    let index = reader.take_operand(wide);
+                    comment = self.closure_name(func, index).map(str::to_string);
+                    write!(text, " {}", index)
+                }
+                Opcode::Drop | Opcode::Call | Opcode::PopPrint => write!(text, " {}", reader.take_operand(wide)),
+                Opcode::PushPropLoad => {
+                    let symbol = Symbol::from_index(reader.take_operand(wide) as u32);
+                    write!(text, " {}", self.symbols.get_name(symbol))
+                }
+                Opcode::Jump8 | Opcode::Jump16 | Opcode::Jump32 |
+                Opcode::JumpIf8 | Opcode::JumpIf16 | Opcode::JumpIf32 |
+                Opcode::JumpIfNot8 | Opcode::JumpIfNot16 | Opcode::JumpIfNot32 |
+                Opcode::JumpIfNone8 | Opcode::JumpIfNone16 | Opcode::JumpIfNone32 |
+                Opcode::JumpIfNotLessLocals8 | Opcode::JumpIfNotLessLocals16 | Opcode::JumpIfNotLessLocals32 => {
+                    let (kind, width) = opcode.jump().unwrap();
+                    let locals = reader.take_bytes(kind.prefix_len());
+                    for local in locals {
+                        write!(text, " {}", local)?;
+                    }
+                    let relative = opcode::read_jump_offset(reader.take_bytes(width));
+                    let target = (reader.offset as isize + relative) as usize;
+                    let arrow = if target > offset { '↓' } else { '↑' };
+                    comment = Some(match locals {
+                        [a, b] => {
+                            let name = |slot: u8| self.local_name(func, offset, slot as u16).map_or_else(|| slot.to_string(), str::to_string);
+                            format!("not {} < {} {} {}", name(*a), name(*b), arrow, target)
+                        }
+                        _ => format!("{} {}", arrow, target),
+                    });
+                    write!(text, " L{}", label(target))
+                }
+                Opcode::PushList => write!(text, " {}", u32::from_be_bytes(reader.take_bytes(size_of::<u32>()).try_into().unwrap())),
+                Opcode::PushGlobalLoad | Opcode::PopGlobalStore => {
+                    let slot = u32::from_be_bytes(reader.take_bytes(size_of::<u32>()).try_into().unwrap());
+                    comment = self.global_name(slot).map(str::to_string);
+                    write!(text, " {}", slot)
+                }
+                Opcode::PushBuiltin => write!(text, " {}", BUILTINS[reader.take_bytes(1)[0] as usize].name),
+                Opcode::ImportModule => write!(text, " module{}", u32::from_be_bytes(reader.take_bytes(size_of::<u32>()).try_into().unwrap())),
             }?;
+
+            let row = format!("{:>5} : {}", offset, text);
+            match comment {
+                Some(comment) => writeln!(f, "{}{:<width$}; {}", pad, row, comment, width = COMMENT_COLUMN)?,
+                None => writeln!(f, "{}{}", pad, row)?,
+            }
+            if let Some((program, printed)) = &mut self.program {
+                let program = *program;
+                if let Some(id) = nested.filter(|id| printed.get(*id) == Some(&false)) {
+                    printed[id] = true;
+                    self.func(f, Some(id), &program.funcs[id], indent + 8)?;
+                }
+            }
         }
+        markers(f, reader.offset)?;
+        writeln!(f, "{}end", pad)
+    }
+}
 
+impl<'a> Display for DispFunc<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Listing { symbols: self.symbols, program: None }.func(f, self.id, self.func, 0)
+    }
+}
+
+impl<'a> Display for DispProgram<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The modules, leaving out the main one while it is as
+        // `Program::new` creates it, and the named global slots.
+        let program = self.program;
+        let mut header = false;
+        for (id, module) in program.modules.iter().enumerate() {
+            let default_main = id == module::MAIN && module.path == module::MAIN_PATH && module.entry_func == 0
+                && module.slot == 0 && module.exports.is_empty();
+            if default_main {
+                continue
+            }
+            write!(f, "module {} {:?} entry func{} slot {}", id, module.path, module.entry_func, module.slot)?;
+            if !module.exports.is_empty() {
+                let names: Vec<_> = module.exports.iter().map(|symbol| program.symbols.get_name(*symbol)).collect();
+                write!(f, " exports({})", names.join(", "))?;
+            }
+            writeln!(f)?;
+            header = true;
+        }
+        for (slot, (module, symbol)) in program.globals.slots.iter().enumerate() {
+            if let Some(symbol) = symbol {
+                write!(f, "global {} {}", slot, program.symbols.get_name(*symbol))?;
+                if *module != module::MAIN {
+                    write!(f, " module{}", module)?;
+                }
+                writeln!(f)?;
+                header = true;
+            }
+        }
+        if header {
+            writeln!(f)?;
+        }
+        let funcs = &self.program.funcs;
+        let mut listing = Listing { symbols: &self.program.symbols, program: Some((self.program, vec![false; funcs.len()])) };
+        for id in 0..funcs.len() {
+            let printed = &mut listing.program.as_mut().unwrap().1;
+            if printed[id] {
+                continue
+            }
+            printed[id] = true;
+            if id > 0 {
+                writeln!(f)?;
+            }
+            listing.func(f, Some(id), &funcs[id], 0)?;
+        }
         Ok(())
     }
-}
\ No newline at end of file
+}
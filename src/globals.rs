@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use crate::{symbols::Symbol, compact_value::CompactValue};
+
+/// Compile time table mapping global variable names to slots, shared by
+/// every chunk compiled into the same `Program`. Each module has its own
+/// namespace, so the same name in two modules gets two slots.
+#[derive(Debug, Clone, Default)]
+pub struct Globals {
+    pub(crate) slots: Vec<(usize, Option<Symbol>)>,
+    index: HashMap<(usize, Symbol), u32>,
+    pub(crate) docs: HashMap<u32, String>,
+}
+
+/// Runtime storage for global variables, indexed by the slots handed out
+/// by `Globals`.
+#[derive(Debug, Clone, Default)]
+pub struct GlobalValues {
+    values: Vec<CompactValue>,
+}
+
+impl Globals {
+    pub fn new() -> Globals {
+        Globals { slots: vec![], index: HashMap::new(), docs: HashMap::new() }
+    }
+    pub fn define(&mut self, module: usize, symbol: Symbol) -> u32 {
+        match self.resolve(module, symbol) {
+            Some(slot) => slot,
+            None => self.push(module, Some(symbol)),
+        }
+    }
+    /// Allocates a slot that no name resolves to.
+    pub fn reserve(&mut self, module: usize) -> u32 {
+        self.push(module, None)
+    }
+    pub fn resolve(&self, module: usize, symbol: Symbol) -> Option<u32> {
+        self.index.get(&(module, symbol)).copied()
+    }
+    /// Appends a slot, indexing it under its name if it has one.
+    pub(crate) fn push(&mut self, module: usize, symbol: Option<Symbol>) -> u32 {
+        self.slots.push((module, None));
+        let slot = self.slots.len() as u32 - 1;
+        self.assign(slot, module, symbol);
+        slot
+    }
+    /// Gives an existing slot a new module and name, as the assembler does
+    /// for the slots a listing declares.
+    pub(crate) fn assign(&mut self, slot: u32, module: usize, symbol: Option<Symbol>) {
+        if let (old_module, Some(old_symbol)) = self.slots[slot as usize] {
+            if self.index.get(&(old_module, old_symbol)) == Some(&slot) {
+                self.index.remove(&(old_module, old_symbol));
+            }
+        }
+        self.slots[slot as usize] = (module, symbol);
+        if let Some(symbol) = symbol {
+            self.index.entry((module, symbol)).or_insert(slot);
+        }
+    }
+    pub fn symbol(&self, slot: u32) -> Option<Symbol> {
+        self.slots[slot as usize].1
+    }
+    /// Attaches the doc comment written before a global's `var`.
+    pub fn set_doc(&mut self, slot: u32, doc: String) {
+        self.docs.insert(slot, doc);
+    }
+    pub fn doc(&self, slot: u32) -> Option<&str> {
+        self.docs.get(&slot).map(String::as_str)
+    }
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+impl GlobalValues {
+    pub fn new() -> GlobalValues {
+        GlobalValues { values: vec![] }
+    }
+    pub fn get(&self, slot: u32) -> CompactValue {
+        self.values.get(slot as usize).copied().unwrap_or(CompactValue::NONE)
+    }
+    pub fn set(&mut self, slot: u32, value: CompactValue) {
+        let slot = slot as usize;
+        if slot >= self.values.len() {
+            self.values.resize(slot + 1, CompactValue::NONE);
+        }
+        self.values[slot] = value;
+    }
+}
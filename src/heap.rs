@@ -1,8 +1,12 @@
-use std::{alloc::{Layout, alloc, dealloc}, fmt, marker::{PhantomData, Unsize}, mem::size_of, ops::{Deref, DerefMut, Index, IndexMut, CoerceUnsized}, ptr::NonNull};
+use std::{alloc::{Layout, alloc, dealloc, handle_alloc_error}, fmt, marker::{PhantomData, Unsize}, ops::{Deref, DerefMut, Index, IndexMut, CoerceUnsized}, ptr::{NonNull, null_mut}, slice};
+
+const CHUNK_SIZE: usize = 64 * 1024;
 
 pub struct Heap {
+    chunks: Vec<(*mut u8, Layout)>,
     base: *mut u8,
     offset: usize,
+    size: usize,
 }
 
 pub struct HeapPtr<T: ?Sized> {
@@ -17,34 +21,68 @@ pub struct HeapSlice<T> {
 
 impl Heap {
     pub fn new() -> Heap {
-        unsafe {
-            Heap {
-                base: alloc(Layout::from_size_align(10000, 8).unwrap()),
-                offset: 0
-            }
+        let mut heap = Heap { chunks: vec![], base: null_mut(), offset: 0, size: 0 };
+        heap.add_chunk(Layout::from_size_align(CHUNK_SIZE, 16).unwrap());
+        heap
+    }
+    fn add_chunk(&mut self, layout: Layout) {
+        let base = unsafe { alloc(layout) };
+        if base.is_null() {
+            handle_alloc_error(layout)
         }
-    }
-    pub fn alloc_raw(&mut self, size: usize) -> *mut u8 {
-        let ptr = unsafe { self.base.add(self.offset) };
-        self.offset += size;
-        ptr
+        self.chunks.push((base, layout));
+        self.base = base;
+        self.offset = 0;
+        self.size = layout.size();
+    }
+    pub fn alloc_raw(&mut self, layout: Layout) -> *mut u8 {
+        let mut start = (self.offset + layout.align() - 1) & !(layout.align() - 1);
+        if start + layout.size() > self.size {
+            let chunk = Layout::from_size_align(layout.size().max(CHUNK_SIZE), layout.align().max(16)).unwrap();
+            self.add_chunk(chunk);
+            start = 0;
+        }
+        self.offset = start + layout.size();
+        unsafe { self.base.add(start) }
     }
     pub fn alloc<T>(&mut self, data: T) -> HeapPtr<T> {
-        let ptr = self.alloc_raw(size_of::<T>()) as *mut T;
-        unsafe { *ptr = data };
+        let ptr = self.alloc_raw(Layout::new::<T>()) as *mut T;
+        unsafe { ptr.write(data) };
         HeapPtr { ptr: NonNull::new(ptr).unwrap(), phantom: PhantomData }
     }
     pub fn alloc_slice<T>(&mut self, length: usize) -> HeapSlice<T> {
-        let ptr = self.alloc_raw(size_of::<T>() * length) as *mut T;
+        let ptr = self.alloc_raw(Layout::array::<T>(length).unwrap()) as *mut T;
         HeapSlice { ptr, length }
     }
 }
 
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl<T: ?Sized> HeapPtr<T> {
+    /// # Safety
+    /// The pointee must actually be a `U`.
+    pub unsafe fn cast<U>(self) -> HeapPtr<U> {
+        HeapPtr { ptr: self.ptr.cast(), phantom: PhantomData }
+    }
+    pub fn as_ptr(self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+    /// # Safety
+    /// `ptr` must have come from `HeapPtr::as_ptr` on a heap that is still alive.
+    pub unsafe fn from_raw(ptr: *mut T) -> HeapPtr<T> {
+        HeapPtr { ptr: NonNull::new_unchecked(ptr), phantom: PhantomData }
+    }
+}
+
 impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<HeapPtr<U>> for HeapPtr<T> {}
 
 impl<T: ?Sized> Clone for HeapPtr<T> {
     fn clone(&self) -> Self {
-        Self { ptr: self.ptr, phantom: PhantomData }
+        *self
     }
 }
 
@@ -52,7 +90,7 @@ impl<T: ?Sized> Copy for HeapPtr<T> {}
 
 impl<T> Clone for HeapSlice<T> {
     fn clone(&self) -> Self {
-        Self { ptr: self.ptr, length: self.length }
+        *self
     }
 }
 
@@ -90,8 +128,8 @@ impl<T> IndexMut<usize> for HeapSlice<T> {
 
 impl Drop for Heap {
     fn drop(&mut self) {
-        unsafe {
-            dealloc(self.base, Layout::from_size_align_unchecked(10000, 8));
+        for (base, layout) in self.chunks.drain(..) {
+            unsafe { dealloc(base, layout) }
         }
     }
 }
@@ -100,10 +138,19 @@ impl<T> HeapSlice<T> {
     pub fn len(&self) -> usize {
         self.length
     }
-    pub fn iter(&self) -> HeapSliceIter<T> {
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr, self.length) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.length) }
+    }
+    pub fn iter(&self) -> HeapSliceIter<'_, T> {
         HeapSliceIter { ptr: self.ptr, index: 0, length: self.length, phantom: PhantomData }
     }
-    pub fn iter_mut(&self) -> HeapSliceIterMut<T> {
+    pub fn iter_mut(&self) -> HeapSliceIterMut<'_, T> {
         HeapSliceIterMut { ptr: self.ptr, start: 0, end: self.length, phantom: PhantomData }
     }
 }
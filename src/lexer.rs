@@ -1,17 +1,64 @@
+use std::fmt;
+
+use unicode_xid::UnicodeXID;
+
 use crate::token::{Token, TokenKind};
 
+/// Words that are not keywords yet but may become ones, so they cannot be
+/// used as names.
+const RESERVED: [&str; 12] = [
+    "and", "break", "class", "const", "continue", "for", "in", "let", "loop", "match", "not", "or",
+];
+
 pub struct Lexer<'src> {
     source: &'src str,
     offset: usize,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LexError {
+    IntegerTooLarge,
+    InvalidDigit,
+    MissingDigits,
+    UnterminatedComment,
+    UnterminatedString,
+    IllegalChar(char),
+    ReservedWord(&'static str),
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::IntegerTooLarge => write!(f, "integer literal is too large"),
+            LexError::InvalidDigit => write!(f, "invalid digit in numeric literal"),
+            LexError::MissingDigits => write!(f, "expected digits in numeric literal"),
+            LexError::UnterminatedComment => write!(f, "unterminated block comment"),
+            LexError::UnterminatedString => write!(f, "unterminated string literal"),
+            LexError::IllegalChar(ch) => write!(f, "illegal character {:?}", ch),
+            LexError::ReservedWord(word) => write!(f, "`{}` is a reserved word", word),
+        }
+    }
+}
+
+/// Identifiers follow Unicode's XID rules, extended with `_` and `$`.
+fn is_ident_start(ch: char) -> bool {
+    ch.is_xid_start() || ch == '_' || ch == '$'
+}
+
+fn is_ident_continue(ch: char) -> bool {
+    ch.is_xid_continue() || ch == '$'
+}
+
 impl<'src> Lexer<'src> {
-    pub fn new(source: &'src str) -> Lexer {
+    pub fn new(source: &'src str) -> Lexer<'src> {
         Lexer { source, offset: 0 }
     }
     fn peek_char(&self) -> Option<char> {
         self.source[self.offset..].chars().next()
     }
+    fn peek_second_char(&self) -> Option<char> {
+        self.source[self.offset..].chars().nth(1)
+    }
     fn next_char(&mut self) {
         let mut chars = self.source[self.offset..].chars();
         let ch = match chars.next() {
@@ -26,13 +73,117 @@ impl<'src> Lexer<'src> {
     }
     fn double_char_token_if(&mut self, ch: char, single: TokenKind<'src>, double: TokenKind<'src>) -> TokenKind<'src> {
         self.next_char();
-        if self.peek_char().map_or(false, |ch1| ch == ch1) {
-            self.next_token();
+        if self.peek_char().is_some_and(|ch1| ch == ch1) {
+            self.next_char();
             double
         } else {
             single
         }
     }
+    /// Eats digits of the given radix, allowing `_` separators between
+    /// them. Returns false if there were no digits or a separator was not
+    /// followed by one.
+    fn eat_digits(&mut self, radix: u32) -> bool {
+        let mut digits = false;
+        let mut separator = false;
+        loop {
+            match self.peek_char() {
+                Some(ch) if ch.is_digit(radix) => {
+                    digits = true;
+                    separator = false;
+                }
+                Some('_') if digits && !separator => separator = true,
+                _ => return digits && !separator,
+            }
+            self.next_char();
+        }
+    }
+    /// Skips a block comment, which may contain nested block comments.
+    /// Returns false if the end of the source is reached first.
+    fn block_comment(&mut self) -> bool {
+        let mut depth = 0;
+        loop {
+            match (self.peek_char(), self.peek_second_char()) {
+                (Some('/'), Some('*')) => {
+                    self.next_char();
+                    depth += 1;
+                }
+                (Some('*'), Some('/')) => {
+                    self.next_char();
+                    depth -= 1;
+                    if depth == 0 {
+                        self.next_char();
+                        return true
+                    }
+                }
+                (None, _) => return false,
+                _ => {}
+            }
+            self.next_char();
+        }
+    }
+    fn number(&mut self) -> TokenKind<'src> {
+        let start = self.offset;
+        let radix = match (self.peek_char(), self.peek_second_char()) {
+            (Some('0'), Some('x' | 'X')) => 16,
+            (Some('0'), Some('b' | 'B')) => 2,
+            (Some('0'), Some('o' | 'O')) => 8,
+            _ => 10,
+        };
+        let kind = if radix != 10 {
+            self.next_char();
+            self.next_char();
+            let digits_start = self.offset;
+            if self.eat_digits(radix) {
+                let digits = self.source[digits_start..self.offset].replace('_', "");
+                match i64::from_str_radix(&digits, radix) {
+                    Ok(int) => TokenKind::Int(int),
+                    Err(_) => TokenKind::Error(LexError::IntegerTooLarge),
+                }
+            } else {
+                TokenKind::Error(LexError::MissingDigits)
+            }
+        } else {
+            let mut valid = self.peek_char() == Some('.') || self.eat_digits(10);
+            let mut float = false;
+            if self.peek_char() == Some('.') {
+                self.next_char();
+                float = true;
+                if self.peek_char().is_some_and(|ch| ch.is_ascii_digit()) {
+                    valid &= self.eat_digits(10);
+                }
+            }
+            if let Some('e' | 'E') = self.peek_char() {
+                self.next_char();
+                float = true;
+                if let Some('+' | '-') = self.peek_char() {
+                    self.next_char();
+                }
+                valid &= self.eat_digits(10);
+            }
+            let literal = self.source[start..self.offset].replace('_', "");
+            if !valid {
+                TokenKind::Error(LexError::MissingDigits)
+            } else if float {
+                match literal.parse() {
+                    Ok(float) => TokenKind::Float(float),
+                    Err(_) => TokenKind::Error(LexError::InvalidDigit),
+                }
+            } else {
+                match literal.parse() {
+                    Ok(int) => TokenKind::Int(int),
+                    Err(_) => TokenKind::Error(LexError::IntegerTooLarge),
+                }
+            }
+        };
+        if self.peek_char().is_some_and(is_ident_continue) {
+            while self.peek_char().is_some_and(is_ident_continue) {
+                self.next_char();
+            }
+            return TokenKind::Error(LexError::InvalidDigit)
+        }
+        kind
+    }
     pub fn next_token(&mut self) -> Token<'src> {
         let mut offset;
         let kind = loop {
@@ -44,9 +195,9 @@ impl<'src> Lexer<'src> {
             };
 
             match ch {
-                ch if ch.is_alphabetic() => {
+                ch if is_ident_start(ch) => {
                     let start = self.offset;
-                    while self.peek_char().map_or(false, char::is_alphanumeric) {
+                    while self.peek_char().is_some_and(is_ident_continue) {
                         self.next_char();
                     }
                     break match &self.source[start..self.offset] {
@@ -65,33 +216,25 @@ impl<'src> Lexer<'src> {
 
                         "print" => TokenKind::Print,
 
-                        name => TokenKind::Ident(name),
-                    }
-                }
-                ch if ch.is_numeric() => {
-                    let start = self.offset;
-                    while self.peek_char().map_or(false, char::is_numeric) {
-                        self.next_char();
-                    }
-                    break if self.peek_char() == Some('.') {
-                        self.next_char();
-                        while self.peek_char().map_or(false, char::is_numeric) {
-                            self.next_char()
-                        }
-                        TokenKind::Float(self.source[start..self.offset].parse().unwrap())
-                    } else {
-                        TokenKind::Int(self.source[start..self.offset].parse().unwrap())
+                        "import" => TokenKind::Import,
+                        "export" => TokenKind::Export,
+
+                        name => match RESERVED.iter().find(|word| **word == name) {
+                            Some(word) => TokenKind::Error(LexError::ReservedWord(word)),
+                            None => TokenKind::Ident(name),
+                        },
                     }
                 }
+                ch if ch.is_ascii_digit() => break self.number(),
                 ch if ch.is_whitespace() => {
-                    while self.peek_char().map_or(false, char::is_whitespace) {
+                    while self.peek_char().is_some_and(char::is_whitespace) {
                         self.next_char();
                     }
                 }
                 '"' => {
                     self.next_char();
                     let start = self.offset;
-                    while self.peek_char().map_or(false, |ch| ch != '"') {
+                    while self.peek_char().is_some_and(|ch| ch != '"') {
                         self.next_char();
                     }
                     break match self.peek_char() {
@@ -100,7 +243,7 @@ impl<'src> Lexer<'src> {
                             self.next_char();
                             TokenKind::String(str)
                         }
-                        None => TokenKind::Invalid
+                        None => TokenKind::Error(LexError::UnterminatedString)
                     }
                 }
 
@@ -112,27 +255,51 @@ impl<'src> Lexer<'src> {
                 ']' => break self.single_char_token(TokenKind::CloseSquareBrace),
                 ';' => break self.single_char_token(TokenKind::SemiColon),
                 ',' => break self.single_char_token(TokenKind::Comma),
+                '.' if self.peek_second_char().is_some_and(|ch| ch.is_ascii_digit()) => break self.number(),
                 '.' => break self.single_char_token(TokenKind::Dot),
 
                 '+' => break self.double_char_token_if('=', TokenKind::Plus, TokenKind::PlusEquals),
                 '-' => break self.double_char_token_if('=', TokenKind::Minus, TokenKind::MinusEquals),
                 '*' => break self.double_char_token_if('=', TokenKind::Multiply, TokenKind::MultiplyEquals),
-                '/' => break self.double_char_token_if('=', TokenKind::Divide, TokenKind::DivideEquals),
+                '#' => {
+                    self.next_char();
+                    let doc = self.peek_char() == Some('#');
+                    let start = self.offset + doc as usize;
+                    while self.peek_char().is_some_and(|ch| ch != '\n') {
+                        self.next_char();
+                    }
+                    if doc {
+                        break TokenKind::DocComment(self.source[start..self.offset].trim())
+                    }
+                }
+                '/' if self.peek_second_char() == Some('*') => {
+                    if !self.block_comment() {
+                        break TokenKind::Error(LexError::UnterminatedComment)
+                    }
+                }
+                '/' => {
+                    self.next_char();
+                    break match self.peek_char() {
+                        Some('/') => self.double_char_token_if('=', TokenKind::IntDivide, TokenKind::IntDivideEquals),
+                        Some('=') => self.single_char_token(TokenKind::DivideEquals),
+                        _ => TokenKind::Divide,
+                    }
+                }
                 '%' => break self.double_char_token_if('=', TokenKind::Modulus, TokenKind::ModulusEquals),
 
                 '!' => break self.double_char_token_if('=', TokenKind::Not, TokenKind::NotEqual),
                 '=' => break self.double_char_token_if('=', TokenKind::Equals, TokenKind::DoubleEquals),
                 '<' => break self.double_char_token_if('=', TokenKind::Less, TokenKind::LessOrEqual),
                 '>' => break self.double_char_token_if('=', TokenKind::Greater, TokenKind::GreaterOrEqual),
-                '&' => break self.double_char_token_if('&', TokenKind::Invalid, TokenKind::And),
-                '|' => break self.double_char_token_if('|', TokenKind::Invalid, TokenKind::Or),
+                '&' => break self.double_char_token_if('&', TokenKind::Error(LexError::IllegalChar('&')), TokenKind::And),
+                '|' => break self.double_char_token_if('|', TokenKind::Error(LexError::IllegalChar('|')), TokenKind::Or),
                 
                 _ => {
                     self.next_char();
-                    break TokenKind::Invalid
+                    break TokenKind::Error(LexError::IllegalChar(ch))
                 },
             }
         };
-        Token { kind, offset }
+        Token { kind, offset, end: self.offset }
     }
 }
\ No newline at end of file
@@ -0,0 +1,27 @@
+#![feature(unsize)]
+#![feature(coerce_unsized)]
+
+pub mod lexer;
+pub mod token;
+pub mod ast;
+pub mod parser;
+pub mod compiler;
+pub mod resolve;
+pub mod optimize;
+pub mod peephole;
+pub mod serialize;
+pub mod assemble;
+pub mod verify;
+pub mod opcode;
+pub mod vm;
+pub mod heap;
+pub mod list;
+pub mod string;
+pub mod builtins;
+pub mod func;
+pub mod symbols;
+pub mod globals;
+pub mod module;
+pub mod value;
+pub mod compact_value;
+pub mod diagnostic;
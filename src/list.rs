@@ -1,14 +1,14 @@
 use std::fmt;
 
-use crate::{heap::{HeapSlice, Heap}, value::{Value, RustValue}, vm::VirtualMachine, symbols::Symbol};
+use crate::{heap::{HeapSlice, Heap}, value::{Value, RustValue}, vm::{RuntimeError, VirtualMachine}, symbols::Symbol, compact_value::CompactValue};
 
 #[derive(Debug, Clone)]
 pub struct List {
-    slice: HeapSlice<Value>,
+    slice: HeapSlice<CompactValue>,
 }
 
 impl List {
-    pub fn new(heap: &mut Heap, length: usize, stack: &mut Vec<Value>) -> List {
+    pub fn new(heap: &mut Heap, length: usize, stack: &mut Vec<CompactValue>) -> List {
         let slice = heap.alloc_slice(length);
         for item in slice.iter_mut().rev() {
             *item = stack.pop().unwrap();
@@ -18,10 +18,13 @@ impl List {
 }
 
 impl RustValue for List {
-    fn get_property(&mut self, index: u8, vm: &mut VirtualMachine) -> Value {
-        match vm.program.symbols.get_name(Symbol::from_index(index as u32)) {
-            "len" => Value::Int(self.slice.len() as i64),
-            _ => panic!()
+    fn type_name(&self) -> &'static str {
+        "list"
+    }
+    fn get_property(&mut self, symbol: Symbol, vm: &mut VirtualMachine) -> Result<Value, RuntimeError> {
+        match vm.program.symbols.get_name(symbol) {
+            "len" => Ok(Value::Int(self.slice.len() as i64)),
+            name => Err(RuntimeError::NoProperty(self.type_name(), name.to_string())),
         }
     }
 }
@@ -30,7 +33,7 @@ impl fmt::Display for List {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "[")?;
         let mut iter = self.slice.iter();
-        if self.slice.len() > 0 {
+        if !self.slice.is_empty() {
             write!(f, "{:?}", iter.next().unwrap())?;
             for item in iter {
                 write!(f, ", {:?}", item)?;
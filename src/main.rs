@@ -1,48 +1,88 @@
-#![feature(unsize)]
-#![feature(coerce_unsized)]
+use std::{env, fs::{self, File}, io::{stderr, stdin, stdout, BufWriter, IsTerminal, Read, Write}, path::Path, process};
 
-use std::{fs, io::{stdin, stdout, Write}};
+use scripting::{heap::Heap, compiler::{Compiler, Program}, vm::{RuntimeError, VirtualMachine}, compact_value::CompactValue, globals::GlobalValues, diagnostic::DispDiagnostics, func::DispProgram, serialize, verify};
 
-use heap::Heap;
-use parser::Parser;
-use vm::VirtualMachine;
+const USAGE: &str = "\
+usage: scripting [COMMAND]
 
-use crate::{parser::{ParseError, Program}, func::DispFunc, value::Value};
+commands:
+    run FILE [ARGS...]        run a source or compiled file
+    FILE [ARGS...]            the same as `run FILE`
+    -e CODE [ARGS...]         run CODE
+    repl                      read and run lines interactively
+    check FILE                report errors in a file without running it
+    disasm FILE               print the bytecode of a source or compiled file
+    compile [-o OUTPUT] FILE  save the compiled program to OUTPUT, by default
+                              FILE with the extension `.sbc`
+    help                      print this message
 
-mod lexer;
-mod token;
-mod parser;
-mod opcode;
-mod vm;
-mod heap;
-mod list;
-mod func;
-mod symbols;
-mod value;
+A FILE of `-` reads standard input. With no command, standard input is run
+if it is not a terminal, and otherwise the REPL starts. Scripts see ARGS as
+the list `args`, and `exit(code)` ends the script with that exit status.";
 
-fn _repl() {
+/// Why a command failed. Each has its own exit status, following the BSD
+/// `sysexits.h` conventions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Failure {
+    Usage,
+    /// A file could not be read or was not a valid compiled program.
+    Input,
+    /// The source had syntax or compile errors.
+    Compile,
+    Runtime,
+    /// The compiled program could not be written.
+    Output,
+    /// The script called `exit`.
+    Exit(u8),
+}
+
+impl Failure {
+    fn status(self) -> i32 {
+        match self {
+            Failure::Usage => 64,
+            Failure::Compile => 65,
+            Failure::Input => 66,
+            Failure::Runtime => 70,
+            Failure::Output => 74,
+            Failure::Exit(status) => status.into(),
+        }
+    }
+}
+
+fn repl() {
     print!(">>> ");
     stdout().flush().unwrap();
     let mut source = String::new();
     let mut program = Program::new();
-    let mut last_scope = vec![symbols::RETURN];
-    let mut stack = vec![Value::None];
+    let mut stack = vec![CompactValue::NONE];
     let mut heap = Heap::new();
+    let mut globals = GlobalValues::new();
     loop {
-        stdin().read_line(&mut source).unwrap();
+        if stdin().read_line(&mut source).unwrap() == 0 {
+            break
+        }
         let entry_func = program.funcs.len();
-        match Parser::parse(&source, None, &mut program, last_scope.clone()) {
-            Ok(final_scope) => {
-                VirtualMachine::run(&program, entry_func, &mut stack, &mut heap);
-                last_scope = final_scope;
+        match Compiler::compile(&source, None, &mut program) {
+            Ok(()) => {
+                match VirtualMachine::run(&program, entry_func, &mut stack, &mut heap, &mut globals) {
+                    Ok(()) => {}
+                    Err(RuntimeError::Exit(status)) => {
+                        stdout().flush().unwrap();
+                        process::exit(status.into())
+                    }
+                    Err(err) => {
+                        println!("runtime error: {}", err);
+                        stack.truncate(1);
+                    }
+                }
                 source.clear();
                 print!(">>> ");
             }
-            Err(ParseError::EndOfInput) => {
+            Err(err) if err.at_end_of_input() => {
                 print!("... ");
             }
-            Err(ParseError::InvalidInput(err)) => {
-                println!("{}", err);
+            Err(err) => {
+                println!("{}", DispDiagnostics::new(&err, stdout().is_terminal()));
                 source.clear();
                 print!(">>> ");
             }
@@ -51,31 +91,119 @@ fn _repl() {
     }
 }
 
-fn _run_file(path: &str, disassemble: bool) {
-    let source = fs::read_to_string(path).unwrap();
+/// Reads a file, or standard input for `-`.
+fn read_input(path: &str) -> Result<Vec<u8>, Failure> {
+    let result = if path == "-" {
+        let mut bytes = vec![];
+        stdin().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        fs::read(path)
+    };
+    result.map_err(|err| {
+        eprintln!("cannot read \"{}\": {}", path, err);
+        Failure::Input
+    })
+}
+
+/// Compiles source code, printing any errors. `path` is `None` for code
+/// that does not come from a file. The program gets the global `args`, so
+/// it is there for `run_program` even after saving and loading.
+fn compile_source(source: &str, path: Option<&str>) -> Result<Program, Failure> {
     let mut program = Program::new();
-    match Parser::parse(&source, Some(path), &mut program, vec![symbols::RETURN]) {
-        Ok(_) => (),
-        Err(ParseError::EndOfInput) => {
-            println!("unexpected end of input");
-            return
+    program.define_global("args");
+    match Compiler::compile(source, path, &mut program) {
+        Ok(()) => Ok(program),
+        Err(err) => {
+            eprintln!("{}", DispDiagnostics::new(&err, stderr().is_terminal()));
+            Err(Failure::Compile)
         }
-        Err(ParseError::InvalidInput(err)) => {
-            println!("{}", err);
-            return
+    }
+}
+
+/// Loads a compiled program if the file starts with `serialize::MAGIC`,
+/// and compiles it as source otherwise.
+fn load_program(path: &str) -> Result<Program, Failure> {
+    let bytes = read_input(path)?;
+    if !bytes.starts_with(&serialize::MAGIC) {
+        let source = String::from_utf8(bytes).map_err(|_| {
+            eprintln!("\"{}\" is not valid UTF-8", path);
+            Failure::Input
+        })?;
+        return compile_source(&source, Some(path).filter(|path| *path != "-"))
+    }
+    let program = Program::read_from(&bytes[..]).map_err(|err| {
+        eprintln!("cannot load \"{}\": {}", path, err);
+        Failure::Input
+    })?;
+    verify::verify(&program).map_err(|err| {
+        eprintln!("invalid bytecode in \"{}\": {}", path, err);
+        Failure::Input
+    })?;
+    Ok(program)
+}
+
+fn run_program(program: &Program, args: &[&str]) -> Result<(), Failure> {
+    let mut stack = vec![CompactValue::NONE];
+    let mut heap = Heap::new();
+    let mut globals = GlobalValues::new();
+    let mut vm = VirtualMachine::new(program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_args(args);
+    vm.resume().map_err(|err| match err {
+        RuntimeError::Exit(status) => Failure::Exit(status),
+        err => {
+            eprintln!("runtime error: {}", err);
+            Failure::Runtime
         }
+    })
+}
+
+/// Compiles the file at `path` and saves the program to `output`.
+fn write_bytecode(path: &str, output: &str) -> Result<(), Failure> {
+    let program = load_program(path)?;
+    File::create(output).and_then(|file| program.write_to(BufWriter::new(file))).map_err(|err| {
+        eprintln!("cannot write \"{}\": {}", output, err);
+        Failure::Output
+    })
+}
+
+fn compile(args: &[&str]) -> Result<(), Failure> {
+    let (path, output) = match *args {
+        ["-o", output, path] | [path, "-o", output] => (path, output.to_string()),
+        [path] if path != "-" => (path, Path::new(path).with_extension("sbc").to_string_lossy().into_owned()),
+        _ => return Err(Failure::Usage),
     };
-    if disassemble {
-        for func in program.funcs.iter() {
-            println!("{}", DispFunc::new(func, &program.symbols))
+    write_bytecode(path, &output)
+}
+
+fn main_with_args(args: &[&str]) -> Result<(), Failure> {
+    match *args {
+        [] if stdin().is_terminal() => repl(),
+        [] => run_program(&load_program("-")?, &[])?,
+        ["repl"] => repl(),
+        ["help" | "-h" | "--help"] => println!("{}", USAGE),
+        ["run", path, ref args @ ..] => run_program(&load_program(path)?, args)?,
+        ["-e", source, ref args @ ..] => run_program(&compile_source(source, None)?, args)?,
+        ["check", path] => {
+            load_program(path)?;
+        }
+        ["disasm", path] => print!("{}", DispProgram::new(&load_program(path)?)),
+        ["compile", ref args @ ..] => compile(args)?,
+        [path, ref args @ ..] if (path == "-" || !path.starts_with('-')) && !["run", "repl", "check", "disasm", "compile", "help"].contains(&path) => {
+            run_program(&load_program(path)?, args)?
         }
+        _ => return Err(Failure::Usage),
     }
-    let mut stack = vec![Value::None];
-    let mut heap = Heap::new();
-    VirtualMachine::run(&program, 0, &mut stack, &mut heap);
+    Ok(())
 }
 
 fn main() {
-    _repl();
-    // _run_file("example.txt", true)
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if let Err(failure) = main_with_args(&args) {
+        if failure == Failure::Usage {
+            eprintln!("{}", USAGE);
+        }
+        stdout().flush().unwrap();
+        process::exit(failure.status());
+    }
+}
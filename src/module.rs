@@ -0,0 +1,154 @@
+use std::{collections::HashMap, fmt, fs, io, path::Path};
+
+use crate::{diagnostic::Cause, symbols::Symbol, value::{Value, RustValue}, vm::{RuntimeError, VirtualMachine}};
+
+pub const MAIN: usize = 0;
+/// The path of the main module, which is not loaded by a `ModuleLoader`.
+pub const MAIN_PATH: &str = "<main>";
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub path: String,
+    pub entry_func: usize,
+    pub exports: Vec<Symbol>,
+    /// Global slot caching the module value once the module has run.
+    pub slot: u32,
+    pub loaded: bool,
+}
+
+/// Finds and reads the source of imported modules, so embedders can serve
+/// modules from somewhere other than the file system.
+pub trait ModuleLoader {
+    /// Turns the path written in an `import` into the key the module is
+    /// cached under. `importer` is the key of the importing module, if any.
+    fn resolve(&self, importer: Option<&str>, path: &str) -> String;
+    fn load(&mut self, path: &str) -> io::Result<String>;
+}
+
+/// Loads modules from disk, relative to the importing file. Paths without
+/// an extension get `extension` appended.
+#[derive(Debug, Clone)]
+pub struct FileLoader {
+    extension: String,
+}
+
+/// Serves modules from sources registered up front.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLoader {
+    sources: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Load(String, io::Error),
+    Cycle(String),
+    InModule(Cause),
+    NotExported(String, String),
+    InvalidName(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleValue {
+    module: usize,
+    path: Box<str>,
+}
+
+impl Module {
+    pub fn new(path: &str, entry_func: usize, slot: u32) -> Module {
+        Module { path: path.to_string(), entry_func, exports: vec![], slot, loaded: false }
+    }
+    pub fn is_exported(&self, symbol: Symbol) -> bool {
+        self.exports.contains(&symbol)
+    }
+}
+
+impl FileLoader {
+    pub fn new(extension: &str) -> FileLoader {
+        FileLoader { extension: extension.to_string() }
+    }
+}
+
+impl Default for FileLoader {
+    fn default() -> Self {
+        FileLoader::new("txt")
+    }
+}
+
+impl ModuleLoader for FileLoader {
+    fn resolve(&self, importer: Option<&str>, path: &str) -> String {
+        let dir = importer.and_then(|importer| Path::new(importer).parent()).unwrap_or(Path::new(""));
+        let mut resolved = dir.join(path);
+        if resolved.extension().is_none() {
+            resolved.set_extension(&self.extension);
+        }
+        resolved.to_string_lossy().into_owned()
+    }
+    fn load(&mut self, path: &str) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+}
+
+impl MemoryLoader {
+    pub fn new() -> MemoryLoader {
+        MemoryLoader { sources: HashMap::new() }
+    }
+    pub fn insert(&mut self, path: &str, source: &str) {
+        self.sources.insert(path.to_string(), source.to_string());
+    }
+}
+
+impl ModuleLoader for MemoryLoader {
+    fn resolve(&self, _importer: Option<&str>, path: &str) -> String {
+        path.to_string()
+    }
+    fn load(&mut self, path: &str) -> io::Result<String> {
+        self.sources.get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such module"))
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Load(path, err) => write!(f, "cannot load module \"{}\": {}", path, err),
+            ImportError::Cycle(path) => write!(f, "import cycle through module \"{}\"", path),
+            ImportError::InModule(cause) => write!(f, "error in module \"{}\"", cause.path),
+            ImportError::NotExported(path, name) => write!(f, "module \"{}\" does not export `{}`", path, name),
+            ImportError::InvalidName(path) => write!(f, "cannot name module \"{}\", use `import ... from`", path),
+        }
+    }
+}
+
+impl ModuleValue {
+    pub fn new(module: usize, path: &str) -> ModuleValue {
+        ModuleValue { module, path: path.into() }
+    }
+}
+
+impl RustValue for ModuleValue {
+    fn type_name(&self) -> &'static str {
+        "module"
+    }
+    /// Only exported variables can be read.
+    fn get_property(&mut self, symbol: Symbol, vm: &mut VirtualMachine) -> Result<Value, RuntimeError> {
+        let program = vm.program;
+        let slot = Some(symbol)
+            .filter(|symbol| program.modules[self.module].is_exported(*symbol))
+            .and_then(|symbol| program.globals.resolve(self.module, symbol))
+            .ok_or_else(|| RuntimeError::NoProperty(self.type_name(), program.symbols.get_name(symbol).to_string()))?;
+        Ok(vm.globals.get(slot).decode())
+    }
+}
+
+impl fmt::Display for ModuleValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "module \"{}\"", self.path)
+    }
+}
+
+/// Name a plain `import "path/to/mod"` binds the module to.
+pub fn binding_name(path: &str) -> &str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.split('.').next().unwrap_or(name)
+}
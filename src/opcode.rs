@@ -1,12 +1,13 @@
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-#[derive(Debug, Clone, Copy, TryFromPrimitive, IntoPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
 pub enum Opcode {
     Add,
     Subtract,
     Multiply,
     Divide,
+    IntDivide,
     Modulus,
 
     Equal,
@@ -16,28 +17,153 @@ pub enum Opcode {
     LessOrEqual,
     GreaterOrEqual,
 
-    PushInt,
-    PushFloat,
+    PushConst,
+    PushConstWide,
     PushTrue,
     PushFalse,
     PushNone,
-    PushFunc,
+    PushBuiltin,
+    ImportModule,
     PushLoad,
     PushClosureLoad,
     PushList,
     PushPropLoad,
+    PushGlobalLoad,
 
     PopStore,
     PopPrint,
-    PopPropStore,
     PopClosureStore,
+    PopGlobalStore,
 
-    Jump,
-    JumpIfNot,
+    Jump8,
+    Jump16,
+    Jump32,
+    JumpIf8,
+    JumpIf16,
+    JumpIf32,
+    JumpIfNot8,
+    JumpIfNot16,
+    JumpIfNot32,
+    JumpIfNone8,
+    JumpIfNone16,
+    JumpIfNone32,
     Drop,
 
+    IncLocal,
+    AddLocalConst,
+    JumpIfNotLessLocals8,
+    JumpIfNotLessLocals16,
+    JumpIfNotLessLocals32,
+
     Call,
     Return,
 
+    /// Prefix giving the next instruction a two-byte operand instead of
+    /// one. Only valid before the instructions `FuncBuilder::push_op`
+    /// emits: loads, stores, `Drop`, `Call` and `PopPrint`.
+    Wide,
+
     Finish,
-}
\ No newline at end of file
+}
+
+impl Opcode {
+    /// The number of operand bytes following the opcode. For `Wide` this
+    /// covers the opcode it prefixes and that opcode's wide operand.
+    pub fn operand_len(self) -> usize {
+        match self {
+            Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide | Opcode::IntDivide | Opcode::Modulus |
+            Opcode::Equal | Opcode::NotEqual | Opcode::Less | Opcode::Greater | Opcode::LessOrEqual | Opcode::GreaterOrEqual |
+            Opcode::PushTrue | Opcode::PushFalse | Opcode::PushNone |
+            Opcode::Return | Opcode::Finish => 0,
+
+            Opcode::PushLoad | Opcode::PopStore | Opcode::PushClosureLoad | Opcode::PopClosureStore |
+            Opcode::PushPropLoad | Opcode::PushBuiltin |
+            Opcode::Drop | Opcode::Call | Opcode::PopPrint | Opcode::IncLocal | Opcode::PushConst => 1,
+
+            Opcode::AddLocalConst => 2,
+
+            Opcode::PushList | Opcode::PushGlobalLoad | Opcode::PopGlobalStore |
+            Opcode::ImportModule | Opcode::PushConstWide => 4,
+
+            Opcode::Wide => 3,
+
+            Opcode::Jump8 | Opcode::Jump16 | Opcode::Jump32 |
+            Opcode::JumpIf8 | Opcode::JumpIf16 | Opcode::JumpIf32 |
+            Opcode::JumpIfNot8 | Opcode::JumpIfNot16 | Opcode::JumpIfNot32 |
+            Opcode::JumpIfNone8 | Opcode::JumpIfNone16 | Opcode::JumpIfNone32 |
+            Opcode::JumpIfNotLessLocals8 | Opcode::JumpIfNotLessLocals16 | Opcode::JumpIfNotLessLocals32 => {
+                let (kind, width) = self.jump().unwrap();
+                kind.prefix_len() + width
+            }
+        }
+    }
+    /// The kind of a jump instruction and the width in bytes of its offset.
+    pub fn jump(self) -> Option<(JumpKind, usize)> {
+        let kind = match self {
+            Opcode::Jump8 | Opcode::Jump16 | Opcode::Jump32 => JumpKind::Always,
+            Opcode::JumpIf8 | Opcode::JumpIf16 | Opcode::JumpIf32 => JumpKind::If,
+            Opcode::JumpIfNot8 | Opcode::JumpIfNot16 | Opcode::JumpIfNot32 => JumpKind::IfNot,
+            Opcode::JumpIfNone8 | Opcode::JumpIfNone16 | Opcode::JumpIfNone32 => JumpKind::IfNone,
+            Opcode::JumpIfNotLessLocals8 | Opcode::JumpIfNotLessLocals16 | Opcode::JumpIfNotLessLocals32 => JumpKind::IfNotLessLocals,
+            _ => return None,
+        };
+        let width = match self {
+            Opcode::Jump8 | Opcode::JumpIf8 | Opcode::JumpIfNot8 | Opcode::JumpIfNone8 | Opcode::JumpIfNotLessLocals8 => 1,
+            Opcode::Jump16 | Opcode::JumpIf16 | Opcode::JumpIfNot16 | Opcode::JumpIfNone16 | Opcode::JumpIfNotLessLocals16 => 2,
+            _ => 4,
+        };
+        Some((kind, width))
+    }
+}
+
+/// What a jump instruction tests before jumping. Each kind has forms with
+/// a one, two and four byte signed offset, counted from the end of the
+/// jump instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JumpKind {
+    Always,
+    /// Pops a bool and jumps if it is true.
+    If,
+    /// Pops a bool and jumps if it is false.
+    IfNot,
+    /// Pops a value and jumps if it is `none`.
+    IfNone,
+    /// Takes two local slots and jumps unless the first is less than the
+    /// second, without touching the stack.
+    IfNotLessLocals,
+}
+
+impl JumpKind {
+    /// The jump instruction of this kind with an offset of `width` bytes.
+    pub fn opcode(self, width: usize) -> Opcode {
+        let opcodes = match self {
+            JumpKind::Always => [Opcode::Jump8, Opcode::Jump16, Opcode::Jump32],
+            JumpKind::If => [Opcode::JumpIf8, Opcode::JumpIf16, Opcode::JumpIf32],
+            JumpKind::IfNot => [Opcode::JumpIfNot8, Opcode::JumpIfNot16, Opcode::JumpIfNot32],
+            JumpKind::IfNone => [Opcode::JumpIfNone8, Opcode::JumpIfNone16, Opcode::JumpIfNone32],
+            JumpKind::IfNotLessLocals => [Opcode::JumpIfNotLessLocals8, Opcode::JumpIfNotLessLocals16, Opcode::JumpIfNotLessLocals32],
+        };
+        match width {
+            1 => opcodes[0],
+            2 => opcodes[1],
+            _ => opcodes[2],
+        }
+    }
+    /// The number of operand bytes before the offset.
+    pub fn prefix_len(self) -> usize {
+        match self {
+            JumpKind::IfNotLessLocals => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// Decodes a jump offset from its one, two or four big-endian bytes.
+pub fn read_jump_offset(bytes: &[u8]) -> isize {
+    match *bytes {
+        [a] => a as i8 as isize,
+        [a, b] => i16::from_be_bytes([a, b]) as isize,
+        [a, b, c, d] => i32::from_be_bytes([a, b, c, d]) as isize,
+        _ => panic!("invalid jump offset width {}", bytes.len()),
+    }
+}
@@ -0,0 +1,224 @@
+use std::mem;
+
+use crate::{ast::{BinaryOp, Block, Else, Expr, ExprKind, FuncBody, Ident, Stmt, StmtKind}, vm::{floor_div, floor_mod, float_floor_mod}};
+
+/// Simplifies a module's syntax tree before it is compiled: arithmetic and
+/// comparisons on literals are folded, branches that can never run are
+/// removed along with code after a `return`, and block locals that are
+/// never used are dropped.
+///
+/// Folding follows the VM exactly. An operation that would fail at run
+/// time, such as dividing by zero, is left alone so it still fails there.
+pub fn optimize(stmts: &mut Vec<Stmt>) {
+    optimize_stmts(stmts, false);
+}
+
+fn optimize_stmts(stmts: &mut Vec<Stmt>, local: bool) {
+    for stmt in mem::take(stmts) {
+        if let Some(stmt) = optimize_stmt(stmt) {
+            let returns = matches!(stmt.kind, StmtKind::Return(_));
+            stmts.push(stmt);
+            if returns {
+                break
+            }
+        }
+    }
+    if local {
+        remove_unused_vars(stmts);
+    }
+}
+
+fn optimize_block(block: &mut Block) {
+    optimize_stmts(&mut block.stmts, true);
+}
+
+/// Returns `None` if the statement does nothing.
+fn optimize_stmt(mut stmt: Stmt) -> Option<Stmt> {
+    match &mut stmt.kind {
+        StmtKind::Var { value, .. } | StmtKind::Assign { value, .. } | StmtKind::Return(value) => fold_expr(value),
+        StmtKind::Call(_, values) | StmtKind::Print(values) => values.iter_mut().for_each(fold_expr),
+        StmtKind::While(cond, body) => {
+            fold_expr(cond);
+            if cond.kind == ExprKind::Bool(false) {
+                return None
+            }
+            optimize_block(body);
+        }
+        StmtKind::If(cond, then, otherwise) => {
+            fold_expr(cond);
+            optimize_block(then);
+            *otherwise = match otherwise.take() {
+                Some(Else::Block(mut block)) => {
+                    optimize_block(&mut block);
+                    Some(Else::Block(block))
+                }
+                Some(Else::If(stmt)) => optimize_stmt(*stmt).map(|stmt| Else::If(Box::new(stmt))),
+                None => None,
+            };
+            let span = stmt.span;
+            match (&cond.kind, otherwise.take()) {
+                (ExprKind::Bool(true), _) => return Some(Stmt { kind: StmtKind::Block(mem::replace(then, Block { stmts: vec![], span })), span }),
+                (ExprKind::Bool(false), None) => return None,
+                (ExprKind::Bool(false), Some(Else::Block(block))) => return Some(Stmt { kind: StmtKind::Block(block), span }),
+                (ExprKind::Bool(false), Some(Else::If(stmt))) => return Some(*stmt),
+                (_, taken) => *otherwise = taken,
+            }
+        }
+        StmtKind::Block(block) => optimize_block(block),
+        StmtKind::Import { .. } => {}
+    }
+    Some(stmt)
+}
+
+fn fold_expr(expr: &mut Expr) {
+    let folded = match &mut expr.kind {
+        ExprKind::Call(_, values) | ExprKind::List(values) => {
+            values.iter_mut().for_each(fold_expr);
+            None
+        }
+        ExprKind::Func(_, FuncBody::Block(block)) => {
+            optimize_block(block);
+            None
+        }
+        ExprKind::Func(_, FuncBody::Expr(value)) | ExprKind::Property(value, _) => {
+            fold_expr(value);
+            None
+        }
+        ExprKind::Binary(op, lhs, rhs) => {
+            fold_expr(lhs);
+            fold_expr(rhs);
+            fold_binary(*op, &lhs.kind, &rhs.kind)
+        }
+        _ => None,
+    };
+    if let Some(kind) = folded {
+        expr.kind = kind;
+    }
+}
+
+fn as_float(kind: &ExprKind) -> Option<f64> {
+    match *kind {
+        ExprKind::Int(int) => Some(int as f64),
+        ExprKind::Float(float) => Some(float),
+        _ => None,
+    }
+}
+
+fn fold_binary<'src>(op: BinaryOp, lhs: &ExprKind<'src>, rhs: &ExprKind<'src>) -> Option<ExprKind<'src>> {
+    use ExprKind::{Int, Float, Bool};
+    match op {
+        BinaryOp::Equal => literal_eq(lhs, rhs).map(Bool),
+        BinaryOp::NotEqual => literal_eq(lhs, rhs).map(|eq| Bool(!eq)),
+        BinaryOp::Less | BinaryOp::Greater | BinaryOp::LessOrEqual | BinaryOp::GreaterOrEqual => {
+            let ord = match (lhs, rhs) {
+                (Int(a), Int(b)) => Some(a.cmp(b)),
+                _ => as_float(lhs)?.partial_cmp(&as_float(rhs)?),
+            };
+            Some(Bool(ord.is_some_and(|ord| match op {
+                BinaryOp::Less => ord.is_lt(),
+                BinaryOp::Greater => ord.is_gt(),
+                BinaryOp::LessOrEqual => ord.is_le(),
+                _ => ord.is_ge(),
+            })))
+        }
+        BinaryOp::Divide => match as_float(rhs)? {
+            0.0 => None,
+            b => Some(Float(as_float(lhs)? / b)),
+        },
+        _ => match (lhs, rhs) {
+            (Int(a), Int(b)) => match op {
+                BinaryOp::Add => a.checked_add(*b),
+                BinaryOp::Subtract => a.checked_sub(*b),
+                BinaryOp::Multiply => a.checked_mul(*b),
+                BinaryOp::IntDivide => floor_div(*a, *b),
+                _ => floor_mod(*a, *b),
+            }.map(Int),
+            _ => {
+                let (a, b) = (as_float(lhs)?, as_float(rhs)?);
+                match op {
+                    BinaryOp::Add => Some(a + b),
+                    BinaryOp::Subtract => Some(a - b),
+                    BinaryOp::Multiply => Some(a * b),
+                    _ if b == 0.0 => None,
+                    BinaryOp::IntDivide => Some((a / b).floor()),
+                    _ => Some(float_floor_mod(a, b)),
+                }.map(Float)
+            }
+        },
+    }
+}
+
+/// Equality of two literals, matching `PartialEq for Value`: values of
+/// different types are never equal.
+fn literal_eq(lhs: &ExprKind, rhs: &ExprKind) -> Option<bool> {
+    use ExprKind::{Int, Float, String, Bool, None};
+    match (lhs, rhs) {
+        (Int(a), Int(b)) => Some(a == b),
+        (Float(a), Float(b)) => Some(a == b),
+        (String(a), String(b)) => Some(a == b),
+        (Bool(a), Bool(b)) => Some(a == b),
+        (None, None) => Some(true),
+        (Int(_) | Float(_) | String(_) | Bool(_) | None, Int(_) | Float(_) | String(_) | Bool(_) | None) => Some(false),
+        _ => Option::None,
+    }
+}
+
+/// Removes locals initialised with a value that has no side effects and
+/// whose name is never mentioned again in the block. Any later mention
+/// counts, even a redefinition, since the first of two locals with the same
+/// name is the one that gets resolved.
+fn remove_unused_vars(stmts: &mut Vec<Stmt>) {
+    let mut i = 0;
+    while i < stmts.len() {
+        let unused = match &stmts[i].kind {
+            StmtKind::Var { name, value, .. } => is_pure(value) && !stmts[i + 1..].iter().any(|stmt| stmt_mentions(stmt, name.name)),
+            _ => false,
+        };
+        if unused {
+            stmts.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+fn is_pure(expr: &Expr) -> bool {
+    matches!(expr.kind, ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::String(_) | ExprKind::Bool(_) | ExprKind::None | ExprKind::Func(..))
+}
+
+fn block_mentions(block: &Block, name: &str) -> bool {
+    block.stmts.iter().any(|stmt| stmt_mentions(stmt, name))
+}
+
+fn stmt_mentions(stmt: &Stmt, name: &str) -> bool {
+    let is = |ident: &Ident| ident.name == name;
+    match &stmt.kind {
+        StmtKind::Var { name: ident, value, .. } | StmtKind::Assign { name: ident, value, .. } => is(ident) || expr_mentions(value, name),
+        StmtKind::Call(ident, args) => is(ident) || args.iter().any(|arg| expr_mentions(arg, name)),
+        StmtKind::While(cond, body) => expr_mentions(cond, name) || block_mentions(body, name),
+        StmtKind::If(cond, then, otherwise) => expr_mentions(cond, name) || block_mentions(then, name) || match otherwise {
+            Some(Else::Block(block)) => block_mentions(block, name),
+            Some(Else::If(stmt)) => stmt_mentions(stmt, name),
+            Option::None => false,
+        },
+        StmtKind::Print(values) => values.iter().any(|value| expr_mentions(value, name)),
+        StmtKind::Return(value) => expr_mentions(value, name),
+        StmtKind::Import { names, path } => names.iter().any(is) || crate::module::binding_name(path) == name,
+        StmtKind::Block(block) => block_mentions(block, name),
+    }
+}
+
+fn expr_mentions(expr: &Expr, name: &str) -> bool {
+    match &expr.kind {
+        ExprKind::Var(ident) => ident.name == name,
+        ExprKind::Call(ident, args) => ident.name == name || args.iter().any(|arg| expr_mentions(arg, name)),
+        ExprKind::List(values) => values.iter().any(|value| expr_mentions(value, name)),
+        ExprKind::Func(params, body) => params.iter().any(|param| param.name == name) || match body {
+            FuncBody::Block(block) => block_mentions(block, name),
+            FuncBody::Expr(value) => expr_mentions(value, name),
+        },
+        ExprKind::Property(value, _) => expr_mentions(value, name),
+        ExprKind::Binary(_, lhs, rhs) => expr_mentions(lhs, name) || expr_mentions(rhs, name),
+        _ => false,
+    }
+}
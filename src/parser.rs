@@ -1,18 +1,14 @@
-use core::fmt;
+use crate::{lexer::Lexer, token::{Token, TokenKind}, diagnostic::{Diagnostic, Diagnostics, Span}};
+use crate::ast::{Block, BinaryOp, Else, Expr, ExprKind, FuncBody, Ident, Stmt, StmtKind};
 
-use crate::{lexer::Lexer, opcode::Opcode, token::{Token, TokenKind, pos_at_offset}, func::{Func, FuncBuilder}, symbols::{Symbols, Symbol}};
-
-pub struct Parser<'a> {
-    source: &'a str,
-    path: Option<&'a str>,
-    lexer: Lexer<'a>,
-    token: Token<'a>,
-    program: &'a mut Program,
-}
-
-pub struct Program {
-    pub funcs: Vec<Func>,
-    pub symbols: Symbols,
+pub struct Parser<'src> {
+    lexer: Lexer<'src>,
+    token: Token<'src>,
+    /// End of the last token eaten, where the span of the node being
+    /// parsed ends.
+    last_end: usize,
+    doc: Vec<&'src str>,
+    diagnostics: Vec<Diagnostic>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
@@ -24,45 +20,28 @@ enum Precedence {
     Top,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum ParseError<'src> {
-    InvalidInput(InvalidInput<'src>),
-    EndOfInput,
-}
-
-#[derive(Debug, Clone, Copy)]
-pub struct InvalidInput<'src> {
-    source: &'src str,
-    path: Option<&'src str>,
-    offset: usize,
-}
-
-impl<'src> fmt::Display for InvalidInput<'src> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let pos = pos_at_offset(self.source, self.offset);
-        match self.path {
-            Some(path) => writeln!(f, "syntax error at {}:{}:{}", path, pos.line, pos.column),
-            None => writeln!(f, "syntax error at {}:{}", pos.line, pos.column),
-        }?;
-        writeln!(f, "'{}'", self.source.lines().nth(pos.line as usize - 1).unwrap())?;
-        for _ in 0..pos.column {
-            write!(f, " ")?;
+impl<'src> Parser<'src> {
+    /// Doc comments are not part of the grammar; the ones directly before
+    /// the current token are kept in `doc` until the next token is read.
+    fn next_token(&mut self) {
+        self.last_end = self.token.end;
+        self.doc.clear();
+        loop {
+            self.token = self.lexer.next_token();
+            match self.token.kind {
+                TokenKind::DocComment(line) => self.doc.push(line),
+                _ => break,
+            }
         }
-        write!(f, "^")
-    }
-}
-
-impl Program {
-    pub fn new() -> Program {
-        Program { funcs: vec![], symbols: Symbols::new() }
     }
-}
-
-impl<'a> Parser<'a> {
-    fn next_token(&mut self) {
-        self.token = self.lexer.next_token();
+    fn doc_comment(&mut self) -> Option<String> {
+        if self.doc.is_empty() {
+            None
+        } else {
+            Some(self.doc.join("\n"))
+        }
     }
-    fn eat_token(&mut self, kind: TokenKind<'a>) -> bool {
+    fn eat_token(&mut self, kind: TokenKind<'src>) -> bool {
         if self.token.kind == kind {
             self.next_token();
             true
@@ -70,317 +49,347 @@ impl<'a> Parser<'a> {
             false
         }
     }
-    fn expect_ident(&mut self) -> Result<&'a str, ParseError<'a>> {
+    fn span(&self) -> Span {
+        Span::new(self.token.offset, self.token.end)
+    }
+    /// Span from `start` to the end of the last token eaten.
+    fn span_from(&self, start: Span) -> Span {
+        Span::new(start.start, self.last_end)
+    }
+    /// Reports the current token as not being the one expected.
+    fn unexpected(&self, expected: &str) -> Diagnostic {
+        match self.token.kind {
+            TokenKind::Error(err) => Diagnostic::new(err.to_string(), self.span()),
+            found => Diagnostic::new(format!("expected {}, found {}", expected, found), self.span()),
+        }
+    }
+    fn expect_ident(&mut self, context: &str) -> Result<Ident<'src>, Diagnostic> {
         match self.token.kind {
             TokenKind::Ident(name) => {
+                let span = self.span();
                 self.next_token();
-                Ok(name)
+                Ok(Ident { name, span })
             }
-            _ => Err(self.parse_error()),
+            _ => Err(self.unexpected(&format!("identifier {}", context))),
         }
     }
-    fn expect_token(&mut self, kind: TokenKind<'a>) -> Result<(), ParseError<'a>> {
+    fn expect_token(&mut self, kind: TokenKind<'src>, context: &str) -> Result<(), Diagnostic> {
         if self.eat_token(kind) {
             Ok(())
         } else {
-            Err(self.parse_error())
+            Err(self.unexpected(&format!("{} {}", kind, context)))
         }
     }
-    fn parse_error(&mut self) -> ParseError<'a> {
-        match self.token.kind {
-            TokenKind::End => ParseError::EndOfInput,
-            _ => ParseError::InvalidInput(InvalidInput {
-                path: self.path,
-                source: self.source,
-                offset: self.token.offset,
-            })
+    /// Skips tokens until one that can start a statement so parsing can
+    /// carry on after an error. Blocks opened along the way are skipped
+    /// whole, and an unmatched `}` is left for the enclosing block.
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.token.kind {
+                TokenKind::End => return,
+                TokenKind::OpenCurlyBrace => depth += 1,
+                TokenKind::CloseCurlyBrace if depth == 0 => return,
+                TokenKind::CloseCurlyBrace => depth -= 1,
+                TokenKind::SemiColon if depth == 0 => {
+                    self.next_token();
+                    return
+                }
+                TokenKind::Var | TokenKind::While | TokenKind::If | TokenKind::Print |
+                TokenKind::Return | TokenKind::Import | TokenKind::Export if depth == 0 => return,
+                _ => {}
+            }
+            self.next_token();
         }
     }
-    fn parse_call(&mut self, func: &mut FuncBuilder<'a, '_>, symbol: Symbol) -> Result<(), ParseError<'a>> {
-        self.next_token();
-        func.push_bytes(&[Opcode::PushNone.into()]);
-        let mut arg_count = 0;
+    /// Parses a statement, recording any error and resynchronising instead
+    /// of returning it.
+    fn parse_stmt_or_recover(&mut self) -> Option<Stmt<'src>> {
+        let offset = self.token.offset;
+        match self.parse_stmt() {
+            Ok(stmt) => Some(stmt),
+            Err(err) => {
+                self.diagnostics.push(err);
+                if self.token.offset == offset {
+                    self.next_token();
+                }
+                self.synchronize();
+                None
+            }
+        }
+    }
+    /// Parses comma separated expressions up to the closing `)`, which may
+    /// be straight after the opening one.
+    fn parse_args(&mut self, context: &str) -> Result<Vec<Expr<'src>>, Diagnostic> {
+        let mut args = vec![];
         if !self.eat_token(TokenKind::CloseBrace) {
             loop {
-                self.parse_expr(func)?;
-                arg_count += 1;
+                args.push(self.parse_expr()?);
                 if !self.eat_token(TokenKind::Comma) {
                     break
                 }
             }
-            self.expect_token(TokenKind::CloseBrace)?;
+            self.expect_token(TokenKind::CloseBrace, context)?;
         }
-        let var = func.resolve_var(symbol).unwrap();
-        func.push_var(var);
-        func.push_bytes(&[Opcode::Call.into(), arg_count]);
-        Ok(())
+        Ok(args)
     }
-    fn parse_value(&mut self, func: &mut FuncBuilder<'a, '_>) -> Result<(), ParseError<'a>> {
-        match self.token.kind {
+    fn parse_value(&mut self) -> Result<Expr<'src>, Diagnostic> {
+        let start = self.span();
+        let kind = match self.token.kind {
             TokenKind::Ident(name) => {
                 self.next_token();
-                let symbol = self.program.symbols.add(name);
-                match self.token.kind {
-                    TokenKind::OpenBrace => {
-                        self.parse_call(func, symbol)?;
-                    }
-                    _ => {
-                        let var = func.resolve_var(symbol).unwrap();
-                        func.push_var(var);
-                    }
+                let ident = Ident { name, span: start };
+                if self.eat_token(TokenKind::OpenBrace) {
+                    ExprKind::Call(ident, self.parse_args("after arguments")?)
+                } else {
+                    ExprKind::Var(ident)
                 }
             }
             TokenKind::Int(val) => {
                 self.next_token();
-                func.push_bytes(&[Opcode::PushInt.into()]);
-                func.push_bytes(&val.to_be_bytes());
+                ExprKind::Int(val)
             }
             TokenKind::Float(val) => {
                 self.next_token();
-                func.push_bytes(&[Opcode::PushFloat.into()]);
-                func.push_bytes(&val.to_be_bytes());
+                ExprKind::Float(val)
+            }
+            TokenKind::String(string) => {
+                self.next_token();
+                ExprKind::String(string)
             }
             TokenKind::True => {
                 self.next_token();
-                func.push_bytes(&[Opcode::PushTrue.into()]);
+                ExprKind::Bool(true)
             }
             TokenKind::False => {
                 self.next_token();
-                func.push_bytes(&[Opcode::PushFalse.into()]);
+                ExprKind::Bool(false)
             }
             TokenKind::None => {
                 self.next_token();
-                func.push_bytes(&[Opcode::PushNone.into()]);
+                ExprKind::None
             }
             TokenKind::OpenBrace => {
                 self.next_token();
-                self.parse_expr(func)?;
-                self.expect_token(TokenKind::CloseBrace)?;
+                let expr = self.parse_expr()?;
+                self.expect_token(TokenKind::CloseBrace, "to close `(`")
+                    .map_err(|err| err.with_label(start, "unclosed `(`"))?;
+                expr.kind
             }
             TokenKind::List => {
                 self.next_token();
-                self.expect_token(TokenKind::OpenBrace)?;
-                let mut length: u32 = 0;
-                if !self.eat_token(TokenKind::CloseBrace) {
-                    loop {
-                        self.parse_expr(func)?;
-                        length += 1;
-                        if !self.eat_token(TokenKind::Comma) {
-                            break
-                        }
-                    }
-                    self.expect_token(TokenKind::CloseBrace)?;
-                }
-                func.push_bytes(&[Opcode::PushList.into()]);
-                func.push_bytes(&length.to_be_bytes());
+                self.expect_token(TokenKind::OpenBrace, "after `list`")?;
+                ExprKind::List(self.parse_args("after list elements")?)
             }
             TokenKind::Func => {
                 self.next_token();
-                let func_index = self.program.funcs.len();
-                self.program.funcs.push(Func::default());
-                func.push_bytes(&[Opcode::PushFunc.into()]);
-                func.push_bytes(&(func_index as u32).to_be_bytes());
-
-                let mut child_func = func.new_child();
-
-                self.expect_token(TokenKind::OpenBrace)?;
+                self.expect_token(TokenKind::OpenBrace, "after `func`")?;
+                let mut params = vec![];
                 if !self.eat_token(TokenKind::CloseBrace) {
                     loop {
-                        let name = self.expect_ident()?;
-                        let symbol = self.program.symbols.add(name);
-                        child_func.define_param(symbol);
+                        params.push(self.expect_ident("in parameter list")?);
                         if !self.eat_token(TokenKind::Comma) {
                             break
                         }
                     }
-                    self.expect_token(TokenKind::CloseBrace)?;
+                    self.expect_token(TokenKind::CloseBrace, "after parameters")?;
                 }
-
-                if self.token.kind == TokenKind::OpenCurlyBrace {
-                    self.parse_block(&mut child_func)?;
+                let body = if self.token.kind == TokenKind::OpenCurlyBrace {
+                    FuncBody::Block(self.parse_block()?)
                 } else {
-                    self.parse_expr(&mut child_func)?;
-                    child_func.push_bytes(&[Opcode::PopStore.into(), 0]);
-                }
-                child_func.push_bytes(&[Opcode::Return.into()]);
-                self.program.funcs[func_index] = child_func.build();
+                    FuncBody::Expr(Box::new(self.parse_expr()?))
+                };
+                ExprKind::Func(params, body)
             }
-            _ => return Err(self.parse_error()),
+            _ => return Err(self.unexpected("expression")),
         };
-        Ok(())
-    }
-    fn parse_property(&mut self, func: &mut FuncBuilder<'a, '_>) -> Result<(), ParseError<'a>> {
-        self.next_token();
-        let name = self.expect_ident()?;
-        let symbol = self.program.symbols.add(name);
-        func.push_bytes(&[Opcode::PushPropLoad.into(), symbol.id() as u8]);
-        if self.eat_token(TokenKind::Dot) {
-            self.parse_property(func)?;
-        }
-        Ok(())
+        Ok(Expr { kind, span: self.span_from(start) })
     }
-    fn parse_infix_op(&mut self, func: &mut FuncBuilder<'a, '_>, prec: Precedence, op: Opcode) -> Result<(), ParseError<'a>> {
-        self.next_token();
-        self.parse_value(func)?;
-        self.parse_infix(func, prec)?;
-        func.push_bytes(&[op.into()]);
-        Ok(())
-    }
-    fn parse_infix(&mut self, func: &mut FuncBuilder<'a, '_>, prec: Precedence) -> Result<(), ParseError<'a>> {
+    fn parse_infix(&mut self, mut lhs: Expr<'src>, prec: Precedence) -> Result<Expr<'src>, Diagnostic> {
         loop {
-            match self.token.kind {
-                TokenKind::Dot => self.parse_property(func)?,
+            let (op, op_prec) = match self.token.kind {
+                TokenKind::Dot => {
+                    self.next_token();
+                    let name = self.expect_ident("after `.`")?;
+                    let span = lhs.span.to(name.span);
+                    lhs = Expr { kind: ExprKind::Property(Box::new(lhs), name), span };
+                    continue
+                }
 
-                TokenKind::Plus if prec > Precedence::Sum => self.parse_infix_op(func, Precedence::Sum, Opcode::Add)?,
-                TokenKind::Minus if prec > Precedence::Sum => self.parse_infix_op(func, Precedence::Sum, Opcode::Subtract)?,
-                TokenKind::Multiply if prec > Precedence::Product => self.parse_infix_op(func, Precedence::Product, Opcode::Multiply)?,
-                TokenKind::Divide if prec > Precedence::Product => self.parse_infix_op(func, Precedence::Product, Opcode::Divide)?,
-                TokenKind::Modulus if prec > Precedence::Product => self.parse_infix_op(func, Precedence::Product, Opcode::Modulus)?,
+                TokenKind::Plus => (BinaryOp::Add, Precedence::Sum),
+                TokenKind::Minus => (BinaryOp::Subtract, Precedence::Sum),
+                TokenKind::Multiply => (BinaryOp::Multiply, Precedence::Product),
+                TokenKind::Divide => (BinaryOp::Divide, Precedence::Product),
+                TokenKind::IntDivide => (BinaryOp::IntDivide, Precedence::Product),
+                TokenKind::Modulus => (BinaryOp::Modulus, Precedence::Product),
 
-                TokenKind::DoubleEquals if prec > Precedence::Equality => self.parse_infix_op(func, Precedence::Equality, Opcode::Equal)?,
-                TokenKind::NotEqual if prec > Precedence::Equality => self.parse_infix_op(func, Precedence::Equality, Opcode::NotEqual)?,
-                TokenKind::Less if prec > Precedence::Relational => self.parse_infix_op(func, Precedence::Relational, Opcode::Less)?,
-                TokenKind::LessOrEqual if prec > Precedence::Relational => self.parse_infix_op(func, Precedence::Relational, Opcode::LessOrEqual)?,
-                TokenKind::Greater if prec > Precedence::Relational => self.parse_infix_op(func, Precedence::Relational, Opcode::Greater)?,
-                TokenKind::GreaterOrEqual if prec > Precedence::Relational => self.parse_infix_op(func, Precedence::Relational, Opcode::GreaterOrEqual)?,
+                TokenKind::DoubleEquals => (BinaryOp::Equal, Precedence::Equality),
+                TokenKind::NotEqual => (BinaryOp::NotEqual, Precedence::Equality),
+                TokenKind::Less => (BinaryOp::Less, Precedence::Relational),
+                TokenKind::LessOrEqual => (BinaryOp::LessOrEqual, Precedence::Relational),
+                TokenKind::Greater => (BinaryOp::Greater, Precedence::Relational),
+                TokenKind::GreaterOrEqual => (BinaryOp::GreaterOrEqual, Precedence::Relational),
 
                 _ => break
+            };
+            if prec <= op_prec {
+                break
             }
+            self.next_token();
+            let rhs = self.parse_value()?;
+            let rhs = self.parse_infix(rhs, op_prec)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Expr { kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), span };
         }
-        Ok(())
+        Ok(lhs)
     }
-    fn parse_expr(&mut self, func: &mut FuncBuilder<'a, '_>) -> Result<(), ParseError<'a>> {
-        self.parse_value(func)?;
-        self.parse_infix(func, Precedence::Top)?;
-        Ok(())
+    fn parse_expr(&mut self) -> Result<Expr<'src>, Diagnostic> {
+        let value = self.parse_value()?;
+        self.parse_infix(value, Precedence::Top)
     }
-    fn parse_assign_op(&mut self, func: &mut FuncBuilder<'a, '_>, symbol: Symbol, opcode: Opcode) -> Result<(), ParseError<'a>> {
+    fn parse_if(&mut self) -> Result<Stmt<'src>, Diagnostic> {
+        let start = self.span();
         self.next_token();
-        self.parse_expr(func)?;
-        let var = func.resolve_var(symbol).unwrap();
-        func.push_var(var);
-        func.push_bytes(&[opcode.into()]);
-        func.pop_var(var);
-        Ok(())
-    }
-    fn parse_if(&mut self, func: &mut FuncBuilder<'a, '_>) -> Result<(), ParseError<'a>> {
-        self.next_token();
-        self.parse_expr(func)?;
-        let cond = func.push_jump_if_not();
-        self.parse_block(func)?;
-        if self.eat_token(TokenKind::Else) {
-            let exit = func.push_jump();
-            let else_target = func.create_jump_target();
-            func.connect_jump(cond, &else_target);
+        let cond = self.parse_expr()?;
+        let then = self.parse_block()?;
+        let otherwise = if self.eat_token(TokenKind::Else) {
             if self.token.kind == TokenKind::If {
-                self.parse_if(func)?;
+                Some(Else::If(Box::new(self.parse_if()?)))
             } else {
-                self.parse_block(func)?;
+                Some(Else::Block(self.parse_block()?))
             }
-            let end = func.create_jump_target();
-            func.connect_jump(exit, &end);
         } else {
-            let end = func.create_jump_target();
-            func.connect_jump(cond, &end);
+            None
+        };
+        Ok(Stmt { kind: StmtKind::If(cond, then, otherwise), span: self.span_from(start) })
+    }
+    fn parse_var(&mut self, export: bool, doc: Option<String>) -> Result<StmtKind<'src>, Diagnostic> {
+        self.next_token();
+        let name = match self.expect_ident("after `var`") {
+            Ok(name) => name,
+            Err(err) => {
+                // Skip the bad name and its value, so that a keyword used as
+                // the name is not taken for the start of the next statement.
+                if self.token.kind != TokenKind::Equals {
+                    self.next_token();
+                }
+                if self.eat_token(TokenKind::Equals) {
+                    let _ = self.parse_expr();
+                }
+                return Err(err)
+            }
+        };
+        self.expect_token(TokenKind::Equals, "after variable name")?;
+        let value = self.parse_expr()?;
+        Ok(StmtKind::Var { name, value, export, doc })
+    }
+    fn parse_import(&mut self) -> Result<StmtKind<'src>, Diagnostic> {
+        self.next_token();
+        let mut names = vec![];
+        if let TokenKind::Ident(_) = self.token.kind {
+            loop {
+                names.push(self.expect_ident("in import list")?);
+                if !self.eat_token(TokenKind::Comma) {
+                    break
+                }
+            }
+            if self.token.kind != TokenKind::Ident("from") {
+                return Err(self.unexpected("`from` after imported names"))
+            }
+            self.next_token();
         }
-        Ok(())
+        let path = match self.token.kind {
+            TokenKind::String(path) => path,
+            _ => return Err(self.unexpected("module path")),
+        };
+        self.next_token();
+        Ok(StmtKind::Import { names, path })
     }
-    fn parse_stmt(&mut self, func: &mut FuncBuilder<'a, '_>) -> Result<(), ParseError<'a>> {
-        match self.token.kind {
+    fn parse_stmt(&mut self) -> Result<Stmt<'src>, Diagnostic> {
+        let doc = self.doc_comment();
+        let start = self.span();
+        let kind = match self.token.kind {
             TokenKind::While => {
                 self.next_token();
-                let start = func.create_jump_target();
-                self.parse_expr(func)?;
-                let cond = func.push_jump_if_not();
-                self.parse_block(func)?;
-                let repeat = func.push_jump();
-                let exit = func.create_jump_target();
-                func.connect_jump(repeat, &start);
-                func.connect_jump(cond, &exit);
+                let cond = self.parse_expr()?;
+                StmtKind::While(cond, self.parse_block()?)
             }
-            TokenKind::If => self.parse_if(func)?,
-            TokenKind::Var => {
+            TokenKind::If => return self.parse_if(),
+            TokenKind::Var => self.parse_var(false, doc)?,
+            TokenKind::Export => {
                 self.next_token();
-                let name = self.expect_ident()?;
-                let symbol = self.program.symbols.add(name);
-                func.define_var(symbol);
-                self.expect_token(TokenKind::Equals)?;
-                if let TokenKind::Ident(name) = self.token.kind {
-                    let symbol = self.program.symbols.add(name);
-                    self.next_token();
-                    if self.token.kind == TokenKind::OpenBrace {
-                        self.parse_call(func, symbol)?;
-                    } else {
-                        let var = func.resolve_var(symbol).unwrap();
-                        func.push_var(var);
-                        self.parse_infix(func, Precedence::Top)?;
-                    }
-                } else {
-                    self.parse_expr(func)?;
+                if self.token.kind != TokenKind::Var {
+                    return Err(self.unexpected("`var` after `export`"))
                 }
+                self.parse_var(true, doc)?
             }
+            TokenKind::Import => self.parse_import()?,
             TokenKind::Print => {
                 self.next_token();
-                self.parse_expr(func)?;
-                func.push_bytes(&[Opcode::PopPrint.into()]);
+                let mut values = vec![];
+                loop {
+                    values.push(self.parse_expr()?);
+                    if !self.eat_token(TokenKind::Comma) {
+                        break
+                    }
+                }
+                StmtKind::Print(values)
             }
             TokenKind::Return => {
                 self.next_token();
-                self.parse_expr(func)?;
-                func.push_bytes(&[Opcode::PopStore.into(), 0]);
-                func.push_bytes(&[Opcode::Return.into()]);
+                StmtKind::Return(self.parse_expr()?)
             }
             TokenKind::Ident(name) => {
                 self.next_token();
-                let symbol = self.program.symbols.add(name);
-                match self.token.kind {
+                let name = Ident { name, span: start };
+                let op = match self.token.kind {
                     TokenKind::OpenBrace => {
-                        self.parse_call(func, symbol)?;
-                        func.push_bytes(&[Opcode::Drop.into(), 1])
-                    }
-                    TokenKind::Equals => {
                         self.next_token();
-                        self.parse_expr(func)?;
-                        let var = func.resolve_var(symbol).unwrap();
-                        func.pop_var(var);
+                        let args = self.parse_args("after arguments")?;
+                        return Ok(Stmt { kind: StmtKind::Call(name, args), span: self.span_from(start) })
                     }
-                    TokenKind::PlusEquals => self.parse_assign_op(func, symbol, Opcode::Add.into())?,
-                    TokenKind::MinusEquals => self.parse_assign_op(func, symbol, Opcode::Subtract.into())?,
-                    TokenKind::MultiplyEquals => self.parse_assign_op(func, symbol, Opcode::Multiply.into())?,
-                    TokenKind::DivideEquals => self.parse_assign_op(func, symbol, Opcode::Divide.into())?,
-                    TokenKind::ModulusEquals => self.parse_assign_op(func, symbol, Opcode::Modulus.into())?,
-                    _ => return Err(self.parse_error()),
-                }
+                    TokenKind::Equals => None,
+                    TokenKind::PlusEquals => Some(BinaryOp::Add),
+                    TokenKind::MinusEquals => Some(BinaryOp::Subtract),
+                    TokenKind::MultiplyEquals => Some(BinaryOp::Multiply),
+                    TokenKind::DivideEquals => Some(BinaryOp::Divide),
+                    TokenKind::IntDivideEquals => Some(BinaryOp::IntDivide),
+                    TokenKind::ModulusEquals => Some(BinaryOp::Modulus),
+                    _ => return Err(self.unexpected("`=` or `(` after name")),
+                };
+                self.next_token();
+                StmtKind::Assign { name, op, value: self.parse_expr()? }
             }
-            _ => return Err(self.parse_error()),
-        }
-        Ok(())
+            _ => return Err(self.unexpected("statement")),
+        };
+        Ok(Stmt { kind, span: self.span_from(start) })
     }
-    fn parse_block(&mut self, func: &mut FuncBuilder<'a, '_>) -> Result<(), ParseError<'a>> {
-        let start_stack_size = func.stack_size();
-        self.expect_token(TokenKind::OpenCurlyBrace)?;
+    fn parse_block(&mut self) -> Result<Block<'src>, Diagnostic> {
+        let start = self.span();
+        self.expect_token(TokenKind::OpenCurlyBrace, "to start block")?;
+        let mut stmts = vec![];
         while !self.eat_token(TokenKind::CloseCurlyBrace) {
-            self.parse_stmt(func)?;
-        }
-        let n = func.stack_size() - start_stack_size;
-        if n > 0 {
-            func.free_vars(n);
+            if self.token.kind == TokenKind::End {
+                return Err(self.unexpected("`}` to close block").with_label(start, "block starts here"))
+            }
+            stmts.extend(self.parse_stmt_or_recover());
         }
-        Ok(())
+        Ok(Block { stmts, span: self.span_from(start) })
     }
-    pub fn parse(source: &'a str, path: Option<&'a str>, program: &'a mut Program, params: Vec<Symbol>) -> Result<Vec<Symbol>, ParseError<'a>> {
-        let mut lexer = Lexer::new(source);
-        let token = lexer.next_token();
-        let func_index = program.funcs.len();
-        program.funcs.push(Func::default());
-        let mut func = FuncBuilder::new(source, params);
-        let mut parser = Parser { path, source, token, lexer, program };
+    /// Parses a whole source file into its statements, reporting every
+    /// syntax error found rather than just the first.
+    pub fn parse(source: &'src str, path: Option<&'src str>) -> Result<Vec<Stmt<'src>>, Diagnostics<'src>> {
+        let token = Token { offset: 0, end: 0, kind: TokenKind::End };
+        let mut parser = Parser { lexer: Lexer::new(source), token, last_end: 0, doc: vec![], diagnostics: vec![] };
+        parser.next_token();
+        let mut stmts = vec![];
         while parser.token.kind != TokenKind::End {
-            parser.parse_stmt(&mut func)?;
+            stmts.extend(parser.parse_stmt_or_recover());
+        }
+        if parser.diagnostics.is_empty() {
+            Ok(stmts)
+        } else {
+            Err(Diagnostics { source, path, errors: parser.diagnostics })
         }
-        func.push_bytes(&[Opcode::Finish.into()]);
-        let last_scope = func.scope.clone();
-        parser.program.funcs[func_index] = func.build();
-        Ok(last_scope)
     }
-}
\ No newline at end of file
+}
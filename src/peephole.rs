@@ -0,0 +1,248 @@
+use std::{collections::HashSet, convert::{TryFrom, TryInto}};
+
+use crate::{func::{self, Constant, Func}, opcode::{self, JumpKind, Opcode}};
+
+#[derive(Debug, Clone, Copy)]
+struct Instr<'a> {
+    offset: usize,
+    opcode: Opcode,
+    operands: &'a [u8],
+    /// For a jump, its kind and the offset it lands on.
+    jump: Option<(JumpKind, usize)>,
+}
+
+fn decode(bytecode: &[u8]) -> Vec<Instr<'_>> {
+    let mut instrs = vec![];
+    let mut offset = 0;
+    while offset < bytecode.len() {
+        let opcode: Opcode = bytecode[offset].try_into().unwrap();
+        let end = offset + 1 + opcode.operand_len();
+        let operands = &bytecode[offset + 1..end];
+        let jump = opcode.jump().map(|(kind, width)| {
+            let relative = opcode::read_jump_offset(&operands[operands.len() - width..]);
+            (kind, (end as isize + relative) as usize)
+        });
+        instrs.push(Instr { offset, opcode, operands, jump });
+        offset = end;
+    }
+    instrs
+}
+
+/// Follows a chain of unconditional jumps to where it ends up. A cycle of
+/// jumps is left where it is.
+fn thread_jump(instrs: &[Instr], mut target: usize) -> usize {
+    for _ in 0..instrs.len() {
+        match instrs.binary_search_by_key(&target, |instr| instr.offset) {
+            Ok(i) => match instrs[i].jump {
+                Some((JumpKind::Always, next)) => target = next,
+                _ => break,
+            },
+            _ => break,
+        }
+    }
+    target
+}
+
+/// The index of `int` in the constant table, adding it if needed, or
+/// `None` if the index would not fit in a byte.
+fn int_constant(constants: &mut Vec<Constant>, int: i64) -> Option<u8> {
+    let constant = Constant::Int(int);
+    let index = match constants.iter().position(|other| *other == constant) {
+        Some(index) => index,
+        None if constants.len() <= u8::MAX as usize => {
+            constants.push(constant);
+            constants.len() - 1
+        }
+        None => return None,
+    };
+    u8::try_from(index).ok()
+}
+
+/// One instruction of the rewritten function. A jump keeps the bytecode
+/// offset it lands on until `layout` knows where everything ends up.
+struct Item {
+    /// The whole instruction, or for a jump only the operands before its
+    /// offset.
+    bytes: Vec<u8>,
+    jump: Option<(JumpKind, usize)>,
+}
+
+/// Rewrites common sequences in a function's bytecode into fused opcodes:
+///
+/// - `PushLoad n; PushConst 1; Add; PopStore n` becomes `IncLocal n`,
+///   and the same with any other integer constant, or with `Subtract`,
+///   becomes `AddLocalConst n k`.
+/// - `PushLoad a; PushLoad b; Less; JumpIfNot t` becomes
+///   `JumpIfNotLessLocals a b t`.
+/// - Adjacent `Drop`s are merged and `Drop 0` is removed.
+/// - Jumps to unconditional jumps go straight to the final target, and an
+///   unconditional jump to the next instruction is removed.
+///
+/// A sequence is only fused when no jump lands in the middle of it. Every
+/// jump is then given the shortest offset that reaches its target, and the
+/// line table and local names are moved to the new offsets.
+pub fn optimize(func: &mut Func) {
+    let bytecode = &func.bytecode;
+    let constants = &mut func.constants;
+    let instrs = decode(bytecode);
+    let targets: HashSet<usize> = instrs.iter().filter_map(|instr| Some(instr.jump?.1)).collect();
+    // Whether the `n` instructions starting at `i` can be replaced as a unit.
+    let fusable = |i: usize, n: usize| i + n <= instrs.len() && instrs[i + 1..i + n].iter().all(|instr| !targets.contains(&instr.offset));
+
+    let mut items = vec![];
+    // The item each instruction's offset now refers to.
+    let mut new_items = vec![0; bytecode.len() + 1];
+    let mut i = 0;
+    while i < instrs.len() {
+        let instr = instrs[i];
+        new_items[instr.offset] = items.len();
+        let len = match &instrs[i..] {
+            [
+                Instr { opcode: Opcode::PushLoad, operands: [load], .. },
+                Instr { opcode: Opcode::PushConst, operands: [constant], .. },
+                Instr { opcode: op @ (Opcode::Add | Opcode::Subtract), .. },
+                Instr { opcode: Opcode::PopStore, operands: [store], .. },
+                ..
+            ] if load == store && fusable(i, 4) => {
+                let int = match (op, &constants[*constant as usize]) {
+                    (Opcode::Add, Constant::Int(int)) => Some(*int),
+                    (_, Constant::Int(int)) => int.checked_neg(),
+                    _ => None,
+                };
+                let bytes = match int {
+                    Some(1) => Some(vec![Opcode::IncLocal.into(), *load]),
+                    Some(int) => int_constant(constants, int).map(|index| vec![Opcode::AddLocalConst.into(), *load, index]),
+                    None => None,
+                };
+                match bytes {
+                    Some(bytes) => {
+                        items.push(Item { bytes, jump: None });
+                        4
+                    }
+                    None => 0,
+                }
+            }
+            [
+                Instr { opcode: Opcode::PushLoad, operands: [a], .. },
+                Instr { opcode: Opcode::PushLoad, operands: [b], .. },
+                Instr { opcode: Opcode::Less, .. },
+                Instr { jump: Some((JumpKind::IfNot, target)), .. },
+                ..
+            ] if fusable(i, 4) => {
+                items.push(Item { bytes: vec![*a, *b], jump: Some((JumpKind::IfNotLessLocals, thread_jump(&instrs, *target))) });
+                4
+            }
+            [Instr { opcode: Opcode::Drop, .. }, ..] => {
+                let mut count = 0;
+                let mut n = 0;
+                while let Some(Instr { opcode: Opcode::Drop, operands: [drop], .. }) = instrs.get(i + n) {
+                    if !fusable(i, n + 1) || count + *drop as usize > u8::MAX as usize {
+                        break
+                    }
+                    count += *drop as usize;
+                    n += 1;
+                }
+                if count > 0 {
+                    items.push(Item { bytes: vec![Opcode::Drop.into(), count as u8], jump: None });
+                }
+                n
+            }
+            _ => 0,
+        };
+        if len > 0 {
+            for inner in &instrs[i + 1..i + len] {
+                new_items[inner.offset] = new_items[instr.offset];
+            }
+            i += len;
+            continue
+        }
+
+        match instr.jump {
+            Some((kind, target)) => {
+                let target = thread_jump(&instrs, target);
+                let next = instrs.get(i + 1).map_or(bytecode.len(), |next| next.offset);
+                if !(kind == JumpKind::Always && target == next) {
+                    let bytes = instr.operands[..kind.prefix_len()].to_vec();
+                    items.push(Item { bytes, jump: Some((kind, target)) });
+                }
+            }
+            None => {
+                let mut bytes = vec![instr.opcode.into()];
+                bytes.extend(instr.operands);
+                items.push(Item { bytes, jump: None });
+            }
+        }
+        i += 1;
+    }
+    new_items[bytecode.len()] = items.len();
+
+    for item in items.iter_mut() {
+        if let Some((kind, target)) = item.jump {
+            item.jump = Some((kind, new_items[target]));
+        }
+    }
+    let (bytecode, offsets) = layout(&items);
+    func.bytecode = bytecode;
+
+    let new_offset = |offset: u32| offsets[new_items[offset as usize]] as u32;
+    let lines = std::mem::take(&mut func.lines);
+    for (offset, line) in lines {
+        func::add_line(&mut func.lines, new_offset(offset), line);
+    }
+    for (offset, _, _) in func.local_names.iter_mut() {
+        *offset = new_offset(*offset);
+    }
+}
+
+/// Whether a jump offset can be encoded in `width` bytes.
+pub(crate) fn fits(relative: isize, width: usize) -> bool {
+    match width {
+        1 => i8::try_from(relative).is_ok(),
+        2 => i16::try_from(relative).is_ok(),
+        _ => i32::try_from(relative).is_ok(),
+    }
+}
+
+/// Encodes the items, with each jump's target given as an item index, and
+/// returns the bytecode with the offset of every item. Every jump starts
+/// out with a one byte offset and is widened until it reaches its target;
+/// widening only moves code further apart, so this settles.
+fn layout(items: &[Item]) -> (Vec<u8>, Vec<usize>) {
+    let mut widths: Vec<usize> = vec![1; items.len()];
+    let mut offsets = vec![0; items.len() + 1];
+    loop {
+        for (i, item) in items.iter().enumerate() {
+            let len = match item.jump {
+                Some(_) => 1 + item.bytes.len() + widths[i],
+                None => item.bytes.len(),
+            };
+            offsets[i + 1] = offsets[i] + len;
+        }
+        let mut settled = true;
+        for (i, item) in items.iter().enumerate() {
+            if let Some((_, target)) = item.jump {
+                if !fits(offsets[target] as isize - offsets[i + 1] as isize, widths[i]) {
+                    widths[i] *= 2;
+                    settled = false;
+                }
+            }
+        }
+        if settled {
+            break
+        }
+    }
+
+    let mut bytecode = Vec::with_capacity(offsets[items.len()]);
+    for (i, item) in items.iter().enumerate() {
+        match item.jump {
+            Some((kind, target)) => {
+                bytecode.push(kind.opcode(widths[i]).into());
+                bytecode.extend(&item.bytes);
+                let relative = offsets[target] as i64 - offsets[i + 1] as i64;
+                bytecode.extend(&relative.to_be_bytes()[8 - widths[i]..]);
+            }
+            None => bytecode.extend(&item.bytes),
+        }
+    }
+    (bytecode, offsets)
+}
@@ -0,0 +1,136 @@
+use crate::ast::{Block, Else, Expr, ExprKind, FuncBody, Ident, Stmt, StmtKind};
+use crate::{builtins, compiler::Program, diagnostic::Diagnostic, module};
+
+/// Checks that every name in a module refers to a variable and that
+/// `return` is only used in functions, before the optimizer removes any
+/// code. The compiler resolves names as it emits code, so without this an
+/// undefined name in a branch that can never run would go unreported.
+///
+/// The rules follow the compiler's: a variable is visible from its `var`
+/// to the end of its block, including in functions defined there, and
+/// top level variables become globals of the module.
+pub fn check<'src>(stmts: &[Stmt<'src>], program: &Program, module: usize) -> Vec<Diagnostic> {
+    let mut resolver = Resolver { program, module, globals: vec![], scope: vec![], depth: 0, funcs: 0, diagnostics: vec![] };
+    for stmt in stmts {
+        resolver.check_stmt(stmt);
+    }
+    resolver.diagnostics
+}
+
+pub(crate) fn undefined_var(ident: &Ident) -> Diagnostic {
+    Diagnostic::new(format!("undefined variable `{}`", ident.name), ident.span)
+}
+
+struct Resolver<'p, 'src> {
+    program: &'p Program,
+    module: usize,
+    /// Globals defined so far by this chunk.
+    globals: Vec<&'src str>,
+    /// Locals and parameters of every enclosing block and function.
+    scope: Vec<&'src str>,
+    depth: u32,
+    /// Number of enclosing functions.
+    funcs: u32,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'p, 'src> Resolver<'p, 'src> {
+    fn define(&mut self, name: &'src str) {
+        if self.depth == 0 {
+            self.globals.push(name);
+        } else {
+            self.scope.push(name);
+        }
+    }
+    fn is_defined(&self, name: &str) -> bool {
+        self.scope.contains(&name) || self.globals.contains(&name) || self.program.symbols.get(name)
+            .and_then(|symbol| self.program.globals.resolve(self.module, symbol))
+            .is_some()
+    }
+    /// Checks a name that is assigned to, which cannot be a builtin.
+    fn check_var(&mut self, ident: &Ident) {
+        if !self.is_defined(ident.name) {
+            self.diagnostics.push(undefined_var(ident));
+        }
+    }
+    fn check_ident(&mut self, ident: &Ident) {
+        if builtins::lookup(ident.name).is_none() {
+            self.check_var(ident);
+        }
+    }
+    fn check_block(&mut self, block: &Block<'src>) {
+        let scope_len = self.scope.len();
+        self.depth += 1;
+        for stmt in &block.stmts {
+            self.check_stmt(stmt);
+        }
+        self.depth -= 1;
+        self.scope.truncate(scope_len);
+    }
+    fn check_stmt(&mut self, stmt: &Stmt<'src>) {
+        match &stmt.kind {
+            StmtKind::Var { name, value, .. } => {
+                self.define(name.name);
+                self.check_expr(value);
+            }
+            StmtKind::Assign { name, value, .. } => {
+                self.check_var(name);
+                self.check_expr(value);
+            }
+            StmtKind::Call(name, args) => {
+                args.iter().for_each(|arg| self.check_expr(arg));
+                self.check_ident(name);
+            }
+            StmtKind::While(cond, body) => {
+                self.check_expr(cond);
+                self.check_block(body);
+            }
+            StmtKind::If(cond, then, otherwise) => {
+                self.check_expr(cond);
+                self.check_block(then);
+                match otherwise {
+                    Some(Else::Block(block)) => self.check_block(block),
+                    Some(Else::If(stmt)) => self.check_stmt(stmt),
+                    None => {}
+                }
+            }
+            StmtKind::Print(values) => values.iter().for_each(|value| self.check_expr(value)),
+            StmtKind::Return(value) => {
+                if self.funcs == 0 {
+                    self.diagnostics.push(Diagnostic::new("`return` outside a function", stmt.span));
+                }
+                self.check_expr(value)
+            }
+            StmtKind::Import { names, path } if names.is_empty() => self.define(module::binding_name(path)),
+            StmtKind::Import { names, .. } => names.iter().for_each(|name| self.define(name.name)),
+            StmtKind::Block(block) => self.check_block(block),
+        }
+    }
+    fn check_expr(&mut self, expr: &Expr<'src>) {
+        match &expr.kind {
+            ExprKind::Var(ident) => self.check_ident(ident),
+            ExprKind::Call(name, args) => {
+                args.iter().for_each(|arg| self.check_expr(arg));
+                self.check_ident(name);
+            }
+            ExprKind::List(values) => values.iter().for_each(|value| self.check_expr(value)),
+            ExprKind::Func(params, body) => {
+                let scope_len = self.scope.len();
+                self.scope.extend(params.iter().map(|param| param.name));
+                self.funcs += 1;
+                match body {
+                    FuncBody::Block(block) => self.check_block(block),
+                    FuncBody::Expr(value) => self.check_expr(value),
+                }
+                self.funcs -= 1;
+                self.scope.truncate(scope_len);
+            }
+            ExprKind::Property(value, _) => self.check_expr(value),
+            ExprKind::Binary(_, lhs, rhs) => {
+                self.check_expr(lhs);
+                self.check_expr(rhs);
+            }
+            ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::String(_) | ExprKind::Bool(_) | ExprKind::None => {}
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::{collections::HashMap, convert::TryFrom, io::{self, Read, Write}};
+
+use crate::{compiler::Program, func::{ClosureValue, Constant, Func}, globals::Globals, module::Module, symbols::Symbol};
+
+/// The first bytes of every compiled program file.
+pub const MAGIC: [u8; 4] = *b"SCRB";
+/// Bumped whenever the file layout or the meaning of any opcode changes.
+pub const VERSION: u16 = 3;
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+struct Writer<W> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.inner.write_all(&[value])
+    }
+    fn u16(&mut self, value: u16) -> io::Result<()> {
+        self.inner.write_all(&value.to_be_bytes())
+    }
+    fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.inner.write_all(&value.to_be_bytes())
+    }
+    fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.inner.write_all(&value.to_be_bytes())
+    }
+    fn len(&mut self, len: usize) -> io::Result<()> {
+        let len = u32::try_from(len).map_err(|_| invalid_data("table too large to serialise"))?;
+        self.u32(len)
+    }
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.len(bytes.len())?;
+        self.inner.write_all(bytes)
+    }
+    fn str(&mut self, string: &str) -> io::Result<()> {
+        self.bytes(string.as_bytes())
+    }
+    fn func(&mut self, func: &Func) -> io::Result<()> {
+        self.u16(func.param_count)?;
+        self.len(func.param_names.len())?;
+        for symbol in &func.param_names {
+            self.u32(symbol.id())?;
+        }
+        self.len(func.closure_scope.len())?;
+        for var in &func.closure_scope {
+            match var {
+                ClosureValue::Outer(index) => {
+                    self.u8(0)?;
+                    self.u16(*index)?;
+                }
+                ClosureValue::Stack(index) => {
+                    self.u8(1)?;
+                    self.u16(*index)?;
+                }
+            }
+        }
+        self.len(func.constants.len())?;
+        for constant in &func.constants {
+            match constant {
+                Constant::Int(int) => {
+                    self.u8(0)?;
+                    self.u64(*int as u64)?;
+                }
+                Constant::Float(float) => {
+                    self.u8(1)?;
+                    self.u64(float.to_bits())?;
+                }
+                Constant::String(string) => {
+                    self.u8(2)?;
+                    self.str(string)?;
+                }
+                Constant::Func(func_id) => {
+                    self.u8(3)?;
+                    self.u32(*func_id)?;
+                }
+            }
+        }
+        self.bytes(&func.bytecode)?;
+        self.len(func.lines.len())?;
+        for (offset, line) in &func.lines {
+            self.u32(*offset)?;
+            self.u32(*line)?;
+        }
+        self.len(func.local_names.len())?;
+        for (offset, slot, symbol) in &func.local_names {
+            self.u32(*offset)?;
+            self.u16(*slot)?;
+            self.u32(symbol.id())?;
+        }
+        self.len(func.closure_names.len())?;
+        for symbol in &func.closure_names {
+            self.u32(symbol.id())?;
+        }
+        Ok(())
+    }
+}
+
+struct Reader<R> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.inner.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+    fn len(&mut self) -> io::Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.len()?;
+        let mut bytes = vec![];
+        (&mut self.inner).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into())
+        }
+        Ok(bytes)
+    }
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid_data("string is not valid UTF-8"))
+    }
+    fn symbol(&mut self) -> io::Result<Symbol> {
+        Ok(Symbol::from_index(self.u32()?))
+    }
+    fn func(&mut self) -> io::Result<Func> {
+        let param_count = self.u16()?;
+        let param_names = (0..self.len()?).map(|_| self.symbol()).collect::<io::Result<_>>()?;
+        let closure_scope = (0..self.len()?).map(|_| match self.u8()? {
+            0 => Ok(ClosureValue::Outer(self.u16()?)),
+            1 => Ok(ClosureValue::Stack(self.u16()?)),
+            tag => Err(invalid_data(format!("unknown closure variable tag {}", tag))),
+        }).collect::<io::Result<_>>()?;
+        let constants = (0..self.len()?).map(|_| match self.u8()? {
+            0 => Ok(Constant::Int(self.u64()? as i64)),
+            1 => Ok(Constant::Float(f64::from_bits(self.u64()?))),
+            2 => Ok(Constant::String(self.string()?.into())),
+            3 => Ok(Constant::Func(self.u32()?)),
+            tag => Err(invalid_data(format!("unknown constant tag {}", tag))),
+        }).collect::<io::Result<_>>()?;
+        let bytecode = self.bytes()?;
+        let lines = (0..self.len()?).map(|_| Ok((self.u32()?, self.u32()?))).collect::<io::Result<_>>()?;
+        let local_names = (0..self.len()?).map(|_| Ok((self.u32()?, self.u16()?, self.symbol()?))).collect::<io::Result<_>>()?;
+        let closure_names = (0..self.len()?).map(|_| self.symbol()).collect::<io::Result<_>>()?;
+        Ok(Func { bytecode, constants, param_count, closure_scope, param_names, lines, local_names, closure_names })
+    }
+}
+
+/// A compiled program file is laid out as follows, with every integer
+/// big-endian and every table or string prefixed by its `u32` length:
+///
+/// - `MAGIC` and the `u16` `VERSION`.
+/// - The symbol names, in `Symbols` order.
+/// - The global slots, each a `u32` module id and a `u32` symbol id plus
+///   one, zero meaning a reserved slot; then the doc comments, each a `u32`
+///   slot and a string.
+/// - The modules, each a path, `u32` entry function, `u32` global slot and
+///   the exported symbol ids.
+/// - The functions, each its `u16` param count, param name symbols,
+///   closure variables as a tag byte and `u16` index, constants as a tag
+///   byte and payload, and bytecode; then the debug info: the line table
+///   as `u32` offset and line pairs, the local names as a `u32` offset,
+///   `u16` slot and symbol, and the closure variable name symbols.
+impl Program {
+    pub fn write_to(&self, output: impl Write) -> io::Result<()> {
+        let mut writer = Writer { inner: output };
+        writer.inner.write_all(&MAGIC)?;
+        writer.u16(VERSION)?;
+
+        writer.len(self.symbols.symbols.len())?;
+        for name in &self.symbols.symbols {
+            writer.str(name)?;
+        }
+
+        writer.len(self.globals.slots.len())?;
+        for (module, symbol) in &self.globals.slots {
+            writer.u32(*module as u32)?;
+            writer.u32(symbol.map_or(0, |symbol| symbol.id() + 1))?;
+        }
+        let mut docs: Vec<_> = self.globals.docs.iter().collect();
+        docs.sort();
+        writer.len(docs.len())?;
+        for (slot, doc) in docs {
+            writer.u32(*slot)?;
+            writer.str(doc)?;
+        }
+
+        writer.len(self.modules.len())?;
+        for module in &self.modules {
+            writer.str(&module.path)?;
+            writer.u32(module.entry_func as u32)?;
+            writer.u32(module.slot)?;
+            writer.len(module.exports.len())?;
+            for symbol in &module.exports {
+                writer.u32(symbol.id())?;
+            }
+        }
+
+        writer.len(self.funcs.len())?;
+        for func in &self.funcs {
+            writer.func(func)?;
+        }
+        writer.inner.flush()
+    }
+    /// Reads a program written by `write_to`. The bytecode itself is not
+    /// checked; pass the program to `verify::verify` before running it.
+    pub fn read_from(input: impl Read) -> io::Result<Program> {
+        let mut reader = Reader { inner: input };
+        if reader.array()? != MAGIC {
+            return Err(invalid_data("not a compiled program"))
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(invalid_data(format!("unsupported bytecode version {} (expected {})", version, VERSION)))
+        }
+
+        let mut program = Program::new();
+        program.symbols.symbols = (0..reader.len()?).map(|_| reader.string()).collect::<io::Result<_>>()?;
+
+        program.globals = Globals::new();
+        for _ in 0..reader.len()? {
+            let module = reader.u32()? as usize;
+            let symbol = reader.u32()?.checked_sub(1).map(Symbol::from_index);
+            program.globals.push(module, symbol);
+        }
+        program.globals.docs = (0..reader.len()?).map(|_| Ok((reader.u32()?, reader.string()?))).collect::<io::Result<_>>()?;
+
+        program.modules = (0..reader.len()?).map(|_| {
+            let path = reader.string()?;
+            let entry_func = reader.u32()? as usize;
+            let slot = reader.u32()?;
+            let mut module = Module::new(&path, entry_func, slot);
+            module.exports = (0..reader.len()?).map(|_| reader.symbol()).collect::<io::Result<_>>()?;
+            module.loaded = true;
+            Ok(module)
+        }).collect::<io::Result<_>>()?;
+        program.module_ids = program.modules.iter().enumerate().skip(1).map(|(id, module)| (module.path.clone(), id)).collect::<HashMap<_, _>>();
+
+        program.funcs = (0..reader.len()?).map(|_| reader.func()).collect::<io::Result<_>>()?;
+        Ok(program)
+    }
+}
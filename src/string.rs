@@ -0,0 +1,40 @@
+use std::{fmt, str};
+
+use crate::{heap::{Heap, HeapSlice}, value::{Value, RustValue}, vm::{RuntimeError, VirtualMachine}, symbols::Symbol};
+
+/// A string whose bytes are copied into the heap, which never runs
+/// destructors, so it must not own an allocation of its own.
+#[derive(Debug, Clone)]
+pub struct Str {
+    bytes: HeapSlice<u8>,
+}
+
+impl Str {
+    pub fn new(heap: &mut Heap, string: &str) -> Str {
+        let mut bytes = heap.alloc_slice(string.len());
+        bytes.as_mut_slice().copy_from_slice(string.as_bytes());
+        Str { bytes }
+    }
+    pub fn as_str(&self) -> &str {
+        // The bytes were copied from a `str` and are never changed.
+        unsafe { str::from_utf8_unchecked(self.bytes.as_slice()) }
+    }
+}
+
+impl RustValue for Str {
+    fn type_name(&self) -> &'static str {
+        "string"
+    }
+    fn get_property(&mut self, symbol: Symbol, vm: &mut VirtualMachine) -> Result<Value, RuntimeError> {
+        match vm.program.symbols.get_name(symbol) {
+            "len" => Ok(Value::Int(self.as_str().chars().count() as i64)),
+            name => Err(RuntimeError::NoProperty(self.type_name(), name.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Str {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
@@ -1,9 +1,9 @@
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Symbol(u32);
 
 #[derive(Debug, Clone)]
 pub struct Symbols {
-    symbols: Vec<String>,
+    pub(crate) symbols: Vec<String>,
 }
 
 pub const RETURN: Symbol = Symbol(0);
@@ -31,7 +31,18 @@ impl Symbols {
             }
         }
     }
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.symbols.iter()
+            .position(|symbol| *symbol == name)
+            .map(|id| Symbol(id as u32))
+    }
     pub fn get_name(&self, Symbol(id): Symbol) -> &str {
         &self.symbols[id as usize]
     }
+}
+
+impl Default for Symbols {
+    fn default() -> Self {
+        Symbols::new()
+    }
 }
\ No newline at end of file
@@ -1,20 +1,27 @@
+use std::fmt;
+
+use crate::lexer::LexError;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Token<'src> {
     pub offset: usize,
+    pub end: usize,
     pub kind: TokenKind<'src>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenKind<'src> {
     Ident(&'src str),
-    Int(u64),
+    Int(i64),
     Float(f64),
     String(&'src str),
+    DocComment(&'src str),
     
     Plus,
     Minus,
     Multiply,
     Divide,
+    IntDivide,
     Modulus,
 
     NotEqual,
@@ -32,6 +39,7 @@ pub enum TokenKind<'src> {
     MinusEquals,
     MultiplyEquals,
     DivideEquals,
+    IntDivideEquals,
     ModulusEquals,
 
     OpenBrace,
@@ -55,31 +63,71 @@ pub enum TokenKind<'src> {
     Func,
     Return,
     Print,
+    Import,
+    Export,
 
     List,
 
     End,
-    Invalid,
+    Error(LexError),
 }
 
-pub struct Position {
-    pub line: u32,
-    pub column: u32,
-}
+impl<'src> fmt::Display for TokenKind<'src> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            TokenKind::Ident(name) => return write!(f, "identifier `{}`", name),
+            TokenKind::Int(_) => return write!(f, "integer"),
+            TokenKind::Float(_) => return write!(f, "float"),
+            TokenKind::String(_) => return write!(f, "string"),
+            TokenKind::DocComment(_) => return write!(f, "doc comment"),
+            TokenKind::End => return write!(f, "end of input"),
+            TokenKind::Error(_) => return write!(f, "invalid token"),
 
-pub fn pos_at_offset(source: &str, offset: usize) -> Position {
-    let mut line = 1;
-    let mut column = 1;
-    for (i, ch) in source.char_indices() {
-        if i == offset {
-            return Position { line, column }
-        }
-        if ch == '\n' {
-            line += 1;
-            column = 1;
-        } else {
-            column += 1;
-        }
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Multiply => "*",
+            TokenKind::Divide => "/",
+            TokenKind::IntDivide => "//",
+            TokenKind::Modulus => "%",
+            TokenKind::NotEqual => "!=",
+            TokenKind::DoubleEquals => "==",
+            TokenKind::Less => "<",
+            TokenKind::Greater => ">",
+            TokenKind::LessOrEqual => "<=",
+            TokenKind::GreaterOrEqual => ">=",
+            TokenKind::Not => "!",
+            TokenKind::And => "&&",
+            TokenKind::Or => "||",
+            TokenKind::PlusEquals => "+=",
+            TokenKind::MinusEquals => "-=",
+            TokenKind::MultiplyEquals => "*=",
+            TokenKind::DivideEquals => "/=",
+            TokenKind::IntDivideEquals => "//=",
+            TokenKind::ModulusEquals => "%=",
+            TokenKind::OpenBrace => "(",
+            TokenKind::CloseBrace => ")",
+            TokenKind::OpenCurlyBrace => "{",
+            TokenKind::CloseCurlyBrace => "}",
+            TokenKind::OpenSquareBrace => "[",
+            TokenKind::CloseSquareBrace => "]",
+            TokenKind::SemiColon => ";",
+            TokenKind::Comma => ",",
+            TokenKind::Dot => ".",
+            TokenKind::Equals => "=",
+            TokenKind::Var => "var",
+            TokenKind::True => "true",
+            TokenKind::False => "false",
+            TokenKind::None => "none",
+            TokenKind::While => "while",
+            TokenKind::If => "if",
+            TokenKind::Else => "else",
+            TokenKind::Func => "func",
+            TokenKind::Return => "return",
+            TokenKind::Print => "print",
+            TokenKind::Import => "import",
+            TokenKind::Export => "export",
+            TokenKind::List => "list",
+        };
+        write!(f, "`{}`", symbol)
     }
-    unreachable!()
-}
\ No newline at end of file
+}
@@ -1,6 +1,6 @@
-use std::fmt;
+use std::{any::Any, fmt};
 
-use crate::{heap::HeapPtr, parser::Program, vm::VirtualMachine};
+use crate::{builtins::NativeFunc, compact_value::CompactValue, heap::HeapPtr, compiler::Program, symbols::Symbol, vm::{RuntimeError, VirtualMachine}};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Value {
@@ -9,6 +9,7 @@ pub enum Value {
     Bool(bool),
     Closure(HeapPtr<Closure>),
     RustValue(HeapPtr<dyn RustValue>),
+    NativeFunc(&'static NativeFunc),
     None,
 }
 
@@ -17,8 +18,14 @@ pub struct DispValue<'a> {
     value: Value,
 }
 
-pub trait RustValue where Self: fmt::Debug + fmt::Display {
-    fn get_property(&mut self, index: u8, vm: &mut VirtualMachine) -> Value;
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+pub trait RustValue where Self: AsAny + fmt::Debug + fmt::Display + 'static {
+    fn type_name(&self) -> &'static str;
+    fn get_property(&mut self, symbol: Symbol, vm: &mut VirtualMachine) -> Result<Value, RuntimeError>;
 }
 
 #[derive(Debug, Clone)]
@@ -30,7 +37,51 @@ pub struct Closure {
 #[derive(Debug, Clone, Copy)]
 pub enum ClosureValueRef {
     Stack(usize),
-    Heap(HeapPtr<Value>),
+    Heap(HeapPtr<CompactValue>),
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl dyn RustValue {
+    pub fn is<T: RustValue>(&self) -> bool {
+        self.as_any().is::<T>()
+    }
+    pub fn downcast_ref<T: RustValue>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+    pub fn downcast_mut<T: RustValue>(&mut self) -> Option<&mut T> {
+        self.as_any_mut().downcast_mut()
+    }
+}
+
+impl HeapPtr<dyn RustValue> {
+    pub fn downcast<T: RustValue>(self) -> Option<HeapPtr<T>> {
+        if self.is::<T>() {
+            Some(unsafe { self.cast() })
+        } else {
+            None
+        }
+    }
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::Closure(_) | Value::NativeFunc(_) => "func",
+            Value::RustValue(value) => value.type_name(),
+            Value::None => "none",
+        }
+    }
 }
 
 impl<'a> DispValue<'a> {
@@ -51,6 +102,7 @@ impl<'a> fmt::Display for DispValue<'a> {
                 write!(f, "func({})", params.join(", "))
             },
             Value::RustValue(value) => write!(f, "{}", &*value),
+            Value::NativeFunc(native) => write!(f, "builtin {}", native.name),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,367 @@
+use std::{convert::TryInto, fmt};
+
+use crate::{builtins::BUILTINS, compiler::Program, func::{ClosureValue, Constant, Func}, opcode::{self, JumpKind, Opcode}, module, symbols::Symbol};
+
+/// Why a program was rejected by `verify`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    /// A module's entry function or global slot does not exist, the entry
+    /// function takes parameters or captures variables, the main module's
+    /// entry function is not `func0`, where the VM starts, or an export has
+    /// no global slot.
+    Module(usize),
+    /// A global slot belongs to a module or names a symbol that does not
+    /// exist.
+    Global(u32),
+    Func {
+        func: usize,
+        offset: usize,
+        error: FuncError,
+    },
+}
+
+/// A problem with the instruction at some offset of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FuncError {
+    /// The function's parameter names or count are inconsistent.
+    Params,
+    /// The debug info names a symbol that does not exist, or names some
+    /// captured variables but not others.
+    DebugInfo,
+    UnknownOpcode(u8),
+    Truncated,
+    /// Execution can run past the last instruction.
+    FallsOffEnd,
+    /// The program's entry function returns, though it has no caller to
+    /// return to.
+    ReturnFromEntry,
+    InvalidWide(Opcode),
+    JumpTarget(isize),
+    StackUnderflow,
+    StackMismatch {
+        expected: usize,
+        found: usize,
+    },
+    Local(usize),
+    ClosureValue(usize),
+    Constant(usize),
+    NotAnInt(usize),
+    FuncId(u32),
+    /// A function created here captures a variable that does not exist.
+    Capture(u32),
+    GlobalSlot(u32),
+    ModuleId(u32),
+    Builtin(u8),
+    Symbol(usize),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Module(id) => write!(f, "module {} has a missing or invalid entry function, slot or export", id),
+            VerifyError::Global(slot) => write!(f, "global slot {} refers to a missing module or symbol", slot),
+            VerifyError::Func { func, offset, error } => write!(f, "func{} at {}: {}", func, offset, error),
+        }
+    }
+}
+
+impl fmt::Display for FuncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FuncError::Params => write!(f, "parameter names do not match the parameter count"),
+            FuncError::DebugInfo => write!(f, "debug info names a missing symbol or closure variable"),
+            FuncError::UnknownOpcode(byte) => write!(f, "unknown opcode {}", byte),
+            FuncError::Truncated => write!(f, "instruction runs past the end of the bytecode"),
+            FuncError::FallsOffEnd => write!(f, "execution runs past the last instruction"),
+            FuncError::ReturnFromEntry => write!(f, "the entry function cannot return"),
+            FuncError::InvalidWide(opcode) => write!(f, "`Wide` cannot prefix {:?}", opcode),
+            FuncError::JumpTarget(target) => write!(f, "jump to {} is not the start of an instruction", target),
+            FuncError::StackUnderflow => write!(f, "stack underflow"),
+            FuncError::StackMismatch { expected, found } => write!(f, "stack depth {} where another path has {}", found, expected),
+            FuncError::Local(index) => write!(f, "local slot {} is out of range", index),
+            FuncError::ClosureValue(index) => write!(f, "closure value {} is out of range", index),
+            FuncError::Constant(index) => write!(f, "constant {} is out of range", index),
+            FuncError::NotAnInt(index) => write!(f, "constant {} is not an integer", index),
+            FuncError::FuncId(id) => write!(f, "func{} does not exist", id),
+            FuncError::Capture(id) => write!(f, "func{} captures a variable that does not exist", id),
+            FuncError::GlobalSlot(slot) => write!(f, "global slot {} does not exist", slot),
+            FuncError::ModuleId(id) => write!(f, "module {} does not exist", id),
+            FuncError::Builtin(index) => write!(f, "builtin {} does not exist", index),
+            FuncError::Symbol(id) => write!(f, "symbol {} does not exist", id),
+        }
+    }
+}
+
+/// Checks that every function of `program` can be run without the VM
+/// reading outside its bytecode, stack, constants or tables: opcodes and
+/// operands are valid, jumps land on instructions, and each instruction is
+/// always reached with the same stack depth, which is deep enough for it.
+///
+/// Types are not checked; adding a string to a list still fails at run
+/// time.
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    let symbol_count = program.symbols.symbols.len() as u32;
+    for (slot, (module, symbol)) in program.globals.slots.iter().enumerate() {
+        if *module >= program.modules.len() || symbol.is_some_and(|symbol| symbol.id() >= symbol_count) {
+            return Err(VerifyError::Global(slot as u32))
+        }
+    }
+    for (id, module) in program.modules.iter().enumerate() {
+        let valid = (id != module::MAIN || module.entry_func == 0)
+            && program.funcs.get(module.entry_func).is_some_and(|func| func.param_count == 0 && func.closure_scope.is_empty())
+            && (module.slot as usize) < program.globals.len()
+            && module.exports.iter().all(|symbol| symbol.id() < symbol_count && program.globals.resolve(id, *symbol).is_some());
+        if !valid {
+            return Err(VerifyError::Module(id))
+        }
+    }
+    if program.modules.is_empty() {
+        return Err(VerifyError::Module(module::MAIN))
+    }
+    for (id, func) in program.funcs.iter().enumerate() {
+        FuncVerifier { program, func, entry: id == 0 }.verify()
+            .map_err(|(offset, error)| VerifyError::Func { func: id, offset, error })?;
+    }
+    Ok(())
+}
+
+/// A decoded instruction. `Wide` is folded into the instruction it
+/// prefixes, whose operand is then two bytes.
+struct Instr<'a> {
+    opcode: Opcode,
+    operands: &'a [u8],
+    end: usize,
+}
+
+impl<'a> Instr<'a> {
+    /// The single byte or `Wide` operand.
+    fn operand(&self) -> usize {
+        match *self.operands {
+            [a] => a as usize,
+            [a, b] => u16::from_be_bytes([a, b]) as usize,
+            _ => unreachable!(),
+        }
+    }
+    fn u32(&self) -> u32 {
+        u32::from_be_bytes(self.operands[..4].try_into().unwrap())
+    }
+}
+
+struct FuncVerifier<'a> {
+    program: &'a Program,
+    func: &'a Func,
+    /// Whether this is the function the program starts in.
+    entry: bool,
+}
+
+type Error = (usize, FuncError);
+
+impl<'a> FuncVerifier<'a> {
+    fn decode(&self, offset: usize) -> Result<Instr<'a>, FuncError> {
+        let bytecode = &self.func.bytecode;
+        let decode_opcode = |byte: u8| -> Result<Opcode, FuncError> { byte.try_into().map_err(|_| FuncError::UnknownOpcode(byte)) };
+        let mut opcode = decode_opcode(bytecode[offset])?;
+        let mut start = offset + 1;
+        let mut len = opcode.operand_len();
+        if let Opcode::Wide = opcode {
+            let prefixed = decode_opcode(*bytecode.get(start).ok_or(FuncError::Truncated)?)?;
+            match prefixed {
+                Opcode::PushLoad | Opcode::PopStore | Opcode::PushClosureLoad | Opcode::PopClosureStore |
+                Opcode::PushPropLoad | Opcode::Drop | Opcode::Call | Opcode::PopPrint => (),
+                _ => return Err(FuncError::InvalidWide(prefixed)),
+            }
+            opcode = prefixed;
+            start += 1;
+            len = 2;
+        }
+        let operands = bytecode.get(start..start + len).ok_or(FuncError::Truncated)?;
+        Ok(Instr { opcode, operands, end: start + len })
+    }
+    fn verify(&self) -> Result<(), Error> {
+        let func = self.func;
+        if func.param_names.len() != func.param_count as usize {
+            return Err((0, FuncError::Params))
+        }
+        let valid_symbol = |symbol: &Symbol| (symbol.id() as usize) < self.program.symbols.symbols.len();
+        if !func.param_names.iter().all(valid_symbol) {
+            return Err((0, FuncError::Params))
+        }
+        let closure_names_valid = func.closure_names.is_empty() || func.closure_names.len() == func.closure_scope.len();
+        if !closure_names_valid || !func.closure_names.iter().all(valid_symbol) || !func.local_names.iter().all(|(_, _, symbol)| valid_symbol(symbol)) {
+            return Err((0, FuncError::DebugInfo))
+        }
+
+        let mut starts = vec![false; func.bytecode.len()];
+        let mut offset = 0;
+        while offset < func.bytecode.len() {
+            starts[offset] = true;
+            offset = self.decode(offset).map_err(|error| (offset, error))?.end;
+        }
+
+        // The stack depth above the frame on entry to each instruction,
+        // counting the return slot and parameters.
+        let mut depths: Vec<Option<usize>> = vec![None; func.bytecode.len()];
+        let mut pending = vec![(0, 1 + func.param_count as usize)];
+        while let Some((offset, depth)) = pending.pop() {
+            if offset >= func.bytecode.len() {
+                return Err((offset, FuncError::FallsOffEnd))
+            }
+            match depths[offset] {
+                Some(expected) if expected == depth => continue,
+                Some(expected) => return Err((offset, FuncError::StackMismatch { expected, found: depth })),
+                None => depths[offset] = Some(depth),
+            }
+            let instr = self.decode(offset).map_err(|error| (offset, error))?;
+            let (next, jump) = self.step(&instr, depth).map_err(|error| (offset, error))?;
+            if let Some((target, depth)) = jump {
+                if target < 0 || target as usize >= starts.len() || !starts[target as usize] {
+                    return Err((offset, FuncError::JumpTarget(target)))
+                }
+                pending.push((target as usize, depth));
+            }
+            if let Some(depth) = next {
+                pending.push((instr.end, depth));
+            }
+        }
+        Ok(())
+    }
+    /// Checks one instruction run with `depth` values on the stack, and
+    /// returns the depth after it falls through, if it can, and the target
+    /// and depth of the jump it can take, if any.
+    #[allow(clippy::type_complexity)]
+    fn step(&self, instr: &Instr, depth: usize) -> Result<(Option<usize>, Option<(isize, usize)>), FuncError> {
+        let func = self.func;
+        let pop = |n: usize| depth.checked_sub(n).filter(|depth| *depth >= 1).ok_or(FuncError::StackUnderflow);
+        let local = |index: usize| if index < depth { Ok(()) } else { Err(FuncError::Local(index)) };
+        let closure_value = |index: usize| if index < func.closure_scope.len() { Ok(()) } else { Err(FuncError::ClosureValue(index)) };
+        let constant = |index: usize| func.constants.get(index).ok_or(FuncError::Constant(index));
+
+        if let Some((kind, width)) = instr.opcode.jump() {
+            let locals = &instr.operands[..kind.prefix_len()];
+            let relative = opcode::read_jump_offset(&instr.operands[locals.len()..locals.len() + width]);
+            let target = instr.end as isize + relative;
+            let depth = match kind {
+                JumpKind::Always => return Ok((None, Some((target, depth)))),
+                JumpKind::If | JumpKind::IfNot | JumpKind::IfNone => pop(1)?,
+                JumpKind::IfNotLessLocals => {
+                    for local_index in locals {
+                        local(*local_index as usize)?;
+                    }
+                    depth
+                }
+            };
+            return Ok((Some(depth), Some((target, depth))))
+        }
+
+        let depth = match instr.opcode {
+            Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide | Opcode::IntDivide | Opcode::Modulus |
+            Opcode::Equal | Opcode::NotEqual | Opcode::Less | Opcode::Greater | Opcode::LessOrEqual | Opcode::GreaterOrEqual => pop(2)? + 1,
+
+            Opcode::PushTrue | Opcode::PushFalse | Opcode::PushNone => depth + 1,
+            Opcode::PushConst | Opcode::PushConstWide => {
+                let index = match instr.opcode {
+                    Opcode::PushConst => instr.operands[0] as usize,
+                    _ => instr.u32() as usize,
+                };
+                if let Constant::Func(id) = constant(index)? {
+                    self.check_capture(*id, depth)?;
+                }
+                depth + 1
+            }
+            Opcode::PushBuiltin => {
+                let index = instr.operands[0];
+                if index as usize >= BUILTINS.len() {
+                    return Err(FuncError::Builtin(index))
+                }
+                depth + 1
+            }
+            Opcode::PushLoad => {
+                local(instr.operand())?;
+                depth + 1
+            }
+            Opcode::PushClosureLoad => {
+                closure_value(instr.operand())?;
+                depth + 1
+            }
+            Opcode::PushGlobalLoad => {
+                self.global(instr.u32())?;
+                depth + 1
+            }
+            Opcode::ImportModule => {
+                let id = instr.u32();
+                if id as usize >= self.program.modules.len() {
+                    return Err(FuncError::ModuleId(id))
+                }
+                depth + 1
+            }
+            Opcode::PushList => pop(instr.u32() as usize)? + 1,
+            Opcode::PushPropLoad => {
+                let symbol = instr.operand();
+                if symbol >= self.program.symbols.symbols.len() {
+                    return Err(FuncError::Symbol(symbol))
+                }
+                pop(1)? + 1
+            }
+
+            Opcode::PopStore => {
+                local(instr.operand())?;
+                pop(1)?
+            }
+            Opcode::PopClosureStore => {
+                closure_value(instr.operand())?;
+                pop(1)?
+            }
+            Opcode::PopGlobalStore => {
+                self.global(instr.u32())?;
+                pop(1)?
+            }
+            Opcode::PopPrint | Opcode::Drop => pop(instr.operand())?,
+            Opcode::Call => pop(instr.operand() + 1)?,
+
+            Opcode::IncLocal => {
+                local(instr.operands[0] as usize)?;
+                depth
+            }
+            Opcode::AddLocalConst => {
+                local(instr.operands[0] as usize)?;
+                let index = instr.operands[1] as usize;
+                match constant(index)? {
+                    Constant::Int(_) => depth,
+                    _ => return Err(FuncError::NotAnInt(index)),
+                }
+            }
+
+            Opcode::Return if self.entry => return Err(FuncError::ReturnFromEntry),
+            Opcode::Return | Opcode::Finish => return Ok((None, None)),
+
+            Opcode::Jump8 | Opcode::Jump16 | Opcode::Jump32 |
+            Opcode::JumpIf8 | Opcode::JumpIf16 | Opcode::JumpIf32 |
+            Opcode::JumpIfNot8 | Opcode::JumpIfNot16 | Opcode::JumpIfNot32 |
+            Opcode::JumpIfNone8 | Opcode::JumpIfNone16 | Opcode::JumpIfNone32 |
+            Opcode::JumpIfNotLessLocals8 | Opcode::JumpIfNotLessLocals16 | Opcode::JumpIfNotLessLocals32 |
+            Opcode::Wide => unreachable!(),
+        };
+        Ok((Some(depth), None))
+    }
+    fn global(&self, slot: u32) -> Result<(), FuncError> {
+        if (slot as usize) < self.program.globals.len() {
+            Ok(())
+        } else {
+            Err(FuncError::GlobalSlot(slot))
+        }
+    }
+    /// Checks that a closure over func `id` created here only captures
+    /// locals below `depth` and this function's own closure values.
+    fn check_capture(&self, id: u32, depth: usize) -> Result<(), FuncError> {
+        let child = self.program.funcs.get(id as usize).ok_or(FuncError::FuncId(id))?;
+        let valid = child.closure_scope.iter().all(|var| match *var {
+            ClosureValue::Stack(index) => (index as usize) < depth,
+            ClosureValue::Outer(index) => (index as usize) < self.func.closure_scope.len(),
+        });
+        if valid {
+            Ok(())
+        } else {
+            Err(FuncError::Capture(id))
+        }
+    }
+}
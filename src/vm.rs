@@ -1,20 +1,62 @@
 use core::cmp::Ordering;
-use std::{collections::HashMap, fmt::Debug};
+use std::{collections::HashMap, fmt::{self, Debug}, io::{self, Write}};
 use std::mem::size_of;
 use std::convert::TryInto;
+use std::sync::{Arc, atomic::{self, AtomicBool}};
 
-use crate::parser::Program;
-use crate::value::{Value, ClosureValueRef, Closure, DispValue};
-use crate::{heap::{Heap, HeapPtr}, opcode::Opcode, list::List, func::{Func, ClosureValue}};
+use crate::{compiler::Program, globals::GlobalValues, symbols::Symbol, module::ModuleValue, compact_value::CompactValue};
+use crate::value::{Value, ClosureValueRef, Closure, DispValue, RustValue};
+use crate::{heap::{Heap, HeapPtr}, opcode::{self, Opcode}, list::List, string::Str, func::{Func, ClosureValue, Constant}, builtins::BUILTINS};
 
 pub struct VirtualMachine<'a> {
     pub program: &'a Program,
     call: Call,
-    stack: &'a mut Vec<Value>,
+    stack: &'a mut Vec<CompactValue>,
     call_stack: Vec<Call>,
-    heap: &'a mut Heap,
+    pub heap: &'a mut Heap,
+    pub globals: &'a mut GlobalValues,
     finished: bool,
     closure_ref_map: HashMap<usize, Vec<HeapPtr<ClosureValueRef>>>,
+    fuel: Option<u64>,
+    interrupt: Arc<AtomicBool>,
+    output: Box<dyn Write + 'a>,
+    print_separator: String,
+    print_terminator: String,
+}
+
+/// Lets another thread stop a running `VirtualMachine` at the next
+/// instruction boundary.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    OutOfFuel,
+    Interrupted,
+    Output(io::ErrorKind),
+    IntegerOverflow,
+    ZeroDivision,
+    /// The script called `exit` with this status.
+    Exit(u8),
+    /// The script called `exit` with a status outside 0 to 255.
+    ExitStatus(i64),
+    /// A value of the named type has no property of this name.
+    NoProperty(&'static str, String),
+    /// An arithmetic or comparison operator was applied to values of these
+    /// types.
+    InvalidOperands(&'static str, &'static str),
+    /// A value of the second type was used where the first is needed.
+    Type {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// A function was called with the wrong number of arguments.
+    ArgCount {
+        expected: usize,
+        found: usize,
+    },
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -24,6 +66,32 @@ struct Call {
     closure: HeapPtr<Closure>,
 }
 
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.flag.store(true, atomic::Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::OutOfFuel => write!(f, "out of fuel"),
+            RuntimeError::Interrupted => write!(f, "interrupted"),
+            RuntimeError::Output(kind) => write!(f, "cannot write output: {}", kind),
+            RuntimeError::IntegerOverflow => write!(f, "integer overflow"),
+            RuntimeError::ZeroDivision => write!(f, "division by zero"),
+            RuntimeError::Exit(status) => write!(f, "exited with status {}", status),
+            RuntimeError::ExitStatus(status) => write!(f, "exit status {} is not between 0 and 255", status),
+            RuntimeError::NoProperty(type_name, name) => write!(f, "{} has no property `{}`", type_name, name),
+            RuntimeError::InvalidOperands(a, b) => write!(f, "invalid operands {} and {}", a, b),
+            RuntimeError::Type { expected, found } => write!(f, "expected {}, found {}", expected, found),
+            RuntimeError::ArgCount { expected, found } => {
+                write!(f, "expected {} argument{}, found {}", expected, if *expected == 1 { "" } else { "s" }, found)
+            }
+        }
+    }
+}
+
 impl PartialEq for Value {
     fn eq(&self, other: &Value) -> bool {
         match (self, other) {
@@ -31,6 +99,11 @@ impl PartialEq for Value {
             (Value::Float(a), Value::Float(b)) => a == b,
             (Value::Bool(a), Value::Bool(b)) => a == b,
             (Value::None, Value::None) => true,
+            (Value::NativeFunc(a), Value::NativeFunc(b)) => std::ptr::eq(*a, *b),
+            (Value::RustValue(a), Value::RustValue(b)) => match (a.downcast_ref::<Str>(), b.downcast_ref::<Str>()) {
+                (Some(a), Some(b)) => a.as_str() == b.as_str(),
+                _ => std::ptr::addr_eq(&**a as *const dyn RustValue, &**b as *const dyn RustValue),
+            },
             _ => false,
         }
     }
@@ -60,26 +133,145 @@ impl Closure {
     }
 }
 
+/// Integer division rounding towards negative infinity.
+pub(crate) fn floor_div(a: i64, b: i64) -> Option<i64> {
+    let quotient = a.checked_div(b)?;
+    if a % b != 0 && (a < 0) != (b < 0) {
+        Some(quotient - 1)
+    } else {
+        Some(quotient)
+    }
+}
+
+/// The remainder of `floor_div`, taking the sign of the divisor so that
+/// `a == floor_div(a, b) * b + floor_mod(a, b)`. Dividing by `-1` leaves
+/// no remainder, even for `i64::MIN` whose quotient overflows.
+pub(crate) fn floor_mod(a: i64, b: i64) -> Option<i64> {
+    if b == -1 {
+        return Some(0)
+    }
+    let remainder = a.checked_rem(b)?;
+    if remainder != 0 && (remainder < 0) != (b < 0) {
+        Some(remainder + b)
+    } else {
+        Some(remainder)
+    }
+}
+
+/// The float counterpart of `floor_mod`, matching `(a / b).floor()`.
+pub(crate) fn float_floor_mod(a: f64, b: f64) -> f64 {
+    let remainder = a % b;
+    if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+        remainder + b
+    } else {
+        remainder
+    }
+}
+
 impl<'a> VirtualMachine<'a> {
-    fn arithmetic_op(&mut self, int: fn(i64, i64) -> i64, float: fn(f64, f64) -> f64) {
-        let c = match (self.stack.pop().unwrap(), self.stack.pop().unwrap()) {
-            (Value::Int(a), Value::Int(b)) => Value::Int(int(b, a)),
+    /// Integer arithmetic is checked; a result that does not fit in an
+    /// `i64` is a runtime error rather than wrapping.
+    #[inline(always)]
+    fn arithmetic_op(&mut self, int: fn(i64, i64) -> Option<i64>, float: fn(f64, f64) -> f64) -> Result<(), RuntimeError> {
+        let len = self.stack.len();
+        let (a, b) = (self.stack[len - 2], self.stack[len - 1]);
+        if let (Some(a), Some(b)) = (a.as_int(), b.as_int()) {
+            self.stack.truncate(len - 2);
+            let c = CompactValue::from_int(int(a, b).ok_or(RuntimeError::IntegerOverflow)?, self.heap);
+            self.stack.push(c);
+            return Ok(())
+        }
+        if let (Some(a), Some(b)) = (a.as_float(), b.as_float()) {
+            self.stack.truncate(len - 2);
+            self.stack.push(CompactValue::from_float(float(a, b)));
+            return Ok(())
+        }
+        let c = match (self.pop(), self.pop()) {
+            (Value::Int(a), Value::Int(b)) => Value::Int(int(b, a).ok_or(RuntimeError::IntegerOverflow)?),
             (Value::Int(a), Value::Float(b)) => Value::Float(float(b, a as f64)),
             (Value::Float(a), Value::Int(b)) => Value::Float(float(b as f64, a)),
             (Value::Float(a), Value::Float(b)) => Value::Float(float(b, a)),
-            (a, b) => panic!("invalid operands {} and {}", DispValue::new(a, self.program), DispValue::new(b, self.program)),
+            (b, a) => return Err(RuntimeError::InvalidOperands(a.type_name(), b.type_name())),
         };
-        self.stack.push(c);
-    }
-    fn comparison_op(&mut self, f: fn(Ordering) -> bool) {
-        let ord = match (self.stack.pop().unwrap(), self.stack.pop().unwrap()) {
-            (Value::Int(b), Value::Int(a)) => a.cmp(&b),
-            (Value::Int(b), Value::Float(a)) => a.partial_cmp(&(b as f64)).unwrap(),
-            (Value::Float(b), Value::Int(a)) => (a as f64).partial_cmp(&b).unwrap(),
-            (Value::Float(b), Value::Float(a)) => a.partial_cmp(&b).unwrap(),
-            _ => panic!(),
+        self.push(c);
+        Ok(())
+    }
+    fn push_const(&mut self, index: usize) {
+        let program = self.program;
+        match &program.funcs[self.call.closure.func_id].constants[index] {
+            Constant::Int(int) => self.stack.push(CompactValue::from_int(*int, self.heap)),
+            Constant::Float(float) => self.stack.push(CompactValue::from_float(*float)),
+            Constant::String(string) => {
+                let string = Str::new(self.heap, string);
+                let string = self.heap.alloc_rust_value(string);
+                self.push(Value::RustValue(string))
+            }
+            Constant::Func(func_id) => {
+                let closure = Closure::new(
+                    *func_id as usize,
+                    Some(&self.call.closure),
+                    self.call.frame,
+                    self.heap,
+                    &mut self.closure_ref_map,
+                    &program.funcs,
+                );
+                let closure = self.heap.alloc(closure);
+                self.push(Value::Closure(closure))
+            }
+        }
+    }
+    /// Adds `int` to a local in place, for the fused opcodes that stand in
+    /// for `PushLoad; PushConst; Add; PopStore`.
+    #[inline(always)]
+    fn add_local(&mut self, slot: usize, int: i64) -> Result<(), RuntimeError> {
+        let value = self.stack[slot];
+        if let Some(sum) = value.as_int().and_then(|value| value.checked_add(int)) {
+            self.stack[slot] = CompactValue::from_int(sum, self.heap);
+            return Ok(())
+        }
+        self.stack.extend([value, CompactValue::from_int(int, self.heap)]);
+        self.arithmetic_op(i64::checked_add, |a, b| a + b)?;
+        self.stack[slot] = self.stack.pop().unwrap();
+        Ok(())
+    }
+    /// `/` is true division and always produces a float.
+    fn divide_op(&mut self) -> Result<(), RuntimeError> {
+        self.check_divisor()?;
+        let c = match (self.pop(), self.pop()) {
+            (Value::Int(b), Value::Int(a)) => a as f64 / b as f64,
+            (Value::Int(b), Value::Float(a)) => a / b as f64,
+            (Value::Float(b), Value::Int(a)) => a as f64 / b,
+            (Value::Float(b), Value::Float(a)) => a / b,
+            (b, a) => return Err(RuntimeError::InvalidOperands(a.type_name(), b.type_name())),
+        };
+        self.push(Value::Float(c));
+        Ok(())
+    }
+    fn check_divisor(&self) -> Result<(), RuntimeError> {
+        match self.stack.last().unwrap().decode() {
+            Value::Int(0) => Err(RuntimeError::ZeroDivision),
+            Value::Float(0.0) => Err(RuntimeError::ZeroDivision),
+            _ => Ok(()),
+        }
+    }
+    /// Any comparison involving NaN is false, as in IEEE 754.
+    #[inline(always)]
+    fn comparison_op(&mut self, f: fn(Ordering) -> bool) -> Result<(), RuntimeError> {
+        let len = self.stack.len();
+        if let (Some(a), Some(b)) = (self.stack[len - 2].as_int(), self.stack[len - 1].as_int()) {
+            self.stack.truncate(len - 2);
+            self.stack.push(CompactValue::from_bool(f(a.cmp(&b))));
+            return Ok(())
+        }
+        let ord = match (self.pop(), self.pop()) {
+            (Value::Int(b), Value::Int(a)) => Some(a.cmp(&b)),
+            (Value::Int(b), Value::Float(a)) => a.partial_cmp(&(b as f64)),
+            (Value::Float(b), Value::Int(a)) => (a as f64).partial_cmp(&b),
+            (Value::Float(b), Value::Float(a)) => a.partial_cmp(&b),
+            (b, a) => return Err(RuntimeError::InvalidOperands(a.type_name(), b.type_name())),
         };
-        self.stack.push(Value::Bool(f(ord)))
+        self.push(Value::Bool(ord.is_some_and(f)));
+        Ok(())
     }
     fn take_bytes(&mut self, n: usize) -> &[u8] {
         let func = &self.program.funcs[self.call.closure.func_id];
@@ -87,156 +279,313 @@ impl<'a> VirtualMachine<'a> {
         self.call.pc += n;
         bytes
     }
+    #[inline]
+    fn push(&mut self, value: Value) {
+        let value = CompactValue::encode(value, self.heap);
+        self.stack.push(value)
+    }
+    #[inline]
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap().decode()
+    }
     fn drop(&mut self) {
         let value = self.stack.pop().unwrap();
-        match self.closure_ref_map.remove(&self.stack.len()) {
-            Some(ref_list) => {
-                if !ref_list.is_empty() {
-                    let heap_value = self.heap.alloc(value);
-                    for mut closure_ref in ref_list {
-                        *closure_ref = ClosureValueRef::Heap(heap_value)
-                    }
+        if let Some(ref_list) = self.closure_ref_map.remove(&self.stack.len()) {
+            if !ref_list.is_empty() {
+                let heap_value = self.heap.alloc(value);
+                for mut closure_ref in ref_list {
+                    *closure_ref = ClosureValueRef::Heap(heap_value)
+                }
+            }
+        }
+    }
+    #[inline(always)]
+    fn take_jump_offset(&mut self, width: usize) -> isize {
+        opcode::read_jump_offset(self.take_bytes(width))
+    }
+    /// Moves the program counter relative to the end of the current
+    /// instruction.
+    #[inline(always)]
+    fn jump_by(&mut self, offset: isize) {
+        self.call.pc = (self.call.pc as isize + offset) as usize
+    }
+    fn pop_condition(&mut self) -> Result<bool, RuntimeError> {
+        match self.stack.pop().unwrap() {
+            CompactValue::TRUE => Ok(true),
+            CompactValue::FALSE => Ok(false),
+            value => Err(RuntimeError::Type { expected: "bool", found: value.decode().type_name() }),
+        }
+    }
+    #[inline(always)]
+    fn push_load(&mut self, index: usize) {
+        self.stack.push(self.stack[self.call.frame + index])
+    }
+    #[inline(always)]
+    fn push_closure_load(&mut self, index: usize) {
+        self.stack.push(match *self.call.closure.closure_values[index] {
+            ClosureValueRef::Stack(index) => self.stack[index],
+            ClosureValueRef::Heap(ptr) => *ptr,
+        });
+    }
+    fn push_prop_load(&mut self, index: usize) -> Result<(), RuntimeError> {
+        let symbol = Symbol::from_index(index as u32);
+        match self.pop() {
+            Value::RustValue(mut value) => {
+                let prop = value.get_property(symbol, self)?;
+                self.push(prop);
+                Ok(())
+            }
+            value => Err(RuntimeError::NoProperty(value.type_name(), self.program.symbols.get_name(symbol).to_string())),
+        }
+    }
+    #[inline(always)]
+    fn pop_store(&mut self, index: usize) {
+        self.stack[self.call.frame + index] = self.stack.pop().unwrap()
+    }
+    #[inline(always)]
+    fn pop_closure_store(&mut self, index: usize) {
+        let val = self.stack.pop().unwrap();
+        match *self.call.closure.closure_values[index] {
+            ClosureValueRef::Stack(index) => self.stack[index] = val,
+            ClosureValueRef::Heap(mut ptr) => *ptr = val,
+        }
+    }
+    fn drop_n(&mut self, n: usize) {
+        for _ in 0..n {
+            self.drop()
+        }
+    }
+    fn call(&mut self, arg_count: usize) -> Result<(), RuntimeError> {
+        match self.pop() {
+            Value::Closure(closure) => {
+                let param_count = self.program.funcs[closure.func_id].param_count as usize;
+                if arg_count != param_count {
+                    return Err(RuntimeError::ArgCount { expected: param_count, found: arg_count })
                 }
+                self.call_stack.push(self.call);
+                self.call = Call {
+                    pc: 0,
+                    frame: self.stack.len() - arg_count - 1,
+                    closure,
+                };
             }
-            _ => (),
+            Value::NativeFunc(native) => {
+                if arg_count != native.param_count as usize {
+                    return Err(RuntimeError::ArgCount { expected: native.param_count as usize, found: arg_count })
+                }
+                let args: Vec<_> = self.stack.split_off(self.stack.len() - arg_count)
+                    .into_iter()
+                    .map(CompactValue::decode)
+                    .collect();
+                let result = (native.func)(self, &args)?;
+                *self.stack.last_mut().unwrap() = CompactValue::encode(result, self.heap);
+            }
+            value => return Err(RuntimeError::Type { expected: "func", found: value.type_name() }),
+        }
+        Ok(())
+    }
+    fn print(&mut self, count: usize) -> io::Result<()> {
+        let values = self.stack.split_off(self.stack.len() - count);
+        for (i, value) in values.into_iter().enumerate() {
+            if i > 0 {
+                self.output.write_all(self.print_separator.as_bytes())?;
+            }
+            write!(self.output, "{}", DispValue::new(value.decode(), self.program))?;
         }
+        self.output.write_all(self.print_terminator.as_bytes())
     }
-    fn step(&mut self) {
+    fn step(&mut self) -> Result<(), RuntimeError> {
         let opcode = self.take_bytes(1)[0].try_into().unwrap();
         match opcode {
-            Opcode::Add => self.arithmetic_op(|a, b| a + b, |a, b| a + b),
-            Opcode::Subtract => self.arithmetic_op(|a, b| a - b, |a, b| a - b),
-            Opcode::Multiply => self.arithmetic_op(|a, b| a * b, |a, b| a * b),
-            Opcode::Divide => self.arithmetic_op(|a, b| a / b, |a, b| a / b),
-            Opcode::Modulus => self.arithmetic_op(|a, b| a % b, |a, b| a % b),
+            Opcode::Add => self.arithmetic_op(i64::checked_add, |a, b| a + b)?,
+            Opcode::Subtract => self.arithmetic_op(i64::checked_sub, |a, b| a - b)?,
+            Opcode::Multiply => self.arithmetic_op(i64::checked_mul, |a, b| a * b)?,
+            Opcode::Divide => self.divide_op()?,
+            Opcode::IntDivide => {
+                self.check_divisor()?;
+                self.arithmetic_op(floor_div, |a, b| (a / b).floor())?
+            }
+            Opcode::Modulus => {
+                self.check_divisor()?;
+                self.arithmetic_op(floor_mod, float_floor_mod)?
+            }
 
             Opcode::Equal => {
-                let val = self.stack.pop().unwrap() == self.stack.pop().unwrap();
-                self.stack.push(Value::Bool(val))
+                let val = self.pop() == self.pop();
+                self.push(Value::Bool(val))
             }
             Opcode::NotEqual => {
-                let val = self.stack.pop().unwrap() != self.stack.pop().unwrap();
-                self.stack.push(Value::Bool(val))
+                let val = self.pop() != self.pop();
+                self.push(Value::Bool(val))
             }
 
-            Opcode::Less => self.comparison_op(|ord| ord.is_lt()),
-            Opcode::Greater => self.comparison_op(|ord| ord.is_gt()),
-            Opcode::LessOrEqual => self.comparison_op(|ord| ord.is_le()),
-            Opcode::GreaterOrEqual => self.comparison_op(|ord| ord.is_ge()),
+            Opcode::Less => self.comparison_op(|ord| ord.is_lt())?,
+            Opcode::Greater => self.comparison_op(|ord| ord.is_gt())?,
+            Opcode::LessOrEqual => self.comparison_op(|ord| ord.is_le())?,
+            Opcode::GreaterOrEqual => self.comparison_op(|ord| ord.is_ge())?,
 
-            Opcode::PushInt => {
-                let bytes = self.take_bytes(size_of::<i64>()).try_into().unwrap();
-                self.stack.push(Value::Int(i64::from_be_bytes(bytes)));
+            Opcode::PushConst => {
+                let index = self.take_bytes(1)[0] as usize;
+                self.push_const(index)
             }
-            Opcode::PushFloat => {
-                let bytes = self.take_bytes(size_of::<f64>()).try_into().unwrap();
-                self.stack.push(Value::Float(f64::from_be_bytes(bytes)));
+            Opcode::PushConstWide => {
+                let index = u32::from_be_bytes(self.take_bytes(size_of::<u32>()).try_into().unwrap()) as usize;
+                self.push_const(index)
             }
-            Opcode::PushTrue => self.stack.push(Value::Bool(true)),
-            Opcode::PushFalse => self.stack.push(Value::Bool(false)),
-            Opcode::PushNone => self.stack.push(Value::None),
+            Opcode::PushTrue => self.stack.push(CompactValue::TRUE),
+            Opcode::PushFalse => self.stack.push(CompactValue::FALSE),
+            Opcode::PushNone => self.stack.push(CompactValue::NONE),
             Opcode::PushLoad => {
                 let index = self.take_bytes(1)[0] as usize;
-                self.stack.push(self.stack[self.call.frame + index])
+                self.push_load(index)
             }
             Opcode::PushClosureLoad => {
                 let index = self.take_bytes(1)[0] as usize;
-                self.stack.push(match *self.call.closure.closure_values[index] {
-                    ClosureValueRef::Stack(index) => self.stack[index],
-                    ClosureValueRef::Heap(ptr) => *ptr,
-                });
+                self.push_closure_load(index)
             }
             Opcode::PushPropLoad => {
-                let index = self.take_bytes(1)[0];
-                match self.stack.pop().unwrap() {
-                    Value::RustValue(mut value) => {
-                        let prop = value.get_property(index, self);
-                        self.stack.push(prop);
+                let index = self.take_bytes(1)[0] as usize;
+                self.push_prop_load(index)?
+            }
+            Opcode::PushGlobalLoad => {
+                let slot = u32::from_be_bytes(self.take_bytes(size_of::<u32>()).try_into().unwrap());
+                self.stack.push(self.globals.get(slot))
+            }
+            Opcode::PushBuiltin => {
+                let index = self.take_bytes(1)[0] as usize;
+                self.push(Value::NativeFunc(&BUILTINS[index]))
+            }
+            Opcode::ImportModule => {
+                let id = u32::from_be_bytes(self.take_bytes(size_of::<u32>()).try_into().unwrap()) as usize;
+                let module = &self.program.modules[id];
+                match self.globals.get(module.slot).decode() {
+                    Value::None => {
+                        let value = Value::RustValue(self.heap.alloc_rust_value(ModuleValue::new(id, &module.path)));
+                        let value = CompactValue::encode(value, self.heap);
+                        self.globals.set(module.slot, value);
+                        self.stack.push(value);
+                        let closure = Closure::new(module.entry_func, None, 0, self.heap, &mut self.closure_ref_map, &self.program.funcs);
+                        self.call_stack.push(self.call);
+                        self.call = Call {
+                            pc: 0,
+                            frame: self.stack.len() - 1,
+                            closure: self.heap.alloc(closure),
+                        };
                     }
-                    _ => panic!(),
+                    _ => self.stack.push(self.globals.get(module.slot)),
                 }
             }
-            Opcode::PushFunc => {
-                let func_id = u32::from_be_bytes(self.take_bytes(size_of::<u32>()).try_into().unwrap());
-                let closure = Closure::new(
-                    func_id as usize,
-                    Some(&self.call.closure),
-                    self.call.frame,
-                    &mut self.heap,
-                    &mut self.closure_ref_map,
-                    &self.program.funcs,
-                );
-                self.stack.push(Value::Closure(self.heap.alloc(closure)))
-            }
             Opcode::PushList => {
                 let length = u32::from_be_bytes(self.take_bytes(size_of::<u32>()).try_into().unwrap()) as usize;
-                let list = List::new(&mut self.heap, length, &mut self.stack);
-                self.stack.push(Value::RustValue(self.heap.alloc(list)))
+                let list = List::new(self.heap, length, self.stack);
+                let list = self.heap.alloc_rust_value(list);
+                self.push(Value::RustValue(list))
             }
             Opcode::PopStore => {
-                let index = self.take_bytes(1)[0];
-                self.stack[self.call.frame + index as usize] = self.stack.pop().unwrap()
+                let index = self.take_bytes(1)[0] as usize;
+                self.pop_store(index)
             }
             Opcode::PopClosureStore => {
                 let index = self.take_bytes(1)[0] as usize;
+                self.pop_closure_store(index)
+            }
+            Opcode::PopGlobalStore => {
+                let slot = u32::from_be_bytes(self.take_bytes(size_of::<u32>()).try_into().unwrap());
                 let val = self.stack.pop().unwrap();
-                match *self.call.closure.closure_values[index] {
-                    ClosureValueRef::Stack(index) => self.stack[index] = val,
-                    ClosureValueRef::Heap(mut ptr) => *ptr = val,
+                self.globals.set(slot, val)
+            }
+            Opcode::PopPrint => {
+                let count = self.take_bytes(1)[0] as usize;
+                self.print(count).map_err(|err| RuntimeError::Output(err.kind()))?
+            }
+            Opcode::Jump8 | Opcode::Jump16 | Opcode::Jump32 => {
+                let offset = self.take_jump_offset(opcode.operand_len());
+                self.jump_by(offset)
+            }
+            Opcode::JumpIf8 | Opcode::JumpIf16 | Opcode::JumpIf32 => {
+                let offset = self.take_jump_offset(opcode.operand_len());
+                if self.pop_condition()? {
+                    self.jump_by(offset)
                 }
             }
-            Opcode::PopPropStore => {
-                todo!()
+            Opcode::JumpIfNot8 | Opcode::JumpIfNot16 | Opcode::JumpIfNot32 => {
+                let offset = self.take_jump_offset(opcode.operand_len());
+                if !self.pop_condition()? {
+                    self.jump_by(offset)
+                }
             }
-            Opcode::PopPrint => {
-                let value = self.stack.pop().unwrap();
-                println!("{}", DispValue::new(value, self.program))
-            }
-            Opcode::Jump => self.call.pc = u32::from_be_bytes(self.take_bytes(size_of::<u32>()).try_into().unwrap()) as usize,
-            Opcode::JumpIfNot => {
-                let pc = u32::from_be_bytes(self.take_bytes(size_of::<u32>()).try_into().unwrap());
-                match self.stack.pop().unwrap() {
-                    Value::Bool(b) => if !b {
-                        self.call.pc = pc as usize;
-                    }
-                    value => panic!("{}", DispValue::new(value, self.program))
+            Opcode::JumpIfNone8 | Opcode::JumpIfNone16 | Opcode::JumpIfNone32 => {
+                let offset = self.take_jump_offset(opcode.operand_len());
+                if self.stack.pop().unwrap() == CompactValue::NONE {
+                    self.jump_by(offset)
                 }
             }
             Opcode::Drop => {
                 let n = self.take_bytes(1)[0] as usize;
-                for _ in 0..n {
-                    self.drop()
+                self.drop_n(n)
+            }
+            Opcode::IncLocal => {
+                let slot = self.call.frame + self.take_bytes(1)[0] as usize;
+                self.add_local(slot, 1)?
+            }
+            Opcode::AddLocalConst => {
+                let slot = self.call.frame + self.take_bytes(1)[0] as usize;
+                let index = self.take_bytes(1)[0] as usize;
+                match self.program.funcs[self.call.closure.func_id].constants[index] {
+                    Constant::Int(int) => self.add_local(slot, int)?,
+                    _ => panic!(),
                 }
             }
-            Opcode::Call => match self.stack.pop().unwrap() {
-                Value::Closure(closure) => {
-                    let arg_count = self.take_bytes(1)[0];
-                    if arg_count != self.program.funcs[closure.func_id].param_count {
-                        panic!()
+            Opcode::JumpIfNotLessLocals8 | Opcode::JumpIfNotLessLocals16 | Opcode::JumpIfNotLessLocals32 => {
+                let (a, b) = (self.take_bytes(1)[0] as usize, self.take_bytes(1)[0] as usize);
+                let (a, b) = (self.stack[self.call.frame + a], self.stack[self.call.frame + b]);
+                let offset = self.take_jump_offset(opcode.operand_len() - 2);
+                let less = match (a.as_int(), b.as_int()) {
+                    (Some(a), Some(b)) => a < b,
+                    _ => {
+                        self.stack.extend([a, b]);
+                        self.comparison_op(|ord| ord.is_lt())?;
+                        self.stack.pop().unwrap() == CompactValue::TRUE
                     }
-                    self.call_stack.push(self.call);
-                    self.call = Call {
-                        pc: 0,
-                        frame: self.stack.len() - arg_count as usize - 1,
-                        closure,
-                    };
+                };
+                if !less {
+                    self.jump_by(offset)
                 }
-                value => panic!("{}", DispValue::new(value, self.program)),
+            }
+            Opcode::Call => {
+                let arg_count = self.take_bytes(1)[0] as usize;
+                self.call(arg_count)?
             }
             Opcode::Return => {
-                for _ in 0..self.program.funcs[self.call.closure.func_id].param_count {
+                while self.stack.len() > self.call.frame + 1 {
                     self.drop()
                 }
                 self.call = self.call_stack.pop().unwrap()
             }
+            Opcode::Wide => {
+                let opcode = self.take_bytes(1)[0].try_into().unwrap();
+                let operand = u16::from_be_bytes(self.take_bytes(size_of::<u16>()).try_into().unwrap()) as usize;
+                match opcode {
+                    Opcode::PushLoad => self.push_load(operand),
+                    Opcode::PushClosureLoad => self.push_closure_load(operand),
+                    Opcode::PushPropLoad => self.push_prop_load(operand)?,
+                    Opcode::PopStore => self.pop_store(operand),
+                    Opcode::PopClosureStore => self.pop_closure_store(operand),
+                    Opcode::PopPrint => self.print(operand).map_err(|err| RuntimeError::Output(err.kind()))?,
+                    Opcode::Drop => self.drop_n(operand),
+                    Opcode::Call => self.call(operand)?,
+                    _ => panic!(),
+                }
+            }
             Opcode::Finish => self.finished = true,
         }
+        Ok(())
     }
-    pub fn run(program: &Program, entry_func: usize, stack: &mut Vec<Value>, heap: &mut Heap) {
+    pub fn new(program: &'a Program, entry_func: usize, stack: &'a mut Vec<CompactValue>, heap: &'a mut Heap, globals: &'a mut GlobalValues) -> VirtualMachine<'a> {
         let mut closure_ref_map = HashMap::new();
         let closure = Closure::new(entry_func, None, 0, heap, &mut closure_ref_map, &program.funcs);
 
-        let mut vm = VirtualMachine {
+        VirtualMachine {
             program,
             call: Call {
                 frame: 0,
@@ -248,10 +597,97 @@ impl<'a> VirtualMachine<'a> {
             closure_ref_map,
             finished: false,
             heap,
-        };
-
-        while !vm.finished {
-            vm.step()
+            globals,
+            fuel: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            output: Box::new(io::stdout()),
+            print_separator: " ".to_string(),
+            print_terminator: "\n".to_string(),
         }
     }
+    /// Redirects `print` from stdout to `output`.
+    pub fn set_output(&mut self, output: impl Write + 'a) {
+        self.output = Box::new(output);
+    }
+    /// Sets what `print` writes between its arguments and after the last one.
+    pub fn set_print_format(&mut self, separator: &str, terminator: &str) {
+        self.print_separator = separator.to_string();
+        self.print_terminator = terminator.to_string();
+    }
+    /// Limits the number of instructions `resume` may execute, `None`
+    /// meaning no limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(remaining) = &mut self.fuel {
+            *remaining = remaining.saturating_add(fuel);
+        }
+    }
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle { flag: self.interrupt.clone() }
+    }
+    /// Sets the main module's global `args`, if the program has one, to a
+    /// list of these strings.
+    pub fn set_args(&mut self, args: &[&str]) {
+        if let Some(slot) = self.program.resolve_global("args") {
+            for arg in args {
+                let arg = Str::new(self.heap, arg);
+                let arg = self.heap.alloc_rust_value(arg);
+                self.push(Value::RustValue(arg))
+            }
+            let list = List::new(self.heap, args.len(), self.stack);
+            let list = Value::RustValue(self.heap.alloc_rust_value(list));
+            self.globals.set(slot, CompactValue::encode(list, self.heap))
+        }
+    }
+    /// Reads the main module's global `name`, or `None` if the program has
+    /// no such global.
+    pub fn global(&self, name: &str) -> Option<CompactValue> {
+        self.program.resolve_global(name).map(|slot| self.globals.get(slot))
+    }
+    /// Writes the main module's global `name`, returning whether the
+    /// program has one.
+    pub fn set_global(&mut self, name: &str, value: CompactValue) -> bool {
+        match self.program.resolve_global(name) {
+            Some(slot) => {
+                self.globals.set(slot, value);
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+    /// Runs until the entry function finishes. Running out of fuel or being
+    /// interrupted leaves the machine where it stopped, so calling `resume`
+    /// again carries on from there. After `exit` the machine is finished.
+    pub fn resume(&mut self) -> Result<(), RuntimeError> {
+        while !self.finished {
+            if self.interrupt.load(atomic::Ordering::Relaxed) {
+                self.interrupt.store(false, atomic::Ordering::Relaxed);
+                return Err(RuntimeError::Interrupted)
+            }
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    return Err(RuntimeError::OutOfFuel)
+                }
+                *fuel -= 1;
+            }
+            if let Err(err) = self.step() {
+                if let RuntimeError::Exit(_) = err {
+                    self.finished = true
+                }
+                return Err(err)
+            }
+        }
+        Ok(())
+    }
+    pub fn run(program: &Program, entry_func: usize, stack: &mut Vec<CompactValue>, heap: &mut Heap, globals: &mut GlobalValues) -> Result<(), RuntimeError> {
+        VirtualMachine::new(program, entry_func, stack, heap, globals).resume()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,65 @@
+use scripting::{compact_value::CompactValue, compiler::{Compiler, Program}, globals::GlobalValues, heap::Heap, vm::{RuntimeError, VirtualMachine}};
+
+/// Runs `source` and returns what it printed.
+fn run(source: &str) -> Result<String, RuntimeError> {
+    let mut program = Program::new();
+    Compiler::compile(source, None, &mut program).unwrap();
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(&program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    vm.resume()?;
+    drop(vm);
+    Ok(String::from_utf8(output).unwrap())
+}
+
+/// `%` takes the sign of the divisor, so `a == (a // b) * b + a % b` for
+/// every combination of signs, whether the operands are constants folded
+/// by the optimizer or values only known at runtime.
+#[test]
+fn floored_modulus() {
+    assert_eq!(run("print 7 % 2, (0 - 7) % 2, 7 % (0 - 2), (0 - 7) % (0 - 2), 6 % (0 - 3)").unwrap(), "1 1 -1 -1 0\n");
+    assert_eq!(run("print 7.5 % 2.0, (0.0 - 7.5) % 2.0, 7.5 % (0.0 - 2.0)").unwrap(), "1.5 0.5 -0.5\n");
+    let identity = "
+        var check = func(a, b) (a // b) * b + a % b == a
+        print check(7, 2), check(0 - 7, 2), check(7, 0 - 2), check(0 - 7, 0 - 2), check(0 - 7.5, 2.0)
+    ";
+    assert_eq!(run(identity).unwrap(), "true true true true true\n");
+    let min = "var min = func() 0 - 9223372036854775807 - 1 ";
+    assert_eq!(run(&format!("{} print min() % (0 - 1), 5 % (0 - 1)", min)).unwrap(), "0 0\n");
+    assert_eq!(run("print (0 - 9223372036854775807 - 1) % (0 - 1)").unwrap(), "0\n");
+}
+
+/// Integer results that do not fit in an `i64` are errors, whether the
+/// optimizer sees the operands or not, and so are literals too large for
+/// one.
+#[test]
+fn integer_overflow() {
+    let max = "var max = func() 9223372036854775807 ";
+    assert_eq!(run(&format!("{} print max() + 1", max)), Err(RuntimeError::IntegerOverflow));
+    assert_eq!(run(&format!("{} print max() * 2", max)), Err(RuntimeError::IntegerOverflow));
+    assert_eq!(run(&format!("{} print 0 - max() - 2", max)), Err(RuntimeError::IntegerOverflow));
+    assert_eq!(run(&format!("{} print (0 - max() - 1) // (0 - 1)", max)), Err(RuntimeError::IntegerOverflow));
+    assert_eq!(run(&format!("{} var i = max() i += 1", max)), Err(RuntimeError::IntegerOverflow));
+    assert_eq!(run("print 9223372036854775807 + 1"), Err(RuntimeError::IntegerOverflow));
+    assert_eq!(run(&format!("{} print max(), 0 - max() - 1, max() + 0.5", max)).unwrap(), "9223372036854775807 -9223372036854775808 9223372036854776000\n");
+
+    let err = Compiler::compile("print 9223372036854775808", None, &mut Program::new()).err().unwrap();
+    assert_eq!(err.errors[0].message, "integer literal is too large");
+}
+
+/// Every comparison with NaN is false except `!=`, and dividing by zero is
+/// an error rather than producing infinity or NaN.
+#[test]
+fn nan_and_zero_division() {
+    let nan = "var inf = func() 1e308 * 10.0 var nan = func() inf() - inf() ";
+    assert_eq!(run(&format!("{} print inf(), nan(), 0 - inf()", nan)).unwrap(), "inf NaN -inf\n");
+    assert_eq!(
+        run(&format!("{} var n = nan() print n == n, n != n, n < 1, n > 1, n <= n, n >= 1, 1 < n", nan)).unwrap(),
+        "false true false false false false false\n",
+    );
+    assert_eq!(run("var n = 1e308 * 10.0 - 1e308 * 10.0 print n == n, n < 1").unwrap(), "false false\n");
+    for source in ["print 1 / 0", "print 1.0 / 0.0", "print 1 // 0", "print 1 % 0", "print 1.5 % 0.0", "var z = func() 0 print 1 / z()"] {
+        assert_eq!(run(source), Err(RuntimeError::ZeroDivision), "{}", source);
+    }
+}
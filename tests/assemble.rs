@@ -0,0 +1,382 @@
+use scripting::{assemble::assemble, compact_value::CompactValue, compiler::{Compiler, Program}, func::DispProgram, globals::GlobalValues, heap::Heap, module::MemoryLoader, verify, vm::{RuntimeError, VirtualMachine}};
+
+/// The modules generated programs can import, with the binding a plain
+/// `import` gives each and the number and one parameter function it
+/// exports.
+const MODULES: &[(&str, &str, &str, &str, &str)] = &[
+    ("lib", "lib", "count", "scale", "export var count = 2\nexport var scale = func(x) x * count\nvar hidden = 1"),
+    ("util/math", "math", "base", "twice", "import count from \"lib\"\nexport var base = count + 1\nexport var twice = func(x) x + x"),
+];
+
+/// Generates random programs from a fixed seed, using every kind of
+/// statement and expression with names that are always defined. Numbers
+/// are kept apart from other values so that most programs run to the end;
+/// the rest may overflow or divide by zero, which is fine as long as the
+/// assembled copy fails the same way.
+struct Generator {
+    state: u64,
+    /// The variables in scope, with what they hold.
+    scope: Vec<(String, Kind)>,
+    names: usize,
+    depth: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Other,
+    /// A module bound by a plain `import`.
+    Module,
+    /// A function taking this many parameters.
+    Func(usize),
+}
+
+impl Generator {
+    fn new(seed: u64) -> Generator {
+        Generator { state: seed, scope: vec![], names: 0, depth: 0 }
+    }
+    /// A number below `n`, from xorshift64.
+    fn below(&mut self, n: usize) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % n as u64) as usize
+    }
+    fn name(&mut self) -> String {
+        self.names += 1;
+        format!("v{}", self.names)
+    }
+    fn define(&mut self, kind: Kind) -> String {
+        let name = self.name();
+        self.scope.push((name.clone(), kind));
+        name
+    }
+    /// A variable in scope holding `kind`, if there is one.
+    fn var(&mut self, kind: Kind) -> Option<String> {
+        let vars: Vec<_> = self.scope.iter().filter(|(_, var)| *var == kind).map(|(name, _)| name.clone()).collect();
+        match vars.len() {
+            0 => None,
+            len => Some(vars[self.below(len)].clone()),
+        }
+    }
+    fn program(&mut self) -> String {
+        let mut source = String::new();
+        for _ in 0..1 + self.below(8) {
+            source += &self.stmt();
+            source.push('\n');
+        }
+        source
+    }
+    /// The statements of a block, without the braces.
+    fn stmts(&mut self) -> String {
+        let scope_len = self.scope.len();
+        let stmts: Vec<_> = (0..self.below(4)).map(|_| self.stmt()).collect();
+        self.scope.truncate(scope_len);
+        stmts.join(" ")
+    }
+    fn stmt(&mut self) -> String {
+        self.depth += 1;
+        let nested = self.depth < 4;
+        let stmt = match self.below(if nested { 9 } else { 4 }) {
+            0 => {
+                let value = self.number();
+                format!("var {} = {}", self.define(Kind::Number), value)
+            }
+            1 => {
+                let value = self.value();
+                format!("var {} = {}", self.define(Kind::Other), value)
+            }
+            2 => match self.var(Kind::Number) {
+                Some(name) => {
+                    let op = ["=", "+=", "-=", "*=", "//="][self.below(5)];
+                    format!("{} {} {}", name, op, self.number())
+                }
+                None => format!("print {}", self.number()),
+            },
+            3 => {
+                let values: Vec<_> = (0..1 + self.below(3)).map(|_| self.value()).collect();
+                format!("print {}", values.join(", "))
+            }
+            4 | 5 => {
+                let params: Vec<_> = (0..self.below(4)).map(|_| self.name()).collect();
+                let scope_len = self.scope.len();
+                self.scope.extend(params.iter().map(|param| (param.clone(), Kind::Number)));
+                let body = match self.below(3) {
+                    0 => self.number(),
+                    _ => format!("{{ {} return {} }}", self.stmts(), self.number()),
+                };
+                self.scope.truncate(scope_len);
+                format!("var {} = func({}) {}", self.define(Kind::Func(params.len())), params.join(", "), body)
+            }
+            6 => format!("if {} {{ {} }} else {{ {} }}", self.condition(), self.stmts(), self.stmts()),
+            7 => {
+                let (path, binding, number, func, _) = MODULES[self.below(MODULES.len())];
+                if self.below(2) == 0 {
+                    self.scope.push((binding.to_string(), Kind::Module));
+                    format!("import \"{}\"", path)
+                } else {
+                    self.scope.extend([(number.to_string(), Kind::Number), (func.to_string(), Kind::Func(1))]);
+                    format!("import {}, {} from \"{}\"", number, func, path)
+                }
+            }
+            _ => {
+                let bound = self.below(4);
+                let (end, counter) = (self.define(Kind::Number), self.define(Kind::Number));
+                format!("var {0} = {1} var {2} = 0 while {2} < {0} {{ {2} += 1 {3} }}", end, bound, counter, self.stmts())
+            }
+        };
+        self.depth -= 1;
+        stmt
+    }
+    fn condition(&mut self) -> String {
+        let op = ["<", ">", "<=", ">=", "==", "!="][self.below(6)];
+        format!("{} {} {}", self.number(), op, self.number())
+    }
+    /// An expression that evaluates to an int or a float.
+    fn number(&mut self) -> String {
+        self.depth += 1;
+        let nested = self.depth < 6;
+        let number = match self.below(if nested { 9 } else { 5 }) {
+            0 => self.below(3).to_string(),
+            1 => (self.below(1000) * 1000).to_string(),
+            2 => format!("{}.5", self.below(10)),
+            3 => self.var(Kind::Number).unwrap_or_else(|| "7".to_string()),
+            4 => match self.var(Kind::Module) {
+                Some(binding) => {
+                    let (_, _, number, _, _) = MODULES.iter().find(|module| module.1 == binding).unwrap();
+                    format!("{}.{}", binding, number)
+                }
+                None => self.var(Kind::Number).unwrap_or_else(|| "7".to_string()),
+            },
+            5 | 6 => {
+                let op = ["+", "-", "*", "/", "//", "%"][self.below(6)];
+                format!("({} {} {})", self.number(), op, self.number())
+            }
+            7 => {
+                let values: Vec<_> = (0..self.below(3)).map(|_| self.value()).collect();
+                format!("list({}).len", values.join(", "))
+            }
+            _ => {
+                let funcs: Vec<_> = (0..4).filter_map(|params| Some((self.var(Kind::Func(params))?, params))).collect();
+                match funcs.len() {
+                    0 => self.number(),
+                    len => {
+                        let (name, params) = funcs[self.below(len)].clone();
+                        let args: Vec<_> = (0..params).map(|_| self.number()).collect();
+                        format!("{}({})", name, args.join(", "))
+                    }
+                }
+            }
+        };
+        self.depth -= 1;
+        number
+    }
+    /// An expression of any type, including numbers.
+    fn value(&mut self) -> String {
+        match self.below(6) {
+            0 => ["true", "false", "none", "\"s\"", "\"ünï\""][self.below(5)].to_string(),
+            1 => self.condition(),
+            2 => format!("type({})", self.value()),
+            3 => self.var(Kind::Other).unwrap_or_else(|| "type".to_string()),
+            _ => self.number(),
+        }
+    }
+}
+
+/// Runs a verified program with a fuel limit, so that generated loops
+/// cannot hang the test, and returns its output and how it stopped.
+fn run(program: &Program) -> (String, Result<(), RuntimeError>) {
+    verify::verify(program).unwrap();
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    vm.set_fuel(Some(100_000));
+    let result = vm.resume();
+    drop(vm);
+    (String::from_utf8(output).unwrap(), result)
+}
+
+/// A call with more than 256 distinct constants, to a function with more
+/// than 256 parameters whose loop body is too long for an 8 bit jump.
+fn wide_source() -> String {
+    let params: Vec<_> = (0..300).map(|i| format!("p{}", i)).collect();
+    let args: Vec<_> = (0..300).map(|i| (i * 7).to_string()).collect();
+    format!(
+        "var f = func({}) {{ var i = 0 while i < 3 {{ i += 1 print {} + i }} return i }} print f({})",
+        params.join(", "), params.join(" + "), args.join(", "),
+    )
+}
+
+/// Every compiled program's listing assembles back into a program with the
+/// same listing, which still verifies and runs the same way.
+#[test]
+fn round_trip() {
+    let sources = (0..500).map(|seed| Generator::new(0x9e37_79b9_7f4a_7c15 ^ seed).program()).chain([wide_source()]);
+    for source in sources {
+        let mut loader = MemoryLoader::new();
+        for (path, _, _, _, source) in MODULES {
+            loader.insert(path, source);
+        }
+        let mut program = Program::with_loader(loader);
+        Compiler::compile(&source, None, &mut program).unwrap_or_else(|err| panic!("{:?}\n{}", err.errors, source));
+        let listing = DispProgram::new(&program).to_string();
+        let assembled = assemble(&listing, None).unwrap_or_else(|err| panic!("{:?}\n{}", err.errors, listing));
+        assert_eq!(DispProgram::new(&assembled).to_string(), listing, "{}", source);
+        assert_eq!(run(&assembled), run(&program), "{}", source);
+    }
+}
+
+/// Functions may be listed in any order and are printed nested under the
+/// instruction that creates them, with the names and lines they were
+/// given.
+#[test]
+fn hand_written() {
+    let program = assemble("
+        func1(n) captures(stack 1 total)
+        line 3
+        var 2 i
+            PushConst 0
+        L0:
+            JumpIfNotLessLocals8 2 1 L1
+            IncLocal 2
+            Jump8 L0
+        L1:
+        line 4
+            PushLoad 2
+            PushClosureLoad 0
+            Add
+            PopStore 0
+            Return
+        end
+        func0()
+        line 1
+        var 1 total
+            PushConst 300
+        line 2
+            PushNone
+            PushConst 5
+            PushConst func1
+            Call 1
+            PopPrint 1
+            Finish
+        end
+    ", None).unwrap();
+    let listing = "\
+func0()
+line 1
+var 1 total
+    0 : PushConst 300
+line 2
+    2 : PushNone
+    3 : PushConst 5
+    5 : PushConst func1
+        func1(n) captures(stack 1 total)
+        line 3
+        var 2 i
+            0 : PushConst 0
+        L0:
+            2 : JumpIfNotLessLocals8 2 1 L1       ; not i < n ↓ 10
+            6 : IncLocal 2                        ; i
+            8 : Jump8 L0                          ; ↑ 2
+        line 4
+        L1:
+           10 : PushLoad 2                        ; i
+           12 : PushClosureLoad 0                 ; total
+           14 : Add
+           15 : PopStore 0                        ; return
+           17 : Return
+        end
+    7 : Call 1
+    9 : PopPrint 1
+   11 : Finish
+end
+";
+    assert_eq!(DispProgram::new(&program).to_string(), listing);
+    assert_eq!(DispProgram::new(&assemble(listing, None).unwrap()).to_string(), listing);
+    assert_eq!(run(&program), ("305\n".to_string(), Ok(())));
+}
+
+/// Modules are listed with their path, entry function, cache slot and
+/// exports, and global slots name the module they belong to, so programs
+/// that import assemble back into working ones.
+#[test]
+fn modules() {
+    let listing = "\
+module 1 \"lib\" entry func1 slot 2 exports(x)
+global 1 lib
+global 3 x module1
+
+func0()
+    0 : ImportModule module1
+    5 : PopGlobalStore 1                  ; lib
+   10 : PushGlobalLoad 1                  ; lib
+   15 : PushPropLoad x
+   17 : PopPrint 1
+   19 : Finish
+end
+
+func1()
+    0 : PushConst 5
+    2 : PopGlobalStore 3                  ; x
+    7 : Return
+end
+";
+    let program = assemble(listing, None).unwrap();
+    assert_eq!(DispProgram::new(&program).to_string(), listing);
+    assert_eq!(program.globals.resolve(1, program.symbols.get("x").unwrap()), Some(3));
+    assert_eq!(run(&program), ("5\n".to_string(), Ok(())));
+}
+
+/// Offsets, comments and blank lines are optional, operands too large for
+/// a byte get a `Wide` prefix, and strings take the escapes they are
+/// printed with.
+#[test]
+fn loose_listing() {
+    let program = assemble("
+        ; never run
+        func0()
+            PushConst 3
+        top:
+            PushLoad 300
+            PushConst \"a;b\" ; a string
+            PushConst \"tab\\t quote\\\" \\u{7f}\"
+            Jump32 top
+        end
+    ", None).unwrap();
+    assert_eq!(DispProgram::new(&program).to_string(), "\
+func0()
+    0 : PushConst 3
+L0:
+    2 : PushLoad 300
+    6 : PushConst \"a;b\"
+    8 : PushConst \"tab\\t quote\\\" \\u{7f}\"
+   10 : Jump32 L0                         ; ↑ 2
+end
+");
+}
+
+#[test]
+fn errors() {
+    let errors = |listing: &str| assemble(listing, None).err().unwrap().errors.into_iter().map(|err| err.message).collect::<Vec<_>>();
+    assert_eq!(errors("Return\nfunc1()\nReturn\nend"), ["instruction outside a function", "`func0` is never defined"]);
+    assert_eq!(errors("func0()\nJump8 nowhere\nend\nend"), ["undefined label `nowhere`", "`end` outside a function"]);
+    assert_eq!(errors("func0()\na:\na:\nFrobnicate\nfunc0()\nend"), [
+        "label `a` is defined twice",
+        "unknown instruction `Frobnicate`",
+        "`func0` is defined twice",
+        "`func0` has no `end`",
+    ]);
+    assert_eq!(errors("func0() captures(stack 1 a, stack 2)\nend"), ["either every captured variable is named or none is"]);
+    assert_eq!(errors("func0()\nPushLoad 70000\nPushBuiltin nope\nPushConst \"open\nend"), [
+        "expected a number from 0 to 65535",
+        "unknown builtin `nope`",
+        "unterminated string",
+    ]);
+    assert_eq!(errors("func0()\nPopPropStore 1\nend"), ["unknown instruction `PopPropStore`"]);
+    assert_eq!(errors("module 2 \"a\" entry func0 slot 1\nglobal 1 x module1\nfunc0()\nImportModule module3\nFinish\nend"), [
+        "expected module 1, as modules are numbered in order",
+        "unknown module `module1`",
+        "unknown module `module3`",
+    ]);
+}
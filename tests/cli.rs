@@ -0,0 +1,40 @@
+use std::process::{Command, Output};
+
+fn scripting(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_scripting")).args(args).output().unwrap()
+}
+
+/// Each kind of failure exits with the status the usage text documents,
+/// and a runtime error is reported rather than crashing.
+#[test]
+fn exit_statuses() {
+    let status = |args: &[&str]| scripting(args).status.code();
+    assert_eq!(status(&["-e", "print 1"]), Some(0));
+    assert_eq!(status(&["--nope"]), Some(64));
+    assert_eq!(status(&["-e", "print )"]), Some(65));
+    assert_eq!(status(&["run", "/no/such/file"]), Some(66));
+    for source in ["print \"a\" + 1", "if 1 { print 2 }", "var f = func(a) a f()", "print list().size", "print 1 // 0"] {
+        let output = scripting(&["-e", source]);
+        assert_eq!(output.status.code(), Some(70), "{}", source);
+        assert!(String::from_utf8(output.stderr).unwrap().starts_with("runtime error: "), "{}", source);
+    }
+}
+
+/// Scripts get the arguments after the file or code as `args`, read the
+/// environment with `env`, and choose their exit status with `exit`.
+#[test]
+fn args_env_and_exit() {
+    let output = Command::new(env!("CARGO_BIN_EXE_scripting"))
+        .args(["-e", "print args.len, env(\"SCRIPTING_TEST\") exit(args.len + 40)", "x", "y z"])
+        .env("SCRIPTING_TEST", "set")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(42));
+    assert_eq!(output.stdout, b"2 set\n");
+    assert!(output.stderr.is_empty());
+
+    assert_eq!(scripting(&["-e", "exit(0) print 1"]).status.code(), Some(0));
+    assert_eq!(scripting(&["-e", "exit(256)"]).status.code(), Some(70));
+    assert_eq!(scripting(&["-e", "exit(none)"]).status.code(), Some(70));
+    assert_eq!(scripting(&["-e", "print env(1)"]).status.code(), Some(70));
+}
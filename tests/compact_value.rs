@@ -0,0 +1,105 @@
+use std::{fmt, mem::size_of, ptr};
+
+use scripting::{builtins::BUILTINS, compact_value::CompactValue, heap::Heap, string::Str, symbols::Symbol, value::{Closure, RustValue, Value}, vm::{RuntimeError, VirtualMachine}};
+
+/// A rust value aligned more strictly than its header, so it sits further
+/// from the start of its allocation than a `Str` does.
+#[repr(align(32))]
+#[derive(Debug)]
+struct Aligned(u8);
+
+impl RustValue for Aligned {
+    fn type_name(&self) -> &'static str {
+        "aligned"
+    }
+    fn get_property(&mut self, _symbol: Symbol, _vm: &mut VirtualMachine) -> Result<Value, RuntimeError> {
+        Ok(Value::None)
+    }
+}
+
+impl fmt::Display for Aligned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[test]
+fn size() {
+    assert_eq!(size_of::<CompactValue>(), 8);
+    assert_eq!(size_of::<Value>(), 24);
+}
+
+/// Ints up to 48 bits are stored inline and larger ones are boxed, and
+/// both decode to the int they were made from.
+#[test]
+fn ints() {
+    let mut heap = Heap::new();
+    let inline = [0, 1, -1, (1 << 47) - 1, -(1 << 47)];
+    let boxed = [1 << 47, -(1 << 47) - 1, i64::MAX, i64::MIN];
+    for (int, is_inline) in inline.iter().map(|int| (*int, true)).chain(boxed.iter().map(|int| (*int, false))) {
+        let value = CompactValue::from_int(int, &mut heap);
+        assert_eq!(value.as_int(), if is_inline { Some(int) } else { None }, "{}", int);
+        assert!(value.as_float().is_none());
+        assert!(matches!(value.decode(), Value::Int(decoded) if decoded == int), "{}", int);
+        assert!(matches!(CompactValue::encode(value.decode(), &mut heap).decode(), Value::Int(decoded) if decoded == int));
+    }
+}
+
+/// Floats keep their exact bits, including the sign of zero, and every NaN,
+/// whatever its sign and payload, stays a float rather than decoding as a
+/// tagged value.
+#[test]
+fn floats() {
+    for float in [0.0, -0.0, 1.5, f64::MIN_POSITIVE, f64::MAX, f64::INFINITY, f64::NEG_INFINITY] {
+        let value = CompactValue::from_float(float);
+        assert_eq!(value.as_float().map(f64::to_bits), Some(float.to_bits()));
+        assert!(matches!(value.decode(), Value::Float(decoded) if decoded.to_bits() == float.to_bits()));
+        assert!(value.as_int().is_none());
+    }
+    for bits in [f64::NAN.to_bits(), 0x7ffc_0000_0000_0000, 0xfffe_0000_0000_0001, 0x7ff0_0000_0000_0001] {
+        let value = CompactValue::from_float(f64::from_bits(bits));
+        assert!(value.as_float().unwrap().is_nan(), "{:x}", bits);
+        assert!(matches!(value.decode(), Value::Float(decoded) if decoded.is_nan()));
+    }
+}
+
+#[test]
+fn other_values() {
+    let mut heap = Heap::new();
+    assert!(matches!(CompactValue::NONE.decode(), Value::None));
+    assert!(matches!(CompactValue::from_bool(true).decode(), Value::Bool(true)));
+    assert!(matches!(CompactValue::from_bool(false).decode(), Value::Bool(false)));
+    assert!(CompactValue::from_bool(true) == CompactValue::TRUE && CompactValue::from_bool(false) == CompactValue::FALSE);
+
+    let closure = heap.alloc(Closure { func_id: 3, closure_values: vec![] });
+    match CompactValue::encode(Value::Closure(closure), &mut heap).decode() {
+        Value::Closure(decoded) => assert!(decoded.as_ptr() == closure.as_ptr() && decoded.func_id == 3),
+        other => panic!("{:?}", other),
+    }
+
+    let native = &BUILTINS[1];
+    match CompactValue::encode(Value::NativeFunc(native), &mut heap).decode() {
+        Value::NativeFunc(decoded) => assert!(ptr::eq(decoded, native)),
+        other => panic!("{:?}", other),
+    }
+}
+
+/// Rust values are fat pointers, which are rebuilt from the header stored
+/// in front of each value with their vtable intact.
+#[test]
+fn rust_values() {
+    let mut heap = Heap::new();
+    let string = Str::new(&mut heap, "text");
+    let string = heap.alloc_rust_value(string);
+    let aligned = heap.alloc_rust_value(Aligned(7));
+    for (value, type_name, display) in [(string, "string", "text"), (aligned, "aligned", "7")] {
+        match CompactValue::encode(Value::RustValue(value), &mut heap).decode() {
+            Value::RustValue(decoded) => {
+                assert!(ptr::addr_eq(decoded.as_ptr(), value.as_ptr()));
+                assert_eq!((decoded.type_name(), decoded.to_string()), (type_name, display.to_string()));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+    assert_eq!(aligned.as_ptr() as *const u8 as usize % 32, 0);
+}
@@ -0,0 +1,129 @@
+use scripting::{compiler::{Compiler, Program}, diagnostic::{Diagnostic, Diagnostics, DispDiagnostics, Span}};
+
+/// The messages of the errors compiling `source` reports.
+fn errors(source: &str) -> Vec<String> {
+    match Compiler::compile(source, None, &mut Program::new()) {
+        Ok(()) => vec![],
+        Err(err) => err.errors.into_iter().map(|err| err.message).collect(),
+    }
+}
+
+/// Names are checked before the optimizer removes code that can never run.
+#[test]
+fn dead_code_is_checked() {
+    assert_eq!(errors("if false { print a }"), ["undefined variable `a`"]);
+    assert_eq!(errors("while false { b = 1 }"), ["undefined variable `b`"]);
+    assert_eq!(errors("var f = func() { return 1 print c } if 1 < 0 { d() } else { print f() }"), [
+        "undefined variable `c`",
+        "undefined variable `d`",
+    ]);
+    assert_eq!(errors("var f = func(x) { if false { var y = x } return x } print f(type(none))"), Vec::<String>::new());
+}
+
+/// Only functions can return; the program's entry function has no caller.
+#[test]
+fn return_outside_function() {
+    assert_eq!(errors("print 1 if false { return 2 }"), ["`return` outside a function"]);
+    assert_eq!(errors("var f = func() { if true { return 1 } return 2 }"), Vec::<String>::new());
+}
+
+/// A keyword or missing name after `var` is one error, and the statements
+/// after it are still parsed.
+#[test]
+fn bad_var_name() {
+    assert_eq!(errors("var while = 1 print )"), [
+        "expected identifier after `var`, found `while`",
+        "expected expression, found `)`",
+    ]);
+    assert_eq!(errors("var if = func() { return 1 } var = 2 var 3 print )"), [
+        "expected identifier after `var`, found `if`",
+        "expected identifier after `var`, found `=`",
+        "expected identifier after `var`, found integer",
+        "expected expression, found `)`",
+    ]);
+}
+
+/// Bad tokens become errors the parser recovers from at the next
+/// statement, so every broken statement in a file is reported at once.
+#[test]
+fn several_errors() {
+    let source = "var a = 0x\nvar b = 1__0 + 2\nprint €\nvar for = 2\nprint a, b )\nprint \"open\nprint 1 /* never closed";
+    let err = Compiler::compile(source, None, &mut Program::new()).err().unwrap();
+    let spans: Vec<(&str, &str)> = err.errors.iter().map(|err| (err.message.as_str(), &source[err.span.start..err.span.end])).collect();
+    assert_eq!(spans, [
+        ("expected digits in numeric literal", "0x"),
+        ("invalid digit in numeric literal", "1__0"),
+        ("illegal character '€'", "€"),
+        ("`for` is a reserved word", "for"),
+        ("expected statement, found `)`", ")"),
+        ("unterminated string literal", "\"open\nprint 1 /* never closed"),
+    ]);
+    assert_eq!(errors("print 1\nprint 2 /* never closed"), ["unterminated block comment"]);
+}
+
+/// Each error shows where it is, the lines its spans cover with the spans
+/// underlined and the lines between them elided, and any labels and notes.
+/// Colors are only added when asked for.
+#[test]
+fn rendering() {
+    let source = "var f = func() {\n    print (1 +\n        2\n\nprint 3";
+    let err = Compiler::compile(source, Some("main.scr"), &mut Program::new()).err().unwrap();
+    assert_eq!(err.to_string(), "\
+error: expected `)` to close `(`, found `print`
+ --> main.scr:5:1
+  |
+2 |     print (1 +
+  |           - unclosed `(`
+...
+5 | print 3
+  | ^^^^^
+
+error: expected `}` to close block, found end of input
+ --> main.scr:5:8
+  |
+1 | var f = func() {
+  |                - block starts here
+...
+5 | print 3
+  |        ^");
+    let colored = DispDiagnostics::new(&err, true).to_string();
+    assert!(colored.starts_with("\x1b[1;31merror\x1b[0m\x1b[1m: expected `)` to close `(`, found `print`\x1b[0m\n"));
+    assert!(colored.contains("\x1b[1;31m^^^^^\x1b[0m"));
+
+    // Spans over several lines are underlined on each, and columns count
+    // characters rather than bytes.
+    let source = "var ü = \"a\nb\" + 1\nprint ü";
+    let err = Diagnostics {
+        source,
+        path: None,
+        errors: vec![
+            Diagnostic::new("can't \"add\"\there", Span::new(9, 14)).with_label(Span::new(17, 18), "int").with_note("strings do not add"),
+            Diagnostic::new("at the end", Span::new(source.len(), source.len())),
+        ],
+    };
+    assert_eq!(err.to_string(), "\
+error: can't \"add\"\there
+ --> <input>:1:9
+  |
+1 | var ü = \"a
+  |         ^^
+2 | b\" + 1
+  | ^^
+  |      - int
+  = note: strings do not add
+
+error: at the end
+ --> <input>:3:8
+  |
+3 | print ü
+  |        ^");
+    assert_eq!(err.to_json(), concat!(
+        r#"[{"message":"can't \"add\"\there","path":null,"#,
+        r#""span":{"start":9,"end":14,"start_line":1,"start_column":9,"end_line":2,"end_column":3},"#,
+        r#""labels":[{"message":"int","span":{"start":17,"end":18,"start_line":2,"start_column":6,"end_line":2,"end_column":7}}],"#,
+        r#""notes":["strings do not add"],"cause":null},"#,
+        r#"{"message":"at the end","path":null,"#,
+        r#""span":{"start":27,"end":27,"start_line":3,"start_column":8,"end_line":3,"end_column":8},"#,
+        r#""labels":[],"notes":[],"cause":null}]"#,
+    ));
+}
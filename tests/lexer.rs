@@ -0,0 +1,102 @@
+use scripting::{compiler::{Compiler, Program}, lexer::{LexError, Lexer}, token::TokenKind};
+
+/// The kinds of every token in `source`, without the final `End`.
+fn tokens(source: &str) -> Vec<TokenKind<'_>> {
+    let mut lexer = Lexer::new(source);
+    let mut tokens = vec![];
+    loop {
+        match lexer.next_token().kind {
+            TokenKind::End => return tokens,
+            kind => tokens.push(kind),
+        }
+    }
+}
+
+/// Ints can be written in hex, binary and octal with either case of
+/// prefix, decimals can have a fraction and an exponent, and any of them
+/// can separate digits with single underscores.
+#[test]
+fn numbers() {
+    assert_eq!(tokens("0x1F 0XfF 0b1010 0B1_0 0o17 0O7_7 1_000 0x7fff_ffff_ffff_ffff 9223372036854775807"), [
+        TokenKind::Int(31), TokenKind::Int(255), TokenKind::Int(10), TokenKind::Int(2), TokenKind::Int(15),
+        TokenKind::Int(63), TokenKind::Int(1000), TokenKind::Int(i64::MAX), TokenKind::Int(i64::MAX),
+    ]);
+    assert_eq!(tokens("1e3 2.5E-2 1_0.5e+1 .5 3."), [
+        TokenKind::Float(1000.0), TokenKind::Float(0.025), TokenKind::Float(105.0), TokenKind::Float(0.5), TokenKind::Float(3.0),
+    ]);
+}
+
+/// A malformed number is a single error token, after which lexing carries
+/// on as normal.
+#[test]
+fn bad_numbers() {
+    for (source, error) in [
+        ("0x", LexError::MissingDigits),
+        ("0b", LexError::MissingDigits),
+        ("0o", LexError::MissingDigits),
+        ("1_", LexError::MissingDigits),
+        ("1e", LexError::MissingDigits),
+        ("1e+", LexError::MissingDigits),
+        ("1.e", LexError::MissingDigits),
+        ("1__0", LexError::InvalidDigit),
+        ("0x_", LexError::InvalidDigit),
+        ("1._5", LexError::InvalidDigit),
+        ("0b2", LexError::InvalidDigit),
+        ("0o8", LexError::InvalidDigit),
+        ("0xg", LexError::InvalidDigit),
+        ("12ab", LexError::InvalidDigit),
+        ("0x8000000000000000", LexError::IntegerTooLarge),
+        ("9223372036854775808", LexError::IntegerTooLarge),
+    ] {
+        assert_eq!(tokens(&format!("{} + 2", source)), [TokenKind::Error(error), TokenKind::Plus, TokenKind::Int(2)], "{}", source);
+    }
+    assert_eq!(LexError::MissingDigits.to_string(), "expected digits in numeric literal");
+    assert_eq!(LexError::InvalidDigit.to_string(), "invalid digit in numeric literal");
+}
+
+/// `#` comments run to the end of the line, `/* */` comments nest, and
+/// `##` comments are kept as doc tokens.
+#[test]
+fn comments() {
+    assert_eq!(tokens("1 # one\n/* a /* nested */ # still a comment\n */ 2 /**/ 3"), [TokenKind::Int(1), TokenKind::Int(2), TokenKind::Int(3)]);
+    assert_eq!(tokens("## Docs for x.\n##   trimmed  \nvar x # not doc"), [
+        TokenKind::DocComment("Docs for x."), TokenKind::DocComment("trimmed"), TokenKind::Var, TokenKind::Ident("x"),
+    ]);
+    assert_eq!(tokens("1 /* /* */"), [TokenKind::Int(1), TokenKind::Error(LexError::UnterminatedComment)]);
+    assert_eq!(tokens("1 /* */ */"), [TokenKind::Int(1), TokenKind::Multiply, TokenKind::Divide]);
+}
+
+/// The doc comments directly before a global's `var` become its doc, and
+/// ones anywhere else are ignored rather than being syntax errors.
+#[test]
+fn doc_comments() {
+    let mut program = Program::new();
+    let source = "## How many.\n## At most 3.\nvar count = 1\n## Not for a var.\nprint count\nvar other = ## inside\n 2\n## Exported.\nexport var f = func() {\n ## local\n var x = 1 return x }";
+    Compiler::compile(source, None, &mut program).unwrap();
+    let doc = |name| program.globals.doc(program.resolve_global(name).unwrap());
+    assert_eq!(doc("count"), Some("How many.\nAt most 3."));
+    assert_eq!(doc("other"), None);
+    assert_eq!(doc("f"), Some("Exported."));
+}
+
+/// Identifiers follow Unicode's XID rules and may also start with `_` or
+/// `$` and contain `$`. Words kept for future keywords are errors.
+#[test]
+fn identifiers() {
+    assert_eq!(tokens("_ _x $ $el a$b x1 ünï 变量 ℮ x·y"), [
+        TokenKind::Ident("_"), TokenKind::Ident("_x"), TokenKind::Ident("$"), TokenKind::Ident("$el"), TokenKind::Ident("a$b"),
+        TokenKind::Ident("x1"), TokenKind::Ident("ünï"), TokenKind::Ident("变量"), TokenKind::Ident("℮"), TokenKind::Ident("x·y"),
+    ]);
+    assert_eq!(tokens("a-b 1a €"), [
+        TokenKind::Ident("a"), TokenKind::Minus, TokenKind::Ident("b"),
+        TokenKind::Error(LexError::InvalidDigit), TokenKind::Error(LexError::IllegalChar('€')),
+    ]);
+    assert_eq!(tokens("for in forest"), [
+        TokenKind::Error(LexError::ReservedWord("for")), TokenKind::Error(LexError::ReservedWord("in")), TokenKind::Ident("forest"),
+    ]);
+    assert_eq!(LexError::ReservedWord("let").to_string(), "`let` is a reserved word");
+
+    let mut program = Program::new();
+    Compiler::compile("var $total = 1 var _n = 2 var ünï = $total + _n print ünï", None, &mut program).unwrap();
+    assert!(program.resolve_global("ünï").is_some());
+}
@@ -0,0 +1,113 @@
+use std::{env, fs, process};
+
+use scripting::{compact_value::CompactValue, compiler::{Compiler, Program}, globals::GlobalValues, heap::Heap, module::{FileLoader, MemoryLoader, ModuleLoader}, verify::{verify, VerifyError}, vm::{RuntimeError, VirtualMachine}};
+
+fn loader(modules: &[(&str, &str)]) -> MemoryLoader {
+    let mut loader = MemoryLoader::new();
+    for (path, source) in modules {
+        loader.insert(path, source);
+    }
+    loader
+}
+
+/// A module importing the entry file back is a cycle, reported at that
+/// import, and the errors of a module that failed keep their own source.
+#[test]
+fn import_cycle_through_entry_file() {
+    let mut program = Program::with_loader(loader(&[("a", "import \"b\""), ("b", "export var x = 1\nimport \"a\"")]));
+    let err = Compiler::compile("import \"b\"", Some("a"), &mut program).err().unwrap();
+    assert_eq!(err.errors.len(), 1);
+    assert_eq!(err.errors[0].message, "error in module \"b\"");
+    let cause = err.errors[0].cause.as_ref().unwrap();
+    assert_eq!((cause.path.as_str(), cause.source.as_str()), ("b", "export var x = 1\nimport \"a\""));
+    assert_eq!(cause.errors.len(), 1);
+    assert_eq!(cause.errors[0].message, "import cycle through module \"a\"");
+    assert_eq!(&cause.source[cause.errors[0].span.start..cause.errors[0].span.end], "import \"a\"");
+    assert!(err.to_json().contains("\"cause\":[{\"message\":\"import cycle through module \\\"a\\\"\",\"path\":\"b\""));
+}
+
+/// Runs a compiled program and returns what it printed before it finished
+/// or failed.
+fn run(program: &Program) -> (Vec<u8>, Result<(), RuntimeError>) {
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    let result = vm.resume();
+    drop(vm);
+    (output, result)
+}
+
+/// Reading a variable a module does not export fails at run time, and so
+/// does reading an export with no variable behind it, which the verifier
+/// rejects up front.
+#[test]
+fn hidden_member() {
+    let mut program = Program::with_loader(loader(&[("lib", "export var shown = 1\nvar hidden = 2")]));
+    Compiler::compile("import \"lib\"\nprint lib.shown\nprint lib.hidden", None, &mut program).unwrap();
+    assert_eq!(run(&program), (b"1\n".to_vec(), Err(RuntimeError::NoProperty("module", "hidden".to_string()))));
+
+    let mut program = Program::with_loader(loader(&[("lib", "export var shown = 1")]));
+    Compiler::compile("import \"lib\"\nprint lib.shown\nprint lib.count", None, &mut program).unwrap();
+    let count = program.symbols.add("count");
+    program.modules[1].exports.push(count);
+    assert_eq!(verify(&program), Err(VerifyError::Module(1)));
+    assert_eq!(run(&program), (b"1\n".to_vec(), Err(RuntimeError::NoProperty("module", "count".to_string()))));
+}
+
+/// A module's body runs the first time it is imported, however many
+/// modules import it and in whichever form.
+#[test]
+fn module_runs_once() {
+    let mut program = Program::with_loader(loader(&[
+        ("lib", "print \"lib\"\nexport var count = 1\nexport var bump = func() { count += 1 return count }"),
+        ("user", "import \"lib\"\nexport var before = lib.count\nvar bump = lib.bump\nbump()"),
+    ]));
+    Compiler::compile("import \"lib\"\nimport \"user\"\nimport \"lib\"\nimport count from \"lib\"\nvar bump = lib.bump\nprint bump(), count, user.before", None, &mut program).unwrap();
+    assert_eq!(program.modules.len(), 3);
+    assert_eq!(run(&program), (b"lib\n3 2 1\n".to_vec(), Ok(())));
+}
+
+/// `import a, b from` binds copies of the named exports instead of the
+/// module, and names the module does not export are compile errors.
+#[test]
+fn import_names() {
+    let modules = [("util/math", "export var base = 10\nexport var twice = func(x) x + x\nvar hidden = 1")];
+    let mut program = Program::with_loader(loader(&modules));
+    Compiler::compile("import base, twice from \"util/math\"\nbase += 1\nprint twice(base)", None, &mut program).unwrap();
+    assert_eq!(run(&program), (b"22\n".to_vec(), Ok(())));
+    assert_eq!(program.modules[1].path, "util/math");
+
+    let mut program = Program::with_loader(loader(&modules));
+    let err = Compiler::compile("import base, hidden from \"util/math\"", None, &mut program).err().unwrap();
+    assert_eq!(err.errors.iter().map(|err| err.message.as_str()).collect::<Vec<_>>(), ["module \"util/math\" does not export `hidden`"]);
+    let err = Compiler::compile("print math", None, &mut Program::with_loader(loader(&modules))).err().unwrap();
+    assert_eq!(err.errors[0].message, "undefined variable `math`");
+}
+
+/// Files are found relative to the file importing them, with the default
+/// extension added to paths that have none.
+#[test]
+fn file_loader() {
+    let loader = FileLoader::default();
+    assert_eq!(loader.resolve(None, "main"), "main.txt");
+    assert_eq!(loader.resolve(Some("app/main.txt"), "util/math"), "app/util/math.txt");
+    assert_eq!(loader.resolve(Some("app/util/math.txt"), "ops.scr"), "app/util/ops.scr");
+    assert_eq!(FileLoader::new("scr").resolve(Some("main.scr"), "lib"), "lib.scr");
+
+    let dir = env::temp_dir().join(format!("scripting-file-loader-{}", process::id()));
+    fs::create_dir_all(dir.join("util")).unwrap();
+    fs::write(dir.join("util/math.txt"), "import \"ops\"\nvar add = ops.add\nexport var twice = func(x) add(x, x)").unwrap();
+    fs::write(dir.join("util/ops.txt"), "export var add = func(a, b) a + b").unwrap();
+    let main = dir.join("main.txt");
+    let mut program = Program::new();
+    let result = Compiler::compile("import \"util/math\"\nvar twice = math.twice\nprint twice(4)", main.to_str(), &mut program).map_err(|err| err.to_string());
+    let missing = Compiler::compile("import \"util/missing\"", main.to_str(), &mut Program::new()).err().map(|err| err.errors[0].message.clone());
+    fs::remove_dir_all(&dir).unwrap();
+    result.unwrap();
+    let paths: Vec<&str> = program.modules.iter().map(|module| module.path.as_str()).collect();
+    assert_eq!(paths, ["<main>", dir.join("util/math.txt").to_str().unwrap(), dir.join("util/ops.txt").to_str().unwrap()]);
+    assert_eq!(run(&program), (b"8\n".to_vec(), Ok(())));
+    let missing_path = dir.join("util/missing.txt");
+    assert!(missing.unwrap().starts_with(&format!("cannot load module \"{}\": ", missing_path.display())));
+}
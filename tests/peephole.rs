@@ -0,0 +1,183 @@
+use scripting::{compact_value::CompactValue, compiler::{Compiler, Program}, func::DispFunc, globals::GlobalValues, heap::Heap, opcode::Opcode, vm::VirtualMachine};
+
+fn compile(source: &str) -> Program {
+    let mut program = Program::new();
+    Compiler::compile(source, None, &mut program).unwrap();
+    program
+}
+
+fn run(program: &Program) -> String {
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    vm.resume().unwrap();
+    drop(vm);
+    String::from_utf8(output).unwrap()
+}
+
+/// Compiles `source` and lists `func1`, the first function it defines.
+fn listing(source: &str) -> String {
+    let program = compile(source);
+    DispFunc::new(&program.funcs[1], &program.symbols).with_id(1).to_string()
+}
+
+/// Adding a constant to a local becomes `IncLocal` or `AddLocalConst`,
+/// subtracting adds the negated constant, and a loop comparing two locals
+/// tests them with a single `JumpIfNotLessLocals`.
+#[test]
+fn fused_locals() {
+    assert_eq!(listing("var f = func(n) { var x = 0 x += 1 x -= 3 var i = 0 while i < n { i += 1 } return x }"), "\
+func1(n)
+line 1
+var 2 x
+    0 : PushConst 0
+    2 : IncLocal 2                        ; x
+    4 : AddLocalConst 2 -3                ; x
+var 3 i
+    7 : PushConst 0
+L0:
+    9 : JumpIfNotLessLocals8 3 1 L1       ; not i < n ↓ 17
+   13 : IncLocal 3                        ; i
+   15 : Jump8 L0                          ; ↑ 9
+L1:
+   17 : PushLoad 2                        ; x
+   19 : PopStore 0                        ; return
+   21 : Return
+   22 : Drop 2
+   24 : Return
+end
+");
+}
+
+/// A jump that lands on an unconditional jump goes straight to where that
+/// one leads: the end of the inner `if` at 12 and the exit of the inner
+/// `while` at 30 both skip the outer statement's own jump.
+#[test]
+fn threaded_jumps() {
+    let source = "var f = func(a, b) {
+        if a { if b { print 1 } else { print 2 } } else { print 3 }
+        while a { while b { b = 0 } }
+    }";
+    assert_eq!(listing(source), "\
+func1(a, b)
+line 2
+    0 : PushLoad 1                        ; a
+    2 : JumpIfNot8 L1                     ; ↓ 20
+    4 : PushLoad 2                        ; b
+    6 : JumpIfNot8 L0                     ; ↓ 14
+    8 : PushConst 1
+   10 : PopPrint 1
+   12 : Jump8 L2                          ; ↓ 24
+L0:
+   14 : PushConst 2
+   16 : PopPrint 1
+   18 : Jump8 L2                          ; ↓ 24
+L1:
+   20 : PushConst 3
+   22 : PopPrint 1
+line 3
+L2:
+   24 : PushLoad 1                        ; a
+   26 : JumpIfNot8 L4                     ; ↓ 40
+L3:
+   28 : PushLoad 2                        ; b
+   30 : JumpIfNot8 L2                     ; ↑ 24
+   32 : PushConst 0
+   34 : PopStore 2                        ; b
+   36 : Jump8 L3                          ; ↑ 28
+   38 : Jump8 L2                          ; ↑ 24
+L4:
+   40 : Return
+end
+");
+}
+
+/// Equal constants share one entry in the function's table, including the
+/// ones `AddLocalConst` adds for a negated step.
+#[test]
+fn constant_table() {
+    let source = "var f = func() { print 1.5, \"s\", 1.5, 2, \"s\", 2 var x = 0 x -= 2 x += 2 x -= 2 }";
+    let program = compile(source);
+    let func = &program.funcs[1];
+    assert_eq!(format!("{:?}", func.constants), "[Float(1.5), String(\"s\"), Int(2), Int(0), Int(-2)]");
+    let pushed: Vec<u8> = func.bytecode[..12].chunks(2).map(|push| push[1]).collect();
+    assert_eq!(pushed, [0, 1, 0, 2, 1, 2]);
+    assert_eq!(listing(source), "\
+func1()
+line 1
+    0 : PushConst 1.5
+    2 : PushConst \"s\"
+    4 : PushConst 1.5
+    6 : PushConst 2
+    8 : PushConst \"s\"
+   10 : PushConst 2
+   12 : PopPrint 6
+var 1 x
+   14 : PushConst 0
+   16 : AddLocalConst 1 -2                ; x
+   19 : AddLocalConst 1 2                 ; x
+   22 : AddLocalConst 1 -2                ; x
+   25 : Drop 1
+   27 : Return
+end
+");
+}
+
+/// Locals past 255 are reached through a `Wide` prefix and constants past
+/// 255 through `PushConstWide`. The fused opcodes only take a byte, so
+/// `x += 1` on such a local is left as it is.
+#[test]
+fn wide_operands() {
+    let vars: Vec<String> = (0..300).map(|i| format!("var v{} = {}", i, i)).collect();
+    let sum: Vec<String> = (0..300).map(|i| format!("v{}", i)).collect();
+    let source = format!("var f = func() {{ {} v299 += 1 v3 += 1 print {} }} f()", vars.join(" "), sum.join(" + "));
+    let program = compile(&source);
+    let listing = DispFunc::new(&program.funcs[1], &program.symbols).with_id(1).to_string();
+    for line in [
+        "  510 : PushConst 255",
+        "  512 : PushConstWide 256",
+        "  727 : PushConstWide 299",
+        "  732 : PushLoad 300                      ; v299",
+        "  736 : PushConst 1",
+        "  738 : Add",
+        "  739 : PopStore 300                      ; v299",
+        "  743 : IncLocal 4                        ; v3",
+        " 1506 : PushLoad 255                      ; v254",
+        " 1509 : PushLoad 256                      ; v255",
+    ] {
+        assert!(listing.lines().any(|listed| listed == line), "{:?} not in\n{}", line, listing);
+    }
+    let bytecode = &program.funcs[1].bytecode;
+    assert_eq!(bytecode[732..736], [Opcode::Wide.into(), Opcode::PushLoad.into(), 1, 44]);
+    assert_eq!(bytecode[1506..1508], [Opcode::PushLoad.into(), 255]);
+
+    assert_eq!(run(&program), format!("{}\n", (0..300).sum::<i64>() + 2));
+}
+
+/// Jump offsets count from the end of the jump and take the narrowest of
+/// 8, 16 and 32 bits that holds them. Each loop body is four bytes per
+/// `print`, so these straddle both limits: at 30 prints the backward jump
+/// is exactly -128 and at 8190 the forward one is exactly 32767.
+#[test]
+fn jump_widths() {
+    for (prints, condition, back) in [
+        (30, "    2 : JumpIfNotLessLocals8 2 1 L1       ; not i < n ↓ 130", "  128 : Jump8 L0                          ; ↑ 2"),
+        (31, "    2 : JumpIfNotLessLocals16 2 1 L1      ; not i < n ↓ 136", "  133 : Jump16 L0                         ; ↑ 2"),
+        (8189, "    2 : JumpIfNotLessLocals16 2 1 L1      ; not i < n ↓ 32768", "32765 : Jump16 L0                         ; ↑ 2"),
+        (8190, "    2 : JumpIfNotLessLocals16 2 1 L1      ; not i < n ↓ 32774", "32769 : Jump32 L0                         ; ↑ 2"),
+        (8191, "    2 : JumpIfNotLessLocals32 2 1 L1      ; not i < n ↓ 32780", "32775 : Jump32 L0                         ; ↑ 2"),
+    ] {
+        let source = format!("var f = func(n) {{ var i = 0 while i < n {{ {} i += 1 }} }} f(2)", vec!["print i"; prints].join(" "));
+        let program = compile(&source);
+        let listing = DispFunc::new(&program.funcs[1], &program.symbols).with_id(1).to_string();
+        for line in [condition, back] {
+            assert!(listing.lines().any(|listed| listed == line), "{:?} not in the listing for {} prints", line, prints);
+        }
+        assert_eq!(run(&program), format!("{}{}", "0\n".repeat(prints), "1\n".repeat(prints)));
+    }
+
+    let bytecode = |prints: usize| compile(&format!("var f = func(n) {{ var i = 0 while i < n {{ {} i += 1 }} }}", vec!["print i"; prints].join(" "))).funcs[1].bytecode.clone();
+    assert_eq!(bytecode(30)[128..130], [Opcode::Jump8.into(), 0x80]);
+    assert_eq!(bytecode(8190)[2..7], [Opcode::JumpIfNotLessLocals16.into(), 2, 1, 0x7f, 0xff]);
+}
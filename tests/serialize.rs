@@ -0,0 +1,85 @@
+use scripting::{compact_value::CompactValue, compiler::{Compiler, Program}, globals::GlobalValues, heap::Heap, module::MemoryLoader, serialize::{MAGIC, VERSION}, verify, vm::VirtualMachine};
+
+const SOURCE: &str = "import \"lib\"
+## How many times to greet.
+var count = 3
+var greet = func(name) {
+    var i = 0
+    var greeting = func() name
+    while i < count { print greeting(), i * 1.5 i += 1 }
+    return lib.total
+}
+print greet(\"world\"), type(greet)";
+
+fn compile() -> Program {
+    let mut loader = MemoryLoader::new();
+    loader.insert("lib", "export var total = 40 + 2");
+    let mut program = Program::with_loader(loader);
+    Compiler::compile(SOURCE, None, &mut program).unwrap();
+    program
+}
+
+fn bytes(program: &Program) -> Vec<u8> {
+    let mut bytes = vec![];
+    program.write_to(&mut bytes).unwrap();
+    bytes
+}
+
+fn run(program: &Program) -> String {
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    vm.resume().unwrap();
+    drop(vm);
+    String::from_utf8(output).unwrap()
+}
+
+/// A program read back from its file writes the same bytes again, passes
+/// the verifier, keeps its doc comments and runs the same way.
+#[test]
+fn round_trip() {
+    let program = compile();
+    let written = bytes(&program);
+    assert!(written.starts_with(&MAGIC));
+    assert_eq!(written[4..6], VERSION.to_be_bytes());
+
+    let read = Program::read_from(&written[..]).unwrap();
+    verify::verify(&read).unwrap();
+    assert_eq!(bytes(&read), written);
+    assert_eq!(read.modules.len(), program.modules.len());
+    let slot = read.globals.resolve(0, read.symbols.get("count").unwrap()).unwrap();
+    assert_eq!(read.globals.doc(slot), Some("How many times to greet."));
+    assert_eq!(run(&read), run(&program));
+    assert_eq!(run(&read), "world 0\nworld 1.5\nworld 3\n42 func\n");
+}
+
+/// Files that are not compiled programs, come from another version, are
+/// cut short or contain unknown tags are rejected instead of loaded.
+#[test]
+fn bad_files() {
+    let error = |bytes: &[u8]| Program::read_from(bytes).err().unwrap();
+    let written = bytes(&compile());
+
+    let err = error(b"print 1\n");
+    assert_eq!((err.kind(), err.to_string()), (std::io::ErrorKind::InvalidData, "not a compiled program".to_string()));
+
+    let mut other_version = written.clone();
+    other_version[4..6].copy_from_slice(&(VERSION + 1).to_be_bytes());
+    assert_eq!(error(&other_version).to_string(), format!("unsupported bytecode version {} (expected {})", VERSION + 1, VERSION));
+
+    for len in 0..written.len() {
+        assert_eq!(error(&written[..len]).kind(), std::io::ErrorKind::UnexpectedEof, "truncated to {} bytes", len);
+    }
+
+    // The smallest program with one function holding one constant, whose
+    // tag is replaced by an unknown one.
+    let mut program = Program::new();
+    Compiler::compile("print 1.5", None, &mut program).unwrap();
+    program.funcs.truncate(1);
+    let written = bytes(&program);
+    let float_tag = written.windows(9).position(|window| window == [&[1][..], &1.5f64.to_bits().to_be_bytes()].concat()).unwrap();
+    let mut unknown_tag = written.clone();
+    unknown_tag[float_tag] = 9;
+    assert_eq!(error(&unknown_tag).to_string(), "unknown constant tag 9");
+}
@@ -0,0 +1,47 @@
+use scripting::{assemble::assemble, compact_value::CompactValue, compiler::{Compiler, Program}, globals::GlobalValues, heap::Heap, opcode::Opcode, verify::{verify, FuncError, VerifyError}, vm::{RuntimeError, VirtualMachine}};
+
+fn verify_listing(listing: &str) -> Result<(), VerifyError> {
+    verify(&assemble(listing, None).unwrap())
+}
+
+fn func_error(func: usize, offset: usize, error: FuncError) -> Result<(), VerifyError> {
+    Err(VerifyError::Func { func, offset, error })
+}
+
+/// Programs that would read outside the VM's tables or stack, or return
+/// from the entry function, are rejected before they run.
+#[test]
+fn malformed_programs() {
+    assert_eq!(verify_listing("func0()\nReturn\nend"), func_error(0, 0, FuncError::ReturnFromEntry));
+    assert_eq!(verify_listing("func0(a, b)\nPushLoad 2\nFinish\nend"), Err(VerifyError::Module(0)));
+    assert_eq!(verify_listing("func0()\nPushLoad 1\nFinish\nend"), func_error(0, 0, FuncError::Local(1)));
+    assert_eq!(verify_listing("func0()\nAdd\nFinish\nend"), func_error(0, 0, FuncError::StackUnderflow));
+    assert_eq!(verify_listing("func0()\nPushNone\nend"), func_error(0, 1, FuncError::FallsOffEnd));
+    assert_eq!(verify_listing("func0()\nPushTrue\nJumpIf8 end\nPushNone\nend:\nFinish\nend"), func_error(0, 4, FuncError::StackMismatch { expected: 2, found: 1 }));
+    assert_eq!(verify_listing("func0()\nPushConst func1\nFinish\nend\nfunc1() captures(stack 5)\nReturn\nend"), func_error(0, 0, FuncError::Capture(1)));
+    assert_eq!(verify_listing("func0()\nPushNone\nPopPrint 1\nFinish\nend"), Ok(()));
+
+    // The VM always starts in func0, so a main module entry elsewhere would
+    // let func0 return with no caller.
+    let mut program = assemble("func0()\nPushNone\nPopStore 0\nReturn\nend\nfunc1()\nFinish\nend", None).unwrap();
+    program.modules[0].entry_func = 1;
+    assert_eq!(verify(&program), Err(VerifyError::Module(0)));
+
+    let mut program = assemble("func0()\nFinish\nend", None).unwrap();
+    program.funcs[0].bytecode = vec![200];
+    assert_eq!(verify(&program), func_error(0, 0, FuncError::UnknownOpcode(200)));
+    program.funcs[0].bytecode = vec![Opcode::PushConst.into()];
+    assert_eq!(verify(&program), func_error(0, 0, FuncError::Truncated));
+}
+
+/// Calling a function with the wrong number of arguments is a runtime
+/// error rather than a crash.
+#[test]
+fn wrong_arg_count() {
+    let mut program = Program::new();
+    Compiler::compile("var f = func(a, b) a + b\nprint f(1)", None, &mut program).unwrap();
+    verify(&program).unwrap();
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let result = VirtualMachine::run(&program, 0, &mut stack, &mut heap, &mut globals);
+    assert_eq!(result, Err(RuntimeError::ArgCount { expected: 2, found: 1 }));
+}
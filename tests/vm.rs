@@ -0,0 +1,212 @@
+use std::{io::{self, Write}, mem, thread, time::Duration};
+
+use scripting::{compact_value::CompactValue, compiler::{Compiler, Program}, globals::GlobalValues, heap::Heap, string::Str, vm::{RuntimeError, VirtualMachine}};
+
+fn compile(source: &str) -> Program {
+    let mut program = Program::new();
+    Compiler::compile(source, None, &mut program).unwrap();
+    program
+}
+
+/// Runs `source` and returns what it printed before it finished or failed.
+fn run(source: &str) -> (String, Result<(), RuntimeError>) {
+    let program = compile(source);
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(&program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    let result = vm.resume();
+    drop(vm);
+    (String::from_utf8(output).unwrap(), result)
+}
+
+/// Values of the wrong type are runtime errors that stop the script, not
+/// crashes of the host.
+#[test]
+fn type_errors() {
+    let error = |source: &str| run(source).1.unwrap_err();
+    assert_eq!(error("print \"a\" + 1"), RuntimeError::InvalidOperands("string", "int"));
+    assert_eq!(error("print 1 / list()"), RuntimeError::InvalidOperands("int", "list"));
+    assert_eq!(error("var a = none print a < 1"), RuntimeError::InvalidOperands("none", "int"));
+    assert_eq!(error("var i = \"s\" while i < 3 { i += 1 }"), RuntimeError::InvalidOperands("string", "int"));
+    assert_eq!(error("if 1 { print 2 }"), RuntimeError::Type { expected: "bool", found: "int" });
+    assert_eq!(error("var f = 1 f()"), RuntimeError::Type { expected: "func", found: "int" });
+    assert_eq!(error("print type(1, 2)"), RuntimeError::ArgCount { expected: 1, found: 2 });
+    assert_eq!(error("print list(1).size"), RuntimeError::NoProperty("list", "size".to_string()));
+    assert_eq!(error("print 1 .len"), RuntimeError::NoProperty("int", "len".to_string()));
+    assert_eq!(run("print 1 print \"a\" + 1 print 2"), ("1\n".to_string(), Err(RuntimeError::InvalidOperands("string", "int"))));
+}
+
+/// `exit` unwinds out of any number of calls and leaves the machine
+/// finished, and statuses a process cannot exit with are rejected.
+#[test]
+fn exit() {
+    let source = "var f = func(n) { if n > 2 { exit(n) } return f(n + 1) } print 1 f(0) print 2";
+    let program = compile(source);
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(&program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    assert_eq!(vm.resume(), Err(RuntimeError::Exit(3)));
+    assert!(vm.is_finished());
+    assert_eq!(vm.resume(), Ok(()));
+    drop(vm);
+    assert_eq!(output, b"1\n");
+
+    assert_eq!(run("exit(0)").1, Err(RuntimeError::Exit(0)));
+    assert_eq!(run("exit(255)").1, Err(RuntimeError::Exit(255)));
+    assert_eq!(run("exit(256)").1, Err(RuntimeError::ExitStatus(256)));
+    assert_eq!(run("exit(0 - 1)").1, Err(RuntimeError::ExitStatus(-1)));
+    assert_eq!(run("exit(\"1\")").1, Err(RuntimeError::Type { expected: "int", found: "string" }));
+}
+
+/// `set_args` fills in the `args` global the embedder defined, and `env`
+/// returns `none` for unset variables.
+#[test]
+fn args_and_env() {
+    let mut program = Program::new();
+    program.define_global("args");
+    Compiler::compile("print args, args.len, type(env(\"PATH\")), env(\"SCRIPTING_UNSET_VARIABLE\")", None, &mut program).unwrap();
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(&program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    vm.set_args(&["a", "b c"]);
+    vm.resume().unwrap();
+    drop(vm);
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.ends_with("] 2 string none\n"), "{}", output);
+
+    assert_eq!(run("print env(1)").1, Err(RuntimeError::Type { expected: "string", found: "int" }));
+    assert_eq!(run("print env(list())").1, Err(RuntimeError::Type { expected: "string", found: "list" }));
+}
+
+/// Running out of fuel stops the machine where it is, and adding fuel lets
+/// `resume` carry on from there until the script finishes.
+#[test]
+fn fuel() {
+    let program = compile("var i = 0 while i < 5 { i += 1 print i }");
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(&program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    vm.set_fuel(Some(10));
+    assert_eq!(vm.resume(), Err(RuntimeError::OutOfFuel));
+    assert_eq!(vm.fuel(), Some(0));
+    assert!(!vm.is_finished());
+    let mut refills = 0;
+    while vm.resume() == Err(RuntimeError::OutOfFuel) {
+        vm.add_fuel(10);
+        refills += 1;
+    }
+    assert!(refills > 1);
+    assert!(vm.is_finished());
+    vm.set_fuel(Some(u64::MAX - 1));
+    vm.add_fuel(10);
+    assert_eq!(vm.fuel(), Some(u64::MAX));
+    drop(vm);
+    assert_eq!(output, b"1\n2\n3\n4\n5\n");
+
+    let (output, result) = run("var i = 0 while i < 3 { i += 1 print i }");
+    assert_eq!((output.as_str(), result), ("1\n2\n3\n", Ok(())));
+}
+
+/// An interrupt from another thread stops a script that never finishes,
+/// and the machine can be resumed afterwards.
+#[test]
+fn interrupt() {
+    let program = compile("var i = 0 while true { i += 1 if i == 1000 { print i } }");
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut output = vec![];
+    let mut vm = VirtualMachine::new(&program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    let handle = vm.interrupt_handle();
+    let interrupter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        handle.interrupt();
+    });
+    assert_eq!(vm.resume(), Err(RuntimeError::Interrupted));
+    interrupter.join().unwrap();
+    assert!(!vm.is_finished());
+
+    vm.interrupt_handle().interrupt();
+    assert_eq!(vm.resume(), Err(RuntimeError::Interrupted));
+    vm.set_fuel(Some(100));
+    assert_eq!(vm.resume(), Err(RuntimeError::OutOfFuel));
+    drop(vm);
+    assert_eq!(output, b"1000\n");
+}
+
+/// A writer that accepts nothing.
+struct Closed;
+
+impl Write for Closed {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `print` writes its arguments to the output sink with the configured
+/// separator and terminator, and a sink that fails stops the script.
+#[test]
+fn output() {
+    let (output, result) = run("print 1, 2.5, \"three\", true, none print type, type(type)");
+    result.unwrap();
+    assert_eq!(output, "1 2.5 three true none\nbuiltin type func\n");
+
+    let program = compile("print 1, 2 print 3");
+    let mut output = vec![];
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(&program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    vm.set_print_format(", ", ";");
+    vm.resume().unwrap();
+    drop(vm);
+    assert_eq!(output, b"1, 2;3;");
+
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut vm = VirtualMachine::new(&program, 0, &mut stack, &mut heap, &mut globals);
+    vm.set_output(Closed);
+    assert_eq!(vm.resume(), Err(RuntimeError::Output(io::ErrorKind::BrokenPipe)));
+}
+
+/// The heap frees its chunks without running destructors, so strings are
+/// copied into it instead of owning allocations that would leak.
+#[test]
+fn strings() {
+    assert!(!mem::needs_drop::<Str>());
+    let (output, result) = run("var i = 0 while i < 2 { i += 1 var s = \"ünï\" print s, s.len, \"\", type(i) }");
+    result.unwrap();
+    assert_eq!(output, "ünï 3  int\nünï 3  int\n");
+}
+
+/// Chunks compiled one after another into the same program, as the REPL
+/// does, share their globals, and the host can read and write them by name.
+#[test]
+fn globals() {
+    let mut program = Program::new();
+    let (mut stack, mut heap, mut globals) = (vec![CompactValue::NONE], Heap::new(), GlobalValues::new());
+    let mut output = vec![];
+    let chunk = |program: &mut Program, source: &str| {
+        let entry_func = program.funcs.len();
+        Compiler::compile(source, None, program).unwrap();
+        entry_func
+    };
+
+    let first = chunk(&mut program, "var count = 2 var limit = 0 var scale = func(x) x * count");
+    VirtualMachine::run(&program, first, &mut stack, &mut heap, &mut globals).unwrap();
+    let second = chunk(&mut program, "count += 1 print scale(2), limit");
+    let mut vm = VirtualMachine::new(&program, second, &mut stack, &mut heap, &mut globals);
+    vm.set_output(&mut output);
+    assert_eq!(vm.global("count").and_then(CompactValue::as_int), Some(2));
+    assert!(vm.set_global("limit", CompactValue::TRUE));
+    assert!(!vm.set_global("missing", CompactValue::TRUE));
+    assert!(vm.global("missing").is_none());
+    vm.resume().unwrap();
+    assert_eq!(vm.global("count").and_then(CompactValue::as_int), Some(3));
+    drop(vm);
+    assert_eq!(String::from_utf8(output).unwrap(), "6 true\n");
+}